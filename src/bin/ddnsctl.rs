@@ -0,0 +1,7 @@
+use anyhow::Result;
+use ddns::run_ctl;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    run_ctl().await
+}
@@ -1,31 +1,124 @@
 use std::collections::HashSet;
-use std::env;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::iter::FromIterator;
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 
 use anyhow::Result;
 use cloudflare::endpoints::dns::{
     CreateDnsRecord, CreateDnsRecordParams, DeleteDnsRecord, DnsContent, DnsRecord, ListDnsRecords,
     ListDnsRecordsParams,
 };
+use cloudflare::endpoints::user::{GetUserDetails, GetUserTokenStatus};
 use cloudflare::endpoints::zone::{ListZones, ListZonesParams, Zone};
 use cloudflare::framework::async_api::{ApiClient, Client};
 use cloudflare::framework::auth::Credentials;
-use cloudflare::framework::response::ApiFailure;
+use cloudflare::framework::endpoint::{Endpoint, Method};
+use cloudflare::framework::response::{ApiFailure, ApiResponse, ApiResult};
 use cloudflare::framework::{Environment, HttpApiClientConfig};
 use http::StatusCode;
+use serde::Serialize;
 use tap::TapFallible;
 use tracing::{error, info, info_span, instrument, Instrument};
+use url::Url;
 
-const DEFAULT_TTL: Option<u32> = Some(120);
+use crate::config::CfCredentials;
+use crate::hostname::{candidate_zones, normalize_hostname};
+use crate::rate_limit::RateLimiter;
+
+/// The max page size of the dns records list api
+const LIST_PER_PAGE: u32 = 100;
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RecordKind {
     A,
     AAAA,
+    SRV,
+}
+
+/// The SRV record `_{service}._{protocol}.{target}`, the priority and the weight are always 0
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct SrvRecord {
+    pub service: String,
+    pub protocol: String,
+    pub port: u16,
+    pub target: String,
+}
+
+impl SrvRecord {
+    pub fn name(&self) -> String {
+        format!("_{}._{}.{}", self.service, self.protocol, self.target)
+    }
+
+    /// Check if the record content `{weight} {port} {target}` points at the same port and target
+    fn matches_content(&self, content: &str) -> bool {
+        match content.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [.., weight, port, target] => {
+                *weight == "0"
+                    && port.parse() == Ok(self.port)
+                    && normalize_hostname(target) == normalize_hostname(&self.target)
+            }
+
+            _ => false,
+        }
+    }
+}
+
+/// Create a SRV record, the [`CreateDnsRecord`] can only set the content, but cloudflare requires
+/// the SRV data
+#[derive(Debug)]
+struct CreateSrvRecord<'a> {
+    zone_identifier: &'a str,
+    params: CreateSrvRecordParams<'a>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CreateSrvRecordParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    ttl: u32,
+    data: SrvData<'a>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SrvData<'a> {
+    service: String,
+    proto: String,
+    name: &'a str,
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: &'a str,
+}
+
+impl<'a> Endpoint<DnsRecord, (), CreateSrvRecordParams<'a>> for CreateSrvRecord<'a> {
+    fn method(&self) -> Method {
+        Method::Post
+    }
+
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone_identifier)
+    }
+
+    fn body(&self) -> Option<CreateSrvRecordParams<'a>> {
+        Some(self.params.clone())
+    }
+}
+
+impl RecordKind {
+    /// Check if the record is of the kind. The address set of a name is published as both A and
+    /// AAAA records, so the A and AAAA kinds both match the records of either family.
+    fn matches(&self, content: &DnsContent) -> bool {
+        match self {
+            RecordKind::A | RecordKind::AAAA => {
+                matches!(content, DnsContent::A { .. } | DnsContent::AAAA { .. })
+            }
+            RecordKind::SRV => matches!(content, DnsContent::SRV { .. }),
+        }
+    }
 }
 
 impl Display for RecordKind {
@@ -34,9 +127,17 @@ impl Display for RecordKind {
     }
 }
 
+/// The client is shared by the clones, and can be swapped when the credentials change, the
+/// in-flight requests keep using the old client
 #[derive(Clone)]
 pub struct CfDns {
-    client: Arc<Client>,
+    client: Arc<RwLock<Arc<Client>>>,
+    token_auth: Arc<AtomicBool>,
+    ttl: u32,
+    proxied: bool,
+    rate_limiter: Option<RateLimiter>,
+    /// The custom API base URL, the production API is used if it is None
+    api_url: Option<Url>,
 }
 
 impl Debug for CfDns {
@@ -48,20 +149,122 @@ impl Debug for CfDns {
 }
 
 impl CfDns {
-    pub async fn new() -> Result<Self> {
-        let cred = create_credentials();
+    pub async fn new(credentials: &CfCredentials, ttl: u32) -> Result<Self> {
+        Self::new_with_api_url(credentials, ttl, None).await
+    }
 
-        let client = Client::new(
-            cred,
-            HttpApiClientConfig::default(),
-            Environment::Production,
-        )?;
+    /// Create the client which sends the requests to the API base URL instead of the production
+    /// API, the URL must end with `/`
+    pub async fn new_with_api_url(
+        credentials: &CfCredentials,
+        ttl: u32,
+        api_url: Option<Url>,
+    ) -> Result<Self> {
+        let client = build_client(credentials, api_url.as_ref())?;
 
         Ok(Self {
-            client: Arc::new(client),
+            client: Arc::new(RwLock::new(Arc::new(client))),
+            token_auth: Arc::new(AtomicBool::new(matches!(
+                credentials,
+                CfCredentials::Token(_)
+            ))),
+            ttl,
+            proxied: false,
+            rate_limiter: None,
+            api_url,
         })
     }
 
+    pub fn api_url(&self) -> Option<&Url> {
+        self.api_url.as_ref()
+    }
+
+    /// Set the ttl of the created records
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+
+        self
+    }
+
+    /// Set whether the created records are proxied by cloudflare
+    pub fn with_proxied(mut self, proxied: bool) -> Self {
+        self.proxied = proxied;
+
+        self
+    }
+
+    /// Limit the requests by the rate limiter, it can be shared with other CfDns using the same
+    /// cloudflare account
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+
+        self
+    }
+
+    /// Replace the client with a new one using the credentials, all clones of this CfDns use the
+    /// new client in the next requests
+    pub fn set_credentials(&self, credentials: &CfCredentials) -> Result<()> {
+        let client = build_client(credentials, self.api_url.as_ref())?;
+
+        *self.client.write().unwrap() = Arc::new(client);
+        self.token_auth.store(
+            matches!(credentials, CfCredentials::Token(_)),
+            Ordering::Release,
+        );
+
+        info!("swap cloudflare client done");
+
+        Ok(())
+    }
+
+    fn client(&self) -> Arc<Client> {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Send the request by the current client, wait for the rate limiter first
+    async fn request<ResultType, QueryType, BodyType>(
+        &self,
+        endpoint: &(dyn Endpoint<ResultType, QueryType, BodyType> + Send + Sync),
+    ) -> ApiResponse<ResultType>
+    where
+        ResultType: ApiResult,
+        QueryType: Serialize,
+        BodyType: Serialize,
+    {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+
+        self.client().request(endpoint).await
+    }
+
+    /// Verify the credentials by the token verify api, or the user details api if using the
+    /// (email, key) credentials
+    #[instrument(err)]
+    pub async fn verify_credentials(&self) -> Result<()> {
+        if self.token_auth.load(Ordering::Acquire) {
+            let token_status = self
+                .request(&GetUserTokenStatus {})
+                .await
+                .tap_err(|err| error!(%err, "verify token failed"))?
+                .result;
+
+            if token_status.status != "active" {
+                error!(?token_status, "token is not active");
+
+                return Err(anyhow::anyhow!("token status is {}", token_status.status));
+            }
+        } else {
+            self.request(&GetUserDetails {})
+                .await
+                .tap_err(|err| error!(%err, "get user details failed"))?;
+        }
+
+        info!("verify cloudflare credentials success");
+
+        Ok(())
+    }
+
     #[instrument(err)]
     pub async fn get_dns_record(
         &self,
@@ -80,6 +283,10 @@ impl CfDns {
         Ok(ip_list)
     }
 
+    /// Make the records of the name exactly the IP list. The records which are not in the list
+    /// are removed, so an IP is unpublished when its service loses the load balancer IP, is
+    /// withheld or is filtered out, instead of being left in the zone forever. The records are
+    /// recreated when their ttl or proxied setting differs from the client.
     #[instrument(err)]
     pub async fn set_dns_record(
         &self,
@@ -90,12 +297,22 @@ impl CfDns {
     ) -> Result<()> {
         let zone_id = self.get_zone_id(zone).await?;
 
-        let exist_dns_records: HashSet<_> = HashSet::from_iter(
-            self.get_dns_record_with_zone_id(name, &zone_id, kind)
-                .await?,
-        );
+        // the IP list has the addresses of both families, so the A and AAAA records are both
+        // compared with it
+        let exist_dns_records = self.list_dns_records(name, &zone_id).await?;
+        let exist_dns_records = exist_dns_records
+            .iter()
+            .filter_map(|dns_record| Some((dns_record, record_address(dns_record, name)?)))
+            .collect::<Vec<_>>();
+
+        let exist_ips: HashSet<_> = exist_dns_records.iter().map(|(_, ip)| *ip).collect();
+
+        // cloudflare always sets the automatic ttl of the proxied records
+        let settings_changed = exist_dns_records.iter().any(|(dns_record, _)| {
+            dns_record.proxied != self.proxied || (!self.proxied && dns_record.ttl != self.ttl)
+        });
 
-        if !ip_list.iter().any(|ip| !exist_dns_records.contains(ip)) {
+        if !settings_changed && exist_ips == HashSet::from_iter(ip_list.iter().copied()) {
             info!(name, zone, %zone_id, %kind, ?ip_list, "no need update");
 
             return Ok(());
@@ -111,9 +328,9 @@ impl CfDns {
                 IpAddr::V4(ip) => CreateDnsRecord {
                     zone_identifier: &zone_id,
                     params: CreateDnsRecordParams {
-                        ttl: DEFAULT_TTL,
+                        ttl: Some(self.ttl),
                         priority: None,
-                        proxied: None,
+                        proxied: Some(self.proxied),
                         name,
                         content: DnsContent::A { content: *ip },
                     },
@@ -122,9 +339,9 @@ impl CfDns {
                 IpAddr::V6(ip) => CreateDnsRecord {
                     zone_identifier: &zone_id,
                     params: CreateDnsRecordParams {
-                        ttl: DEFAULT_TTL,
+                        ttl: Some(self.ttl),
                         priority: None,
-                        proxied: None,
+                        proxied: Some(self.proxied),
                         name,
                         content: DnsContent::AAAA { content: *ip },
                     },
@@ -132,7 +349,6 @@ impl CfDns {
             };
 
             let create_dns_resp = self
-                .client
                 .request(&create_dns_req)
                 .instrument(info_span!("create_dns_record"))
                 .await
@@ -153,6 +369,60 @@ impl CfDns {
         Ok(())
     }
 
+    /// Publish the SRV record, the other records with the same name are replaced
+    #[instrument(err)]
+    pub async fn set_srv_record(&self, zone: &str, srv_record: &SrvRecord) -> Result<()> {
+        let zone_id = self.get_zone_id(zone).await?;
+        let name = srv_record.name();
+
+        let mut exist_dns_records = self.list_dns_records(&name, &zone_id).await?;
+        exist_dns_records.retain(|dns_record| RecordKind::SRV.matches(&dns_record.content));
+
+        if let [dns_record] = exist_dns_records.as_slice() {
+            if dns_record.ttl == self.ttl
+                && matches!(&dns_record.content, DnsContent::SRV { content } if srv_record.matches_content(content))
+            {
+                info!(%name, zone, %zone_id, ?srv_record, "no need update");
+
+                return Ok(());
+            }
+        }
+
+        self.remove_dns_record_with_zone_id(&name, &zone_id, RecordKind::SRV)
+            .await?;
+
+        let create_srv_req = CreateSrvRecord {
+            zone_identifier: &zone_id,
+            params: CreateSrvRecordParams {
+                kind: "SRV",
+                name: name.clone(),
+                ttl: self.ttl,
+                data: SrvData {
+                    service: format!("_{}", srv_record.service),
+                    proto: format!("_{}", srv_record.protocol),
+                    name: &srv_record.target,
+                    priority: 0,
+                    weight: 0,
+                    port: srv_record.port,
+                    target: &srv_record.target,
+                },
+            },
+        };
+
+        let create_srv_resp = self
+            .request(&create_srv_req)
+            .instrument(info_span!("create_srv_record"))
+            .await
+            .tap_err(|err| error!(%name, zone, %zone_id, %err, "create srv record failed"))?;
+        if let Some(api_err) = create_srv_resp.errors.first() {
+            return Err(anyhow::anyhow!("{}", api_err));
+        }
+
+        info!(%name, zone, %zone_id, ?srv_record, "set srv record success");
+
+        Ok(())
+    }
+
     #[instrument(err)]
     pub async fn remove_dns_records(&self, name: &str, zone: &str, kind: RecordKind) -> Result<()> {
         let zone_id = self.get_zone_id(zone).await?;
@@ -172,42 +442,14 @@ impl CfDns {
         &self,
         name: &str,
         zone_id: &str,
-        _kind: RecordKind,
+        kind: RecordKind,
     ) -> Result<()> {
-        let list_dns_req = ListDnsRecords {
-            zone_identifier: zone_id,
-            params: ListDnsRecordsParams {
-                record_type: None,
-                name: Some(name.to_string()),
-                page: None,
-                per_page: None,
-                order: None,
-                direction: None,
-                search_match: None,
-            },
-        };
-
-        info!(?list_dns_req, "create list dns request");
-
-        let list_dns_resp = self.client.request(&list_dns_req).await.tap_err(|err| {
-            error!(?list_dns_req, %err, "list dns failed");
-        })?;
-
-        info!(?list_dns_resp, "get dns list response done");
-
-        if let Some(api_err) = list_dns_resp.errors.first() {
-            error!(%api_err, "list dns failed with response");
-
-            return Err(anyhow::anyhow!("{}", api_err));
-        }
-
-        let dns_list: Vec<DnsRecord> = list_dns_resp.result;
-
-        info!(?dns_list, "get dns list");
+        let dns_list = self.list_dns_records(name, zone_id).await?;
 
+        // the records of other kinds with the same name, like TXT, are not managed by us
         for dns_record in dns_list
             .into_iter()
-            .filter(|dns_record| dns_record.name == name)
+            .filter(|dns_record| dns_record.name == name && kind.matches(&dns_record.content))
         {
             let delete_dns_req = DeleteDnsRecord {
                 zone_identifier: zone_id,
@@ -216,11 +458,11 @@ impl CfDns {
 
             info!(?delete_dns_req, "create delete dns request");
 
-            let delete_dns_resp = match self.client.request(&delete_dns_req).await {
+            let delete_dns_resp = match self.request(&delete_dns_req).await {
                 Err(ApiFailure::Error(status_code, _)) if status_code == StatusCode::NOT_FOUND => {
-                    info!(name, zone_id, "dns record has been removed");
+                    info!(name, zone_id, id = %dns_record.id, "dns record has been removed");
 
-                    return Ok(());
+                    continue;
                 }
 
                 Err(err) => {
@@ -246,8 +488,82 @@ impl CfDns {
         Ok(())
     }
 
+    /// List the records with the name in all pages
+    #[instrument(err)]
+    async fn list_dns_records(&self, name: &str, zone_id: &str) -> Result<Vec<DnsRecord>> {
+        let mut dns_list = vec![];
+        let mut page = 1;
+
+        loop {
+            let list_dns_req = ListDnsRecords {
+                zone_identifier: zone_id,
+                params: ListDnsRecordsParams {
+                    record_type: None,
+                    name: Some(name.to_string()),
+                    page: Some(page),
+                    per_page: Some(LIST_PER_PAGE),
+                    order: None,
+                    direction: None,
+                    search_match: None,
+                },
+            };
+
+            info!(?list_dns_req, "create list dns request");
+
+            let list_dns_resp = self.request(&list_dns_req).await.tap_err(|err| {
+                error!(?list_dns_req, %err, "list dns failed");
+            })?;
+
+            if let Some(api_err) = list_dns_resp.errors.first() {
+                error!(%api_err, "list dns failed with response");
+
+                return Err(anyhow::anyhow!("{}", api_err));
+            }
+
+            let total_pages = list_dns_resp
+                .result_info
+                .as_ref()
+                .and_then(|result_info| result_info["total_pages"].as_u64())
+                .unwrap_or_default();
+
+            dns_list.extend(list_dns_resp.result);
+
+            if u64::from(page) >= total_pages {
+                break;
+            }
+
+            page += 1;
+        }
+
+        info!(?dns_list, "get dns list");
+
+        Ok(dns_list)
+    }
+
+    /// Find the zone of the hostname in the zones of the account, the longest zone wins, so a
+    /// subdomain delegated as its own zone is found, None means no zone contains the hostname
+    #[instrument(err)]
+    pub async fn find_zone(&self, hostname: &str) -> Result<Option<String>> {
+        for zone in candidate_zones(hostname) {
+            if self.find_zone_id(&zone).await?.is_some() {
+                return Ok(Some(zone));
+            }
+        }
+
+        Ok(None)
+    }
+
     #[instrument(err)]
     async fn get_zone_id(&self, zone: &str) -> Result<String> {
+        self.find_zone_id(zone).await?.ok_or_else(|| {
+            error!(?zone, "zone is not exist");
+
+            anyhow::anyhow!("zone {} is not exist", zone)
+        })
+    }
+
+    #[instrument(err)]
+    async fn find_zone_id(&self, zone: &str) -> Result<Option<String>> {
         let list_zones_req = ListZones {
             params: ListZonesParams {
                 name: Some(zone.to_string()),
@@ -262,7 +578,7 @@ impl CfDns {
 
         info!(?list_zones_req, "create list zones request");
 
-        let list_zones_resp = self.client.request(&list_zones_req).await.map_err(|err| {
+        let list_zones_resp = self.request(&list_zones_req).await.map_err(|err| {
             error!(%err, get_zone_request = ?list_zones_req, "send get zone id request failed");
 
             err
@@ -280,14 +596,9 @@ impl CfDns {
 
         info!(?list_zones_resp, "list zones done");
 
-        list_zones_resp
+        Ok(list_zones_resp
             .into_iter()
-            .find_map(|zone_info| (zone_info.name == zone).then(|| zone_info.id))
-            .ok_or_else(|| {
-                error!(?zone, "zone is not exist");
-
-                anyhow::anyhow!("zone {} is not exist", zone)
-            })
+            .find_map(|zone_info| (zone_info.name == zone).then_some(zone_info.id)))
     }
 
     #[instrument(err)]
@@ -297,52 +608,11 @@ impl CfDns {
         zone_id: &str,
         kind: RecordKind,
     ) -> Result<Vec<IpAddr>> {
-        let list_dns_req = ListDnsRecords {
-            zone_identifier: zone_id,
-            params: ListDnsRecordsParams {
-                record_type: None,
-                name: Some(name.to_string()),
-                page: None,
-                per_page: None,
-                order: None,
-                direction: None,
-                search_match: None,
-            },
-        };
-
-        let list_dns_resp = self.client.request(&list_dns_req).await?;
-        if let Some(api_err) = list_dns_resp.errors.first() {
-            return Err(anyhow::anyhow!("{}", api_err));
-        }
-
-        let list_dns_resp: Vec<DnsRecord> = list_dns_resp.result;
+        let list_dns_resp = self.list_dns_records(name, zone_id).await?;
 
         let ip_list = list_dns_resp
-            .into_iter()
-            .filter_map(|dns_record| {
-                if dns_record.name == name {
-                    match dns_record.content {
-                        DnsContent::A { content } => {
-                            if kind == RecordKind::A {
-                                Some(IpAddr::from(content))
-                            } else {
-                                None
-                            }
-                        }
-                        DnsContent::AAAA { content } => {
-                            if kind == RecordKind::AAAA {
-                                Some(IpAddr::from(content))
-                            } else {
-                                None
-                            }
-                        }
-
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            })
+            .iter()
+            .filter_map(|dns_record| record_ip(dns_record, name, kind))
             .collect::<Vec<_>>();
 
         info!(name, zone_id, %kind, ?ip_list, "get dns records success");
@@ -351,131 +621,466 @@ impl CfDns {
     }
 }
 
-fn create_credentials() -> Credentials {
-    if let Some(cred) = create_credentials_from_email() {
-        return cred;
+/// The IP of the A or AAAA record with the name
+fn record_ip(dns_record: &DnsRecord, name: &str, kind: RecordKind) -> Option<IpAddr> {
+    if dns_record.name != name {
+        return None;
     }
 
-    create_credentials_from_token().expect("can't find cloudflare (email, key) or token")
+    match dns_record.content {
+        DnsContent::A { content } if kind == RecordKind::A => Some(IpAddr::from(content)),
+        DnsContent::AAAA { content } if kind == RecordKind::AAAA => Some(IpAddr::from(content)),
+        _ => None,
+    }
 }
 
-fn create_credentials_from_email() -> Option<Credentials> {
-    let email = env::var("CF_DNS_EMAIL").ok()?;
-    let key = env::var("CF_DNS_KEY").ok()?;
+/// The IP of the address record with the name, whatever its family is
+fn record_address(dns_record: &DnsRecord, name: &str) -> Option<IpAddr> {
+    record_ip(dns_record, name, RecordKind::A)
+        .or_else(|| record_ip(dns_record, name, RecordKind::AAAA))
+}
 
-    Some(Credentials::UserAuthKey { email, key })
+fn build_client(credentials: &CfCredentials, api_url: Option<&Url>) -> Result<Client> {
+    let environment = match api_url {
+        None => Environment::Production,
+        Some(api_url) => Environment::Custom(api_url.clone()),
+    };
+
+    Client::new(
+        create_credentials(credentials),
+        HttpApiClientConfig::default(),
+        environment,
+    )
 }
 
-fn create_credentials_from_token() -> Option<Credentials> {
-    let token = env::var("CF_DNS_TOKEN").ok()?;
+fn create_credentials(credentials: &CfCredentials) -> Credentials {
+    match credentials {
+        CfCredentials::Token(token) => Credentials::UserAuthToken {
+            token: token.clone(),
+        },
 
-    Some(Credentials::UserAuthToken { token })
+        CfCredentials::EmailKey { email, key } => Credentials::UserAuthKey {
+            email: email.clone(),
+            key: key.clone(),
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::mem;
-    use std::sync::Once;
+    use std::time::Duration;
 
     use super::*;
+    use crate::fake_cloudflare::FakeCloudflare;
+
+    const ZONE: &str = "example.com";
+
+    async fn cf_dns(server: &FakeCloudflare) -> CfDns {
+        CfDns::new_with_api_url(
+            &CfCredentials::Token("token".to_string()),
+            300,
+            Some(server.api_url()),
+        )
+        .await
+        .unwrap()
+    }
 
-    fn init_tracing() {
-        static TRACING_INIT: Once = Once::new();
+    #[tokio::test]
+    async fn get_dns_record() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.add_record(ZONE, "test-get.example.com", IpAddr::from([127, 0, 0, 1]));
+        server.add_record(
+            ZONE,
+            "test-get.example.com",
+            IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+        );
+        server.add_record(ZONE, "other.example.com", IpAddr::from([127, 0, 0, 2]));
+        let cf_dns = cf_dns(&server).await;
+
+        let dns_records = cf_dns
+            .get_dns_record("test-get.example.com", ZONE, RecordKind::A)
+            .await
+            .unwrap();
+        assert_eq!(dns_records, [IpAddr::from([127, 0, 0, 1])]);
 
-        TRACING_INIT.call_once(|| {
-            let tracing_stop = crate::trace::init_tracing().unwrap();
+        let dns_records = cf_dns
+            .get_dns_record("test-get.example.com", ZONE, RecordKind::AAAA)
+            .await
+            .unwrap();
+        assert_eq!(dns_records, [IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]);
 
-            mem::forget(tracing_stop);
-        });
+        let err = cf_dns
+            .get_dns_record("test-get.example.org", "example.org", RecordKind::A)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "zone example.org is not exist");
     }
 
     #[tokio::test]
-    async fn get_dns_record() {
-        init_tracing();
+    async fn set_dns_record() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.add_record(ZONE, "test-set.example.com", IpAddr::from([127, 0, 0, 3]));
+        let cf_dns = cf_dns(&server).await;
+
+        let ips = [IpAddr::from([127, 0, 0, 1]), IpAddr::from([127, 0, 0, 2])];
 
-        let cf_dns = CfDns::new().await.unwrap();
+        cf_dns
+            .set_dns_record("test-set.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
 
-        let zone = env::var("TEST_ZONE").unwrap();
-        let domain = format!("test-get.{}", zone);
+        assert_eq!(server.ips(ZONE, "test-set.example.com"), ips);
+        assert!(server
+            .records(ZONE, "test-set.example.com")
+            .iter()
+            .all(|record| record.ttl == 300 && !record.proxied));
 
         let dns_records = cf_dns
-            .get_dns_record(&domain, &zone, RecordKind::A)
+            .get_dns_record("test-set.example.com", ZONE, RecordKind::A)
             .await
             .unwrap();
+        let set: HashSet<_> = HashSet::from_iter(dns_records);
+        assert_eq!(set, HashSet::from(ips));
 
-        println!("{:?}", dns_records);
+        // the records are not changed when they are up to date
+        let requests = server.requests();
+        cf_dns
+            .set_dns_record("test-set.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
+        // list zones and list dns records
+        assert_eq!(server.requests(), requests + 2);
     }
 
     #[tokio::test]
-    async fn set_dns_record() {
-        init_tracing();
+    async fn set_mixed_family_records() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        let cf_dns = cf_dns(&server).await;
 
-        let cf_dns = CfDns::new().await.unwrap();
+        let ips = [IpAddr::from([127, 0, 0, 1]), "::1".parse().unwrap()];
 
-        let zone = env::var("TEST_ZONE").unwrap();
-        let domain = format!("test-set.{}", zone);
+        cf_dns
+            .set_dns_record("test-mixed.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
+        assert_eq!(server.ips(ZONE, "test-mixed.example.com"), ips);
 
-        let ips = [IpAddr::from([127, 0, 0, 1]), IpAddr::from([127, 0, 0, 2])];
+        // the AAAA records are compared too, so the records are not recreated
+        let requests = server.requests();
+        cf_dns
+            .set_dns_record("test-mixed.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
+        // list zones and list dns records
+        assert_eq!(server.requests(), requests + 2);
+        assert_eq!(server.ips(ZONE, "test-mixed.example.com"), ips);
+    }
+
+    #[tokio::test]
+    async fn update_record_settings() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        let cf_dns = cf_dns(&server).await;
+
+        let ips = [IpAddr::from([127, 0, 0, 1])];
 
         cf_dns
-            .set_dns_record(&domain, &zone, RecordKind::A, &ips)
+            .set_dns_record("test-settings.example.com", ZONE, RecordKind::A, &ips)
             .await
             .unwrap();
 
-        let dns_records = cf_dns
-            .get_dns_record(&domain, &zone, RecordKind::A)
+        // the same IPs with another ttl
+        let cf_dns = cf_dns.with_ttl(600);
+        cf_dns
+            .set_dns_record("test-settings.example.com", ZONE, RecordKind::A, &ips)
             .await
             .unwrap();
-        assert_eq!(dns_records.len(), 2);
+        let records = server.records(ZONE, "test-settings.example.com");
+        assert_eq!(records.len(), 1);
+        assert!(records[0].ttl == 600 && !records[0].proxied);
 
-        let set: HashSet<_> = HashSet::from_iter(dns_records);
+        let cf_dns = cf_dns.with_proxied(true);
+        cf_dns
+            .set_dns_record("test-settings.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
+        let records = server.records(ZONE, "test-settings.example.com");
+        assert_eq!(records.len(), 1);
+        assert!(records[0].proxied);
 
-        for ip in &ips {
-            if !set.contains(ip) {
-                panic!("ip {} not exist", ip);
-            }
-        }
+        // the ttl of the proxied records is not compared
+        let requests = server.requests();
+        cf_dns
+            .with_ttl(300)
+            .set_dns_record("test-settings.example.com", ZONE, RecordKind::A, &ips)
+            .await
+            .unwrap();
+        assert_eq!(server.requests(), requests + 2);
+    }
+
+    #[tokio::test]
+    async fn remove_stale_records() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.add_record(ZONE, "test-stale.example.com", IpAddr::from([127, 0, 0, 1]));
+        server.add_record(ZONE, "test-stale.example.com", IpAddr::from([127, 0, 0, 2]));
+        let cf_dns = cf_dns(&server).await;
+
+        // the IP list is a subset of the records, the extra record is still removed
+        cf_dns
+            .set_dns_record(
+                "test-stale.example.com",
+                ZONE,
+                RecordKind::A,
+                &[IpAddr::from([127, 0, 0, 1])],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            server.ips(ZONE, "test-stale.example.com"),
+            [IpAddr::from([127, 0, 0, 1])]
+        );
+    }
+
+    #[tokio::test]
+    async fn find_zone() {
+        let server = FakeCloudflare::start();
+        server.add_zone("example.co.uk");
+        server.add_zone("dev.example.co.uk");
+        let cf_dns = cf_dns(&server).await;
+
+        assert_eq!(
+            cf_dns
+                .find_zone("*.a.example.co.uk")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("example.co.uk")
+        );
+        // the delegated subdomain is the longest zone
+        assert_eq!(
+            cf_dns
+                .find_zone("a.dev.example.co.uk")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("dev.example.co.uk")
+        );
+        assert_eq!(cf_dns.find_zone("a.example.com").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn set_srv_record() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        let cf_dns = cf_dns(&server).await;
+
+        let mut srv_record = SrvRecord {
+            service: "sip".to_string(),
+            protocol: "udp".to_string(),
+            port: 5060,
+            target: "sip.example.com".to_string(),
+        };
+        assert_eq!(srv_record.name(), "_sip._udp.sip.example.com");
+
+        cf_dns.set_srv_record(ZONE, &srv_record).await.unwrap();
+
+        let records = server.records(ZONE, "_sip._udp.sip.example.com");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, "SRV");
+        assert_eq!(records[0].content, "0 5060 sip.example.com");
+
+        // the record is not changed when it is up to date
+        let requests = server.requests();
+        cf_dns.set_srv_record(ZONE, &srv_record).await.unwrap();
+        assert_eq!(server.requests(), requests + 2);
+
+        srv_record.port = 5080;
+        cf_dns.set_srv_record(ZONE, &srv_record).await.unwrap();
+
+        let records = server.records(ZONE, "_sip._udp.sip.example.com");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].content, "0 5080 sip.example.com");
+
+        cf_dns
+            .remove_dns_records("_sip._udp.sip.example.com", ZONE, RecordKind::SRV)
+            .await
+            .unwrap();
+        assert!(server.records(ZONE, "_sip._udp.sip.example.com").is_empty());
     }
 
     #[tokio::test]
     async fn remove_dns_record() {
-        init_tracing();
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.add_record(
+            ZONE,
+            "test-remove.example.com",
+            IpAddr::from([127, 0, 0, 1]),
+        );
+        server.add_record(
+            ZONE,
+            "test-remove.example.com",
+            IpAddr::from([127, 0, 0, 2]),
+        );
+        server.add_record(ZONE, "other.example.com", IpAddr::from([127, 0, 0, 3]));
+        let cf_dns = cf_dns(&server).await;
+
+        cf_dns
+            .remove_dns_records("test-remove.example.com", ZONE, RecordKind::A)
+            .await
+            .unwrap();
 
-        let cf_dns = CfDns::new().await.unwrap();
+        assert!(server.records(ZONE, "test-remove.example.com").is_empty());
+        assert_eq!(
+            server.ips(ZONE, "other.example.com"),
+            [IpAddr::from([127, 0, 0, 3])]
+        );
 
-        let zone = env::var("TEST_ZONE").unwrap();
-        let domain = format!("test-remove.{}", zone);
+        // removing the removed records is fine
+        cf_dns
+            .remove_dns_records("test-remove.example.com", ZONE, RecordKind::A)
+            .await
+            .unwrap();
+    }
 
-        let ips = [IpAddr::from([127, 0, 0, 1]), IpAddr::from([127, 0, 0, 2])];
+    #[tokio::test]
+    async fn remove_only_records_of_kind() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.add_record(ZONE, "test-kind.example.com", IpAddr::from([127, 0, 0, 1]));
+        server.add_record(ZONE, "test-kind.example.com", "::1".parse().unwrap());
+        server.add_txt_record(ZONE, "test-kind.example.com", "verification");
+        let cf_dns = cf_dns(&server).await;
 
         cf_dns
-            .set_dns_record(&domain, &zone, RecordKind::A, &ips)
+            .remove_dns_records("test-kind.example.com", ZONE, RecordKind::A)
             .await
             .unwrap();
 
-        let dns_records = cf_dns
-            .get_dns_record(&domain, &zone, RecordKind::A)
+        let records = server.records(ZONE, "test-kind.example.com");
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, "TXT");
+
+        let srv_record = SrvRecord {
+            service: "sip".to_string(),
+            protocol: "udp".to_string(),
+            port: 5060,
+            target: "test-kind.example.com".to_string(),
+        };
+        server.add_txt_record(ZONE, &srv_record.name(), "verification");
+        cf_dns.set_srv_record(ZONE, &srv_record).await.unwrap();
+
+        cf_dns
+            .remove_dns_records(&srv_record.name(), ZONE, RecordKind::SRV)
             .await
             .unwrap();
-        assert_eq!(dns_records.len(), 2);
 
-        let set: HashSet<_> = HashSet::from_iter(dns_records);
+        let records = server.records(ZONE, &srv_record.name());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, "TXT");
+    }
+
+    #[tokio::test]
+    async fn list_all_pages() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.set_max_per_page(2);
 
+        let ips = (1..=5)
+            .map(|i| IpAddr::from([127, 0, 0, i]))
+            .collect::<Vec<_>>();
         for ip in &ips {
-            if !set.contains(ip) {
-                panic!("ip {} not exist", ip);
-            }
+            server.add_record(ZONE, "test-page.example.com", *ip);
         }
+        let cf_dns = cf_dns(&server).await;
+
+        let dns_records = cf_dns
+            .get_dns_record("test-page.example.com", ZONE, RecordKind::A)
+            .await
+            .unwrap();
+        assert_eq!(dns_records, ips);
 
         cf_dns
-            .remove_dns_records(&domain, &zone, RecordKind::A)
+            .remove_dns_records("test-page.example.com", ZONE, RecordKind::A)
             .await
             .unwrap();
+        assert!(server.records(ZONE, "test-page.example.com").is_empty());
+    }
 
-        let dns_records = cf_dns
-            .get_dns_record(&domain, &zone, RecordKind::A)
+    #[tokio::test]
+    async fn api_error() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        let cf_dns = cf_dns(&server).await;
+
+        server.fail_next(1, StatusCode::INTERNAL_SERVER_ERROR);
+        cf_dns
+            .set_dns_record(
+                "test-error.example.com",
+                ZONE,
+                RecordKind::A,
+                &[IpAddr::from([127, 0, 0, 1])],
+            )
+            .await
+            .unwrap_err();
+        assert!(server.records(ZONE, "test-error.example.com").is_empty());
+
+        // the failure is not sticky
+        cf_dns
+            .set_dns_record(
+                "test-error.example.com",
+                ZONE,
+                RecordKind::A,
+                &[IpAddr::from([127, 0, 0, 1])],
+            )
             .await
             .unwrap();
-        assert_eq!(dns_records.len(), 0);
+        assert_eq!(
+            server.ips(ZONE, "test-error.example.com"),
+            [IpAddr::from([127, 0, 0, 1])]
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        let server = FakeCloudflare::start();
+        server.add_zone(ZONE);
+        server.set_rate_limit(1, Duration::from_secs(60));
+        let cf_dns = cf_dns(&server).await;
+
+        let err = cf_dns
+            .get_dns_record("test-limit.example.com", ZONE, RecordKind::A)
+            .await
+            .unwrap_err();
+        let err = err.downcast::<ApiFailure>().unwrap();
+        assert!(
+            matches!(err, ApiFailure::Error(status, _) if status == StatusCode::TOO_MANY_REQUESTS)
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_credentials() {
+        let server = FakeCloudflare::start();
+
+        cf_dns(&server).await.verify_credentials().await.unwrap();
+
+        let cf_dns = CfDns::new_with_api_url(
+            &CfCredentials::EmailKey {
+                email: "user@example.com".to_string(),
+                key: "key".to_string(),
+            },
+            300,
+            Some(server.api_url()),
+        )
+        .await
+        .unwrap();
+        cf_dns.verify_credentials().await.unwrap();
+
+        server.fail_next(1, StatusCode::FORBIDDEN);
+        cf_dns.verify_credentials().await.unwrap_err();
     }
 }
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::Secret;
+use kube::runtime::reflector::ObjectRef;
+use kube::{Api, Client};
+use tap::TapFallible;
+use tokio::sync::Mutex;
+use tracing::{error, info, instrument};
+
+use crate::cf_dns::CfDns;
+use crate::config::{check_ttl, CfCredentials};
+use crate::rate_limit::RateLimiter;
+use crate::spec::{
+    DdnsSpec, DdnsStatus, DnsProviderConfig, DnsProviderKind, ProviderRef, SecretRef,
+};
+
+const TOKEN_KEY: &str = "token";
+const EMAIL_KEY: &str = "email";
+const API_KEY_KEY: &str = "key";
+
+/// The clients built from the Secrets, with the resource version of the Secret
+type SecretCfDns = HashMap<ObjectRef<Secret>, (String, CfDns)>;
+
+/// The clients built from the providers, with the resource versions of the provider and its
+/// Secret
+type ProviderCfDns = HashMap<ObjectRef<DnsProviderConfig>, (String, String, CfDns)>;
+
+/// The pool of [`CfDns`] clients. The Ddns referring a provider gets the client built from the
+/// [`DnsProviderConfig`], the Ddns referring a credentials Secret gets the client built from the
+/// Secret, others get the default client
+#[derive(Clone)]
+pub struct CfDnsPool {
+    client: Client,
+    default_cf_dns: CfDns,
+    ttl: u32,
+    rate_limiter: RateLimiter,
+    // the resource version is used to find out the Secret or the provider is changed
+    secret_cf_dns: Arc<Mutex<SecretCfDns>>,
+    provider_cf_dns: Arc<Mutex<ProviderCfDns>>,
+}
+
+impl Debug for CfDnsPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CfDnsPool")
+            .field("default_cf_dns", &self.default_cf_dns)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl CfDnsPool {
+    pub fn new(client: Client, default_cf_dns: CfDns, ttl: u32, rate_limiter: RateLimiter) -> Self {
+        Self {
+            client,
+            default_cf_dns,
+            ttl,
+            rate_limiter,
+            secret_cf_dns: Arc::new(Default::default()),
+            provider_cf_dns: Arc::new(Default::default()),
+        }
+    }
+
+    /// The client of the controller credentials
+    pub fn default_cf_dns(&self) -> CfDns {
+        self.default_cf_dns.clone()
+    }
+
+    /// Get the client of the Ddns in the namespace.
+    ///
+    /// The Secret and the provider are read every time, so the client is rebuilt once they
+    /// change. The Ddns referring a provider are reconciled when its spec changes, the Secret is
+    /// not watched, so the controller only needs the `get` permission of the Secrets.
+    #[instrument(err, skip(self))]
+    pub async fn get(&self, namespace: &str, spec: &DdnsSpec) -> Result<CfDns> {
+        self.get_by_refs(
+            namespace,
+            &spec.zone,
+            spec.provider_ref.as_ref(),
+            spec.credentials_secret_ref.as_ref(),
+        )
+        .await
+    }
+
+    /// Get the client which the records in the status are published with, the spec is used if
+    /// the status doesn't record it, like the status written by an older controller
+    #[instrument(err, skip(self))]
+    pub async fn get_published(
+        &self,
+        namespace: &str,
+        spec: &DdnsSpec,
+        status: &DdnsStatus,
+    ) -> Result<CfDns> {
+        match &status.published_credentials {
+            None => self.get(namespace, spec).await,
+
+            Some(credentials) => {
+                self.get_by_refs(
+                    namespace,
+                    &status.zone,
+                    credentials.provider_ref.as_ref(),
+                    credentials.credentials_secret_ref.as_ref(),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn get_by_refs(
+        &self,
+        namespace: &str,
+        zone: &str,
+        provider_ref: Option<&ProviderRef>,
+        secret_ref: Option<&SecretRef>,
+    ) -> Result<CfDns> {
+        match (provider_ref, secret_ref) {
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "providerRef and credentialsSecretRef can't be set together"
+            )),
+
+            (Some(provider_ref), None) => self.get_by_provider(namespace, zone, provider_ref).await,
+
+            (None, Some(secret_ref)) => self.get_by_secret(namespace, secret_ref).await,
+
+            (None, None) => Ok(self.default_cf_dns.clone()),
+        }
+    }
+
+    async fn get_by_secret(&self, namespace: &str, secret_ref: &SecretRef) -> Result<CfDns> {
+        let secret = self.get_secret(namespace, &secret_ref.name).await?;
+
+        let obj_ref = ObjectRef::from_obj(&secret);
+        let resource_version = secret.metadata.resource_version.clone().unwrap_or_default();
+
+        let mut secret_cf_dns = self.secret_cf_dns.lock().await;
+
+        if let Some((cached_version, cf_dns)) = secret_cf_dns.get(&obj_ref) {
+            if *cached_version == resource_version {
+                return Ok(cf_dns.clone());
+            }
+        }
+
+        let credentials = credentials_from_secret(&secret).tap_err(
+            |err| error!(%err, namespace, ?secret_ref, "read credentials from secret failed"),
+        )?;
+
+        // the clients use the same api as the default client
+        let api_url = self.default_cf_dns.api_url().cloned();
+        let cf_dns = CfDns::new_with_api_url(&credentials, self.ttl, api_url)
+            .await?
+            .with_rate_limiter(self.rate_limiter.clone());
+
+        info!(%obj_ref, %resource_version, "build cf dns client from secret done");
+
+        secret_cf_dns.insert(obj_ref, (resource_version, cf_dns.clone()));
+
+        Ok(cf_dns)
+    }
+
+    async fn get_by_provider(
+        &self,
+        namespace: &str,
+        zone: &str,
+        provider_ref: &ProviderRef,
+    ) -> Result<CfDns> {
+        let provider_api: Api<DnsProviderConfig> = Api::all(self.client.clone());
+        let provider = provider_api
+            .get(&provider_ref.name)
+            .await
+            .tap_err(|err| error!(%err, ?provider_ref, "get dns provider config failed"))?;
+
+        provider
+            .spec
+            .check_allowed(namespace, zone)
+            .map_err(|reason| {
+                error!(%reason, namespace, zone, ?provider_ref, "dns provider is not allowed");
+
+                anyhow::anyhow!("provider {} is not allowed: {}", provider_ref.name, reason)
+            })?;
+
+        let secret_ref = &provider.spec.credentials_secret_ref;
+        let secret = self
+            .get_secret(&secret_ref.namespace, &secret_ref.name)
+            .await?;
+
+        let obj_ref = ObjectRef::from_obj(&provider);
+        let provider_version = provider
+            .metadata
+            .resource_version
+            .clone()
+            .unwrap_or_default();
+        let secret_version = secret.metadata.resource_version.clone().unwrap_or_default();
+
+        let mut provider_cf_dns = self.provider_cf_dns.lock().await;
+
+        if let Some((cached_provider_version, cached_secret_version, cf_dns)) =
+            provider_cf_dns.get(&obj_ref)
+        {
+            if *cached_provider_version == provider_version
+                && *cached_secret_version == secret_version
+            {
+                return Ok(cf_dns.clone());
+            }
+        }
+
+        let ttl = provider.spec.default_ttl.unwrap_or(self.ttl);
+        check_ttl(ttl).map_err(|reason| {
+            error!(%reason, ?provider_ref, "dns provider default ttl is invalid");
+
+            anyhow::anyhow!("provider {} ttl is invalid: {}", provider_ref.name, reason)
+        })?;
+
+        let credentials = match provider.spec.provider {
+            DnsProviderKind::Cloudflare => credentials_from_secret(&secret).tap_err(|err| {
+                error!(%err, ?provider_ref, ?secret_ref, "read credentials from secret failed")
+            })?,
+        };
+
+        let api_url = self.default_cf_dns.api_url().cloned();
+        let cf_dns = CfDns::new_with_api_url(&credentials, ttl, api_url)
+            .await?
+            .with_proxied(provider.spec.proxied)
+            .with_rate_limiter(self.rate_limiter.clone());
+
+        info!(%obj_ref, %provider_version, %secret_version, "build cf dns client from provider done");
+
+        provider_cf_dns.insert(obj_ref, (provider_version, secret_version, cf_dns.clone()));
+
+        Ok(cf_dns)
+    }
+
+    async fn get_secret(&self, namespace: &str, name: &str) -> Result<Secret> {
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+
+        Ok(secret_api
+            .get(name)
+            .await
+            .tap_err(|err| error!(%err, namespace, name, "get credentials secret failed"))?)
+    }
+}
+
+/// Read the credentials from the Secret, the Secret contains a `token`, or an `email` and a `key`
+fn credentials_from_secret(secret: &Secret) -> Result<CfCredentials> {
+    let get = |key: &str| -> Result<Option<String>> {
+        if let Some(value) = secret.data.as_ref().and_then(|data| data.get(key)) {
+            return Ok(Some(String::from_utf8(value.0.clone()).map_err(|_| {
+                anyhow::anyhow!("secret key {} is not valid utf8", key)
+            })?));
+        }
+
+        Ok(secret
+            .string_data
+            .as_ref()
+            .and_then(|data| data.get(key))
+            .cloned())
+    };
+
+    match (get(EMAIL_KEY)?, get(API_KEY_KEY)?, get(TOKEN_KEY)?) {
+        (Some(email), Some(key), _) => Ok(CfCredentials::EmailKey { email, key }),
+        (_, _, Some(token)) => Ok(CfCredentials::Token(token)),
+
+        _ => Err(anyhow::anyhow!(
+            "secret doesn't contain {}, or both {} and {}",
+            TOKEN_KEY,
+            EMAIL_KEY,
+            API_KEY_KEY
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::ByteString;
+
+    use super::*;
+
+    fn secret(data: &[(&str, &str)]) -> Secret {
+        Secret {
+            data: Some(
+                data.iter()
+                    .map(|(key, value)| (key.to_string(), ByteString(value.as_bytes().to_vec())))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn token_credentials() {
+        let credentials = credentials_from_secret(&secret(&[("token", "abc")])).unwrap();
+
+        assert_eq!(credentials, CfCredentials::Token("abc".to_string()));
+    }
+
+    #[test]
+    fn email_key_credentials() {
+        let credentials = credentials_from_secret(&secret(&[
+            ("email", "admin@example.com"),
+            ("key", "abc"),
+            ("token", "def"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            credentials,
+            CfCredentials::EmailKey {
+                email: "admin@example.com".to_string(),
+                key: "abc".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn missing_credentials() {
+        assert!(credentials_from_secret(&secret(&[("email", "admin@example.com")])).is_err());
+        assert!(credentials_from_secret(&Secret::default()).is_err());
+    }
+}
@@ -0,0 +1,1026 @@
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Parser;
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+
+use crate::ip_filter::{parse_cidrs, IpFilter};
+
+const DEFAULT_JAEGER_AGENT: &str = "127.0.0.1:6831";
+const DEFAULT_OTLP_GRPC_ENDPOINT: &str = "http://127.0.0.1:4317";
+const DEFAULT_OTLP_HTTP_ENDPOINT: &str = "http://127.0.0.1:4318/v1/traces";
+const DEFAULT_TRACE_SAMPLE_RATIO: f64 = 1.0;
+pub const DEFAULT_TTL: u32 = 120;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(3);
+const DEFAULT_NO_IP_RETRY_DELAY: Duration = Duration::from_secs(3);
+const DEFAULT_CONFLICT_RECHECK_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_QUEUE_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_DELETE_CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_MAX_CONCURRENT_RECONCILES: usize = 8;
+/// Cloudflare allows 1200 requests per 5 minutes for a user
+pub const DEFAULT_CF_RATE_LIMIT: u32 = 1200;
+pub const DEFAULT_CF_RATE_BURST: u32 = 100;
+pub const CF_RATE_LIMIT_PERIOD: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_HEALTH_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_WEBHOOK_ADDR: &str = "0.0.0.0:8443";
+/// Kubernetes waits 30 seconds by default before killing the pod, leave some time to flush the
+/// telemetry
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Cloudflare TTL 1 means automatic
+const AUTO_TTL: u32 = 1;
+const MIN_TTL: u32 = 60;
+const MAX_TTL: u32 = 86400;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("read config file {path:?} failed: {source}")]
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("parse config file {path:?} failed: {source}")]
+    ParseFile {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[error("parse arguments failed: {0}")]
+    Args(#[from] clap::Error),
+
+    #[error("missing cloudflare credentials, set cf-token, or both cf-email and cf-key")]
+    MissingCredentials,
+
+    #[error("{0} is set but {1} is missing")]
+    Incomplete(&'static str, &'static str),
+
+    #[error("invalid {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+/// The command line arguments, every argument can be set by env too
+#[derive(Debug, Default, Parser)]
+#[command(
+    name = "ddns",
+    version,
+    about = "Publish load balancer service IPs to Cloudflare DNS"
+)]
+struct Args {
+    /// YAML config file, the arguments and env override the values in it
+    #[arg(long, env = "DDNS_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Subcommand {
+    /// Manage the CRDs of ddns
+    Crd {
+        #[command(subcommand)]
+        command: CrdCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum CrdCommand {
+    /// Print the CRDs generated from the code as YAML
+    Print,
+}
+
+/// The options which can be set by arguments, env or config file, the config file uses the
+/// argument names as keys
+#[derive(Debug, Default, clap::Args, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Options {
+    /// Cloudflare account email, used with cf-key
+    #[arg(long, env = "CF_DNS_EMAIL")]
+    cf_email: Option<String>,
+
+    /// Cloudflare global API key, used with cf-email
+    #[arg(long, env = "CF_DNS_KEY", hide_env_values = true)]
+    cf_key: Option<String>,
+
+    /// Cloudflare API token
+    #[arg(long, env = "CF_DNS_TOKEN", hide_env_values = true)]
+    cf_token: Option<String>,
+
+    /// File containing the Cloudflare global API key, used with cf-email, it is reloaded when
+    /// changed and preferred over cf-key
+    #[arg(long, env = "CF_DNS_KEY_FILE")]
+    cf_key_file: Option<PathBuf>,
+
+    /// File containing the Cloudflare API token, it is reloaded when changed and preferred over
+    /// cf-token
+    #[arg(long, env = "CF_DNS_TOKEN_FILE")]
+    cf_token_file: Option<PathBuf>,
+
+    /// Cloudflare API base URL, for a proxy or a test server [default:
+    /// https://api.cloudflare.com/client/v4/]
+    #[arg(long, env = "CF_API_URL")]
+    cf_api_url: Option<Url>,
+
+    /// Where the traces are exported [default: jaeger if built with the jaeger feature, else
+    /// none]
+    #[arg(long, env = "TRACE_EXPORTER", value_enum)]
+    trace_exporter: Option<TraceExporterKind>,
+
+    /// Jaeger agent address [default: 127.0.0.1:6831]
+    #[arg(long, env = "JAEGER_AGENT")]
+    jaeger_agent: Option<String>,
+
+    /// OTLP collector endpoint [default: http://127.0.0.1:4317 for otlp-grpc,
+    /// http://127.0.0.1:4318/v1/traces for otlp-http]
+    #[arg(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Ratio of the root traces which are sampled, from 0 to 1 [default: 1]
+    #[arg(long, env = "TRACE_SAMPLE_RATIO")]
+    trace_sample_ratio: Option<f64>,
+
+    /// Format of the logs written to stderr [default: pretty]
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// TTL of the created records, 1 means automatic [default: 120]
+    #[arg(long, env = "DEFAULT_TTL")]
+    default_ttl: Option<u32>,
+
+    /// Delay before retrying a failed reconcile [default: 3s]
+    #[arg(long, env = "RETRY_DELAY", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    retry_delay: Option<Duration>,
+
+    /// Delay before retrying a Ddns whose services have no load balancer IP [default: 3s]
+    #[arg(long, env = "NO_IP_RETRY_DELAY", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    no_ip_retry_delay: Option<Duration>,
+
+    /// Delay before checking a conflicted Ddns again [default: 30s]
+    #[arg(long, env = "CONFLICT_RECHECK_DELAY", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    conflict_recheck_delay: Option<Duration>,
+
+    /// Time after which the handle task of a Ddns without updates exits [default: 5m]
+    #[arg(long, env = "QUEUE_IDLE_TIMEOUT", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    queue_idle_timeout: Option<Duration>,
+
+    /// Time a deleting Ddns waits for the credentials of its records, then it is deleted and the
+    /// records are left in the zone [default: 5m]
+    #[arg(long, env = "DELETE_CREDENTIALS_TIMEOUT", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    delete_credentials_timeout: Option<Duration>,
+
+    /// Number of the reconciles which can run at the same time, the others wait [default: 8]
+    #[arg(long, env = "MAX_CONCURRENT_RECONCILES")]
+    max_concurrent_reconciles: Option<usize>,
+
+    /// Cloudflare API requests allowed in 5 minutes, the requests over the limit wait
+    /// [default: 1200]
+    #[arg(long, env = "CF_RATE_LIMIT")]
+    cf_rate_limit: Option<u32>,
+
+    /// Cloudflare API requests which can be sent at once, must be less than cf-rate-limit
+    /// [default: 100]
+    #[arg(long, env = "CF_RATE_BURST")]
+    cf_rate_burst: Option<u32>,
+
+    /// Listen address of the health server [default: 0.0.0.0:8080]
+    #[arg(long, env = "HEALTH_ADDR")]
+    health_addr: Option<SocketAddr>,
+
+    /// Listen address of the admission webhook [default: 0.0.0.0:8443]
+    #[arg(long, env = "WEBHOOK_ADDR")]
+    webhook_addr: Option<SocketAddr>,
+
+    /// TLS certificate of the admission webhook, the webhook is enabled when it is set
+    #[arg(long, env = "WEBHOOK_TLS_CERT")]
+    webhook_tls_cert: Option<PathBuf>,
+
+    /// TLS private key of the admission webhook
+    #[arg(long, env = "WEBHOOK_TLS_KEY")]
+    webhook_tls_key: Option<PathBuf>,
+
+    /// Time to wait the in-flight reconciles when shutting down [default: 25s]
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", value_parser = humantime::parse_duration)]
+    #[serde(default, with = "humantime_serde")]
+    shutdown_timeout: Option<Duration>,
+
+    /// Apply the CRDs generated from the code by server-side apply when starting, the
+    /// controller needs the permission to patch the CRDs [default: false]
+    #[arg(long, env = "INSTALL_CRD", num_args = 0..=1, default_missing_value = "true")]
+    install_crd: Option<bool>,
+
+    /// Comma separated CIDRs of the load balancer IPs which can be published by every Ddns, empty
+    /// means all IPs
+    #[arg(long, env = "ALLOW_CIDRS", value_delimiter = ',')]
+    allow_cidrs: Option<Vec<String>>,
+
+    /// Comma separated CIDRs of the load balancer IPs which are never published, it wins over
+    /// allow-cidrs
+    #[arg(long, env = "DENY_CIDRS", value_delimiter = ',')]
+    deny_cidrs: Option<Vec<String>>,
+
+    /// Only publish the load balancer IPs which are reachable from the internet [default: false]
+    #[arg(long, env = "PUBLIC_IPS_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    public_ips_only: Option<bool>,
+}
+
+impl Options {
+    /// Fill the unset options with the other options
+    fn or(self, other: Options) -> Options {
+        Options {
+            cf_email: self.cf_email.or(other.cf_email),
+            cf_key: self.cf_key.or(other.cf_key),
+            cf_token: self.cf_token.or(other.cf_token),
+            cf_key_file: self.cf_key_file.or(other.cf_key_file),
+            cf_token_file: self.cf_token_file.or(other.cf_token_file),
+            cf_api_url: self.cf_api_url.or(other.cf_api_url),
+            trace_exporter: self.trace_exporter.or(other.trace_exporter),
+            jaeger_agent: self.jaeger_agent.or(other.jaeger_agent),
+            otlp_endpoint: self.otlp_endpoint.or(other.otlp_endpoint),
+            trace_sample_ratio: self.trace_sample_ratio.or(other.trace_sample_ratio),
+            log_format: self.log_format.or(other.log_format),
+            default_ttl: self.default_ttl.or(other.default_ttl),
+            retry_delay: self.retry_delay.or(other.retry_delay),
+            no_ip_retry_delay: self.no_ip_retry_delay.or(other.no_ip_retry_delay),
+            conflict_recheck_delay: self.conflict_recheck_delay.or(other.conflict_recheck_delay),
+            queue_idle_timeout: self.queue_idle_timeout.or(other.queue_idle_timeout),
+            delete_credentials_timeout: self
+                .delete_credentials_timeout
+                .or(other.delete_credentials_timeout),
+            max_concurrent_reconciles: self
+                .max_concurrent_reconciles
+                .or(other.max_concurrent_reconciles),
+            cf_rate_limit: self.cf_rate_limit.or(other.cf_rate_limit),
+            cf_rate_burst: self.cf_rate_burst.or(other.cf_rate_burst),
+            health_addr: self.health_addr.or(other.health_addr),
+            webhook_addr: self.webhook_addr.or(other.webhook_addr),
+            webhook_tls_cert: self.webhook_tls_cert.or(other.webhook_tls_cert),
+            webhook_tls_key: self.webhook_tls_key.or(other.webhook_tls_key),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
+            install_crd: self.install_crd.or(other.install_crd),
+            allow_cidrs: self.allow_cidrs.or(other.allow_cidrs),
+            deny_cidrs: self.deny_cidrs.or(other.deny_cidrs),
+            public_ips_only: self.public_ips_only.or(other.public_ips_only),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceExporterKind {
+    None,
+    Jaeger,
+    OtlpGrpc,
+    OtlpHttp,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TraceExporter {
+    None,
+    Jaeger { agent: String },
+    OtlpGrpc { endpoint: String },
+    OtlpHttp { endpoint: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryConfig {
+    pub exporter: TraceExporter,
+    pub sample_ratio: f64,
+    pub log_format: LogFormat,
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub enum CfCredentials {
+    Token(String),
+    EmailKey { email: String, key: String },
+}
+
+impl Debug for CfCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CfCredentials::Token(_) => f.debug_tuple("Token").field(&"***").finish(),
+            CfCredentials::EmailKey { email, .. } => f
+                .debug_struct("EmailKey")
+                .field("email", email)
+                .field("key", &"***")
+                .finish(),
+        }
+    }
+}
+
+/// The file which the credentials are read from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CredentialsFile {
+    Token(PathBuf),
+    Key { email: String, path: PathBuf },
+}
+
+impl CredentialsFile {
+    pub fn path(&self) -> &Path {
+        match self {
+            CredentialsFile::Token(path) => path,
+            CredentialsFile::Key { path, .. } => path,
+        }
+    }
+
+    /// Read the credentials from the file, the surrounding whitespaces are trimmed
+    pub fn read(&self) -> Result<CfCredentials, ConfigError> {
+        let content = fs::read_to_string(self.path()).map_err(|source| ConfigError::ReadFile {
+            path: self.path().to_path_buf(),
+            source,
+        })?;
+
+        self.parse(&content)
+    }
+
+    pub fn parse(&self, content: &str) -> Result<CfCredentials, ConfigError> {
+        let content = content.trim().to_string();
+
+        match self {
+            CredentialsFile::Token(_) if content.is_empty() => Err(ConfigError::Invalid {
+                field: "cf-token-file",
+                reason: "file is empty".to_string(),
+            }),
+            CredentialsFile::Token(_) => Ok(CfCredentials::Token(content)),
+
+            CredentialsFile::Key { .. } if content.is_empty() => Err(ConfigError::Invalid {
+                field: "cf-key-file",
+                reason: "file is empty".to_string(),
+            }),
+            CredentialsFile::Key { email, .. } => Ok(CfCredentials::EmailKey {
+                email: email.clone(),
+                key: content,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WebhookConfig {
+    pub addr: SocketAddr,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// The validated controller config
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub credentials: CfCredentials,
+    /// The credentials are reloaded from the file when it is set
+    pub credentials_file: Option<CredentialsFile>,
+    /// The production API is used if it is None
+    pub cf_api_url: Option<Url>,
+    pub telemetry: TelemetryConfig,
+    pub default_ttl: u32,
+    pub retry_delay: Duration,
+    pub no_ip_retry_delay: Duration,
+    pub conflict_recheck_delay: Duration,
+    pub queue_idle_timeout: Duration,
+    pub delete_credentials_timeout: Duration,
+    pub max_concurrent_reconciles: usize,
+    /// Cloudflare API requests allowed in [`CF_RATE_LIMIT_PERIOD`]
+    pub cf_rate_limit: u32,
+    pub cf_rate_burst: u32,
+    pub health_addr: SocketAddr,
+    /// The admission webhook is disabled when it is None
+    pub webhook: Option<WebhookConfig>,
+    pub shutdown_timeout: Duration,
+    /// Apply the CRDs when starting
+    pub install_crd: bool,
+    /// Applied to the IPs of every Ddns and annotated service, together with the Ddns filter
+    pub ip_filter: IpFilter,
+}
+
+/// What the process does, selected by the subcommand
+#[derive(Debug)]
+pub enum Command {
+    /// Run the controller
+    Run(Box<Config>),
+    /// Print the CRDs generated from the code
+    PrintCrd,
+}
+
+impl Command {
+    /// Load the command from the process arguments, env and the config file
+    ///
+    /// The process exits with the usage when the arguments are invalid, or `--help` is set.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_args(Args::parse())
+    }
+
+    fn from_args(mut args: Args) -> Result<Self, ConfigError> {
+        match args.command.take() {
+            // the credentials are not needed to print the crd
+            Some(Subcommand::Crd {
+                command: CrdCommand::Print,
+            }) => Ok(Command::PrintCrd),
+
+            None => Ok(Command::Run(Box::new(Config::from_args(args)?))),
+        }
+    }
+}
+
+impl Config {
+    #[cfg(test)]
+    pub fn load_from<I, T>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Self::from_args(Args::try_parse_from(args)?)
+    }
+
+    fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let options = match &args.config {
+            None => args.options,
+            Some(path) => args.options.or(read_config_file(path)?),
+        };
+
+        Self::from_options(options)
+    }
+
+    fn from_options(options: Options) -> Result<Self, ConfigError> {
+        let credentials_file = match (
+            &options.cf_email,
+            options.cf_key_file,
+            options.cf_token_file,
+        ) {
+            (Some(email), Some(path), _) => Some(CredentialsFile::Key {
+                email: email.clone(),
+                path,
+            }),
+            (None, Some(_), None) => {
+                return Err(ConfigError::Incomplete("cf-key-file", "cf-email"))
+            }
+            (_, _, Some(path)) => Some(CredentialsFile::Token(path)),
+            (_, None, None) => None,
+        };
+
+        let credentials = match &credentials_file {
+            Some(credentials_file) => credentials_file.read()?,
+
+            None => credentials(options.cf_email, options.cf_key, options.cf_token)?,
+        };
+
+        let default_exporter = if cfg!(feature = "jaeger") {
+            TraceExporterKind::Jaeger
+        } else {
+            TraceExporterKind::None
+        };
+
+        let exporter = match options.trace_exporter.unwrap_or(default_exporter) {
+            TraceExporterKind::None => TraceExporter::None,
+            TraceExporterKind::Jaeger => TraceExporter::Jaeger {
+                agent: options
+                    .jaeger_agent
+                    .unwrap_or_else(|| DEFAULT_JAEGER_AGENT.to_string()),
+            },
+            TraceExporterKind::OtlpGrpc => TraceExporter::OtlpGrpc {
+                endpoint: options
+                    .otlp_endpoint
+                    .unwrap_or_else(|| DEFAULT_OTLP_GRPC_ENDPOINT.to_string()),
+            },
+            TraceExporterKind::OtlpHttp => TraceExporter::OtlpHttp {
+                endpoint: options
+                    .otlp_endpoint
+                    .unwrap_or_else(|| DEFAULT_OTLP_HTTP_ENDPOINT.to_string()),
+            },
+        };
+
+        let missing_feature = match exporter {
+            TraceExporter::Jaeger { .. } if !cfg!(feature = "jaeger") => Some("jaeger"),
+            TraceExporter::OtlpGrpc { .. } | TraceExporter::OtlpHttp { .. }
+                if !cfg!(feature = "otlp") =>
+            {
+                Some("otlp")
+            }
+            _ => None,
+        };
+        if let Some(feature) = missing_feature {
+            return Err(ConfigError::Invalid {
+                field: "trace-exporter",
+                reason: format!("ddns is built without the {} feature", feature),
+            });
+        }
+
+        let sample_ratio = options
+            .trace_sample_ratio
+            .unwrap_or(DEFAULT_TRACE_SAMPLE_RATIO);
+        if !(0.0..=1.0).contains(&sample_ratio) {
+            return Err(ConfigError::Invalid {
+                field: "trace-sample-ratio",
+                reason: format!("{} is not in 0..=1", sample_ratio),
+            });
+        }
+
+        let telemetry = TelemetryConfig {
+            exporter,
+            sample_ratio,
+            log_format: options.log_format.unwrap_or(LogFormat::Pretty),
+        };
+
+        let default_ttl = options.default_ttl.unwrap_or(DEFAULT_TTL);
+        check_ttl(default_ttl).map_err(|reason| ConfigError::Invalid {
+            field: "default-ttl",
+            reason,
+        })?;
+
+        let retry_delay = non_zero_duration(
+            "retry-delay",
+            options.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY),
+        )?;
+        let no_ip_retry_delay = non_zero_duration(
+            "no-ip-retry-delay",
+            options
+                .no_ip_retry_delay
+                .unwrap_or(DEFAULT_NO_IP_RETRY_DELAY),
+        )?;
+        let conflict_recheck_delay = non_zero_duration(
+            "conflict-recheck-delay",
+            options
+                .conflict_recheck_delay
+                .unwrap_or(DEFAULT_CONFLICT_RECHECK_DELAY),
+        )?;
+        let queue_idle_timeout = non_zero_duration(
+            "queue-idle-timeout",
+            options
+                .queue_idle_timeout
+                .unwrap_or(DEFAULT_QUEUE_IDLE_TIMEOUT),
+        )?;
+        let delete_credentials_timeout = non_zero_duration(
+            "delete-credentials-timeout",
+            options
+                .delete_credentials_timeout
+                .unwrap_or(DEFAULT_DELETE_CREDENTIALS_TIMEOUT),
+        )?;
+        let shutdown_timeout = non_zero_duration(
+            "shutdown-timeout",
+            options.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+        )?;
+
+        let max_concurrent_reconciles = options
+            .max_concurrent_reconciles
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_RECONCILES);
+        if max_concurrent_reconciles == 0 {
+            return Err(ConfigError::Invalid {
+                field: "max-concurrent-reconciles",
+                reason: "must be greater than 0".to_string(),
+            });
+        }
+
+        let cf_rate_limit = options.cf_rate_limit.unwrap_or(DEFAULT_CF_RATE_LIMIT);
+        let cf_rate_burst = options
+            .cf_rate_burst
+            .unwrap_or_else(|| DEFAULT_CF_RATE_BURST.min(cf_rate_limit / 2));
+        if cf_rate_burst == 0 || cf_rate_burst >= cf_rate_limit {
+            return Err(ConfigError::Invalid {
+                field: "cf-rate-burst",
+                reason: format!(
+                    "{} is not in 1..{} (cf-rate-limit)",
+                    cf_rate_burst, cf_rate_limit
+                ),
+            });
+        }
+
+        let webhook = match (options.webhook_tls_cert, options.webhook_tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(WebhookConfig {
+                addr: options
+                    .webhook_addr
+                    .unwrap_or_else(|| DEFAULT_WEBHOOK_ADDR.parse().unwrap()),
+                cert_path,
+                key_path,
+            }),
+
+            (Some(_), None) => {
+                return Err(ConfigError::Incomplete(
+                    "webhook-tls-cert",
+                    "webhook-tls-key",
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Incomplete(
+                    "webhook-tls-key",
+                    "webhook-tls-cert",
+                ))
+            }
+            (None, None) => None,
+        };
+
+        let ip_filter = IpFilter {
+            allow: parse_cidrs(&options.allow_cidrs.unwrap_or_default()).map_err(|reason| {
+                ConfigError::Invalid {
+                    field: "allow-cidrs",
+                    reason,
+                }
+            })?,
+            deny: parse_cidrs(&options.deny_cidrs.unwrap_or_default()).map_err(|reason| {
+                ConfigError::Invalid {
+                    field: "deny-cidrs",
+                    reason,
+                }
+            })?,
+            public_only: options.public_ips_only.unwrap_or(false),
+        };
+
+        // the url is joined with the relative endpoint paths, without the trailing slash the last
+        // path segment is replaced
+        let cf_api_url = options.cf_api_url.map(|mut url| {
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+
+            url
+        });
+
+        Ok(Self {
+            credentials,
+            credentials_file,
+            cf_api_url,
+            telemetry,
+            default_ttl,
+            retry_delay,
+            no_ip_retry_delay,
+            conflict_recheck_delay,
+            queue_idle_timeout,
+            delete_credentials_timeout,
+            max_concurrent_reconciles,
+            cf_rate_limit,
+            cf_rate_burst,
+            health_addr: options
+                .health_addr
+                .unwrap_or_else(|| DEFAULT_HEALTH_ADDR.parse().unwrap()),
+            webhook,
+            shutdown_timeout,
+            install_crd: options.install_crd.unwrap_or(false),
+            ip_filter,
+        })
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<Options, ConfigError> {
+    let content = fs::read(path).map_err(|source| ConfigError::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    serde_yaml::from_slice(&content).map_err(|source| ConfigError::ParseFile {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Select the credentials from the arguments
+pub fn credentials(
+    email: Option<String>,
+    key: Option<String>,
+    token: Option<String>,
+) -> Result<CfCredentials, ConfigError> {
+    // the (email, key) credentials are preferred, keep the same as before
+    match (email, key, token) {
+        (Some(email), Some(key), _) => Ok(CfCredentials::EmailKey { email, key }),
+        (Some(_), None, None) => Err(ConfigError::Incomplete("cf-email", "cf-key")),
+        (None, Some(_), None) => Err(ConfigError::Incomplete("cf-key", "cf-email")),
+        (_, _, Some(token)) => Ok(CfCredentials::Token(token)),
+        (None, None, None) => Err(ConfigError::MissingCredentials),
+    }
+}
+
+/// Check the ttl is accepted by cloudflare
+pub fn check_ttl(ttl: u32) -> Result<(), String> {
+    if ttl != AUTO_TTL && !(MIN_TTL..=MAX_TTL).contains(&ttl) {
+        return Err(format!(
+            "{} is not {} (automatic) or in {}..={}",
+            ttl, AUTO_TTL, MIN_TTL, MAX_TTL
+        ));
+    }
+
+    Ok(())
+}
+
+fn non_zero_duration(field: &'static str, duration: Duration) -> Result<Duration, ConfigError> {
+    if duration.is_zero() {
+        return Err(ConfigError::Invalid {
+            field,
+            reason: "must be greater than 0".to_string(),
+        });
+    }
+
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(yaml: &str) -> Options {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn default_values() {
+        let config = Config::from_options(options("cf-token: token")).unwrap();
+
+        assert_eq!(
+            config.credentials,
+            CfCredentials::Token("token".to_string())
+        );
+        #[cfg(feature = "jaeger")]
+        assert_eq!(
+            config.telemetry.exporter,
+            TraceExporter::Jaeger {
+                agent: DEFAULT_JAEGER_AGENT.to_string()
+            }
+        );
+        assert_eq!(config.telemetry.sample_ratio, DEFAULT_TRACE_SAMPLE_RATIO);
+        assert_eq!(config.telemetry.log_format, LogFormat::Pretty);
+        assert_eq!(config.default_ttl, DEFAULT_TTL);
+        assert_eq!(config.retry_delay, DEFAULT_RETRY_DELAY);
+        assert_eq!(
+            config.delete_credentials_timeout,
+            DEFAULT_DELETE_CREDENTIALS_TIMEOUT
+        );
+        assert_eq!(
+            config.max_concurrent_reconciles,
+            DEFAULT_MAX_CONCURRENT_RECONCILES
+        );
+        assert_eq!(config.cf_rate_limit, DEFAULT_CF_RATE_LIMIT);
+        assert_eq!(config.cf_rate_burst, DEFAULT_CF_RATE_BURST);
+        assert!(config.webhook.is_none());
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let config = Config::from_options(options(
+            r#"
+cf-email: admin@example.com
+cf-key: key
+default-ttl: 300
+retry-delay: 1m
+health-addr: 127.0.0.1:9090
+webhook-tls-cert: /tls/tls.crt
+webhook-tls-key: /tls/tls.key
+"#,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            config.credentials,
+            CfCredentials::EmailKey {
+                email: "admin@example.com".to_string(),
+                key: "key".to_string()
+            }
+        );
+        assert_eq!(config.default_ttl, 300);
+        assert_eq!(config.retry_delay, Duration::from_secs(60));
+        assert_eq!(config.health_addr, "127.0.0.1:9090".parse().unwrap());
+        assert_eq!(
+            config.webhook,
+            Some(WebhookConfig {
+                addr: DEFAULT_WEBHOOK_ADDR.parse().unwrap(),
+                cert_path: "/tls/tls.crt".into(),
+                key_path: "/tls/tls.key".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn ip_filter() {
+        let args = Args::try_parse_from([
+            "ddns",
+            "--deny-cidrs",
+            "10.0.0.0/8,192.168.0.0/16",
+            "--public-ips-only",
+        ])
+        .unwrap();
+
+        let config = Config::from_options(
+            args.options
+                .or(options("cf-token: token\nallow-cidrs: [203.0.113.0/24]")),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.ip_filter,
+            IpFilter {
+                allow: parse_cidrs(&["203.0.113.0/24".to_string()]).unwrap(),
+                deny: parse_cidrs(&["10.0.0.0/8".to_string(), "192.168.0.0/16".to_string()])
+                    .unwrap(),
+                public_only: true,
+            }
+        );
+
+        let err = Config::from_options(options("cf-token: token\ndeny-cidrs: [10.0.0.0/33]"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "deny-cidrs",
+                ..
+            }
+        ));
+
+        let config = Config::from_options(options("cf-token: token")).unwrap();
+        assert_eq!(config.ip_filter, IpFilter::default());
+    }
+
+    #[test]
+    fn unknown_key_in_config_file() {
+        assert!(serde_yaml::from_str::<Options>("cf-tokne: token").is_err());
+    }
+
+    #[test]
+    fn args_override_config_file() {
+        let args =
+            Args::try_parse_from(["ddns", "--default-ttl", "600", "--retry-delay", "10s"]).unwrap();
+
+        let config = Config::from_options(
+            args.options
+                .or(options("cf-token: token\ndefault-ttl: 300")),
+        )
+        .unwrap();
+
+        assert_eq!(config.default_ttl, 600);
+        assert_eq!(config.retry_delay, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn crd_command() {
+        // the credentials are not needed to print the crd
+        let command = Command::from_args(Args::try_parse_from(["ddns", "crd", "print"]).unwrap());
+        assert!(matches!(command, Ok(Command::PrintCrd)));
+
+        let command = Command::from_args(
+            Args::try_parse_from(["ddns", "--cf-token", "token", "--install-crd"]).unwrap(),
+        );
+        assert!(matches!(command, Ok(Command::Run(config)) if config.install_crd));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let err = Config::from_options(options("default-ttl: 300")).unwrap_err();
+        assert!(matches!(err, ConfigError::MissingCredentials));
+
+        let err = Config::from_options(options("cf-email: admin@example.com")).unwrap_err();
+        assert!(matches!(err, ConfigError::Incomplete("cf-email", "cf-key")));
+
+        let err = Config::from_options(options("cf-token: token\ndefault-ttl: 30")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "default-ttl",
+                ..
+            }
+        ));
+
+        let err = Config::from_options(options("cf-token: token\nretry-delay: 0s")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "retry-delay",
+                ..
+            }
+        ));
+
+        let err = Config::from_options(options("cf-token: token\ncf-rate-limit: 1")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "cf-rate-burst",
+                ..
+            }
+        ));
+
+        let err = Config::from_options(options("cf-token: token\nwebhook-tls-cert: /tls.crt"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Incomplete("webhook-tls-cert", "webhook-tls-key")
+        ));
+    }
+
+    #[test]
+    fn credentials_file() {
+        let path = std::env::temp_dir().join(format!("ddns-config-test-{}", std::process::id()));
+        fs::write(&path, "token-in-file\n").unwrap();
+
+        let config = Config::from_options(options(&format!(
+            "cf-token: token\ncf-token-file: {}",
+            path.display()
+        )))
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config.credentials,
+            CfCredentials::Token("token-in-file".to_string())
+        );
+        assert_eq!(config.credentials_file, Some(CredentialsFile::Token(path)));
+
+        let err = Config::from_options(options("cf-key-file: /cf/key")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Incomplete("cf-key-file", "cf-email")
+        ));
+
+        let key_file = CredentialsFile::Key {
+            email: "admin@example.com".to_string(),
+            path: "/cf/key".into(),
+        };
+        assert!(key_file.parse(" \n").is_err());
+        assert_eq!(
+            key_file.parse("key\n").unwrap(),
+            CfCredentials::EmailKey {
+                email: "admin@example.com".to_string(),
+                key: "key".to_string()
+            }
+        );
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn telemetry_config() {
+        let config = Config::from_options(options(
+            r#"
+cf-token: token
+trace-exporter: otlp-http
+trace-sample-ratio: 0.1
+log-format: json
+"#,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            config.telemetry,
+            TelemetryConfig {
+                exporter: TraceExporter::OtlpHttp {
+                    endpoint: DEFAULT_OTLP_HTTP_ENDPOINT.to_string()
+                },
+                sample_ratio: 0.1,
+                log_format: LogFormat::Json,
+            }
+        );
+
+        let err =
+            Config::from_options(options("cf-token: token\ntrace-sample-ratio: 2")).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid {
+                field: "trace-sample-ratio",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn cf_api_url() {
+        let config = Config::from_options(options("cf-token: token")).unwrap();
+        assert_eq!(config.cf_api_url, None);
+
+        for url in [
+            "http://127.0.0.1:8080/client/v4",
+            "http://127.0.0.1:8080/client/v4/",
+        ] {
+            let config =
+                Config::from_options(options(&format!("cf-token: token\ncf-api-url: {}", url)))
+                    .unwrap();
+
+            assert_eq!(
+                config.cf_api_url.unwrap().as_str(),
+                "http://127.0.0.1:8080/client/v4/"
+            );
+        }
+    }
+
+    #[test]
+    fn credentials_debug_hides_secret() {
+        let debug = format!(
+            "{:?}",
+            CfCredentials::EmailKey {
+                email: "admin@example.com".to_string(),
+                key: "secret".to_string()
+            }
+        );
+
+        assert!(!debug.contains("secret"));
+    }
+}
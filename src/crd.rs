@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::api::{Patch, PatchParams};
+use kube::runtime::wait::{await_condition, conditions};
+use kube::{Api, Client, CustomResourceExt, ResourceExt};
+use tap::TapFallible;
+use tokio::time;
+use tracing::{error, info, instrument};
+
+use crate::spec::{v2, Ddns, DnsProviderConfig};
+use crate::webhook::CONVERT_PATH;
+
+/// The field manager of the server-side apply
+const FIELD_MANAGER: &str = "ddns";
+const ESTABLISH_TIMEOUT: Duration = Duration::from_secs(30);
+/// The service of the webhook in the deployment manifest
+const WEBHOOK_SERVICE_NAMESPACE: &str = "ddns-system";
+const WEBHOOK_SERVICE_NAME: &str = "ddns-webhook";
+
+/// The CRDs generated from the spec types, they are the source of truth of the schema
+pub fn crds() -> Vec<CustomResourceDefinition> {
+    vec![ddns_crd(), DnsProviderConfig::crd()]
+}
+
+/// The Ddns CRD serves all versions, v1 is the storage version and the others are converted by
+/// the webhook
+fn ddns_crd() -> CustomResourceDefinition {
+    let mut crd = Ddns::crd();
+
+    crd.spec.versions.extend(
+        v2::Ddns::crd()
+            .spec
+            .versions
+            .into_iter()
+            .map(|mut version| {
+                version.storage = false;
+
+                version
+            }),
+    );
+
+    // the caBundle is injected when deploying, the same as the validating webhook
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".to_string(),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    namespace: WEBHOOK_SERVICE_NAMESPACE.to_string(),
+                    name: WEBHOOK_SERVICE_NAME.to_string(),
+                    path: Some(CONVERT_PATH.to_string()),
+                    port: None,
+                }),
+                ..Default::default()
+            }),
+            conversion_review_versions: vec!["v1".to_string()],
+        }),
+    });
+
+    crd
+}
+
+/// Render the CRDs as a multi-document YAML
+pub fn crds_yaml() -> Result<String> {
+    let documents = crds()
+        .iter()
+        .map(serde_yaml::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(documents.join("---\n"))
+}
+
+/// Apply the CRDs by server-side apply, and wait until the api server serves them
+#[instrument(err, skip(client))]
+pub async fn install_crds(client: Client) -> Result<()> {
+    let crd_api: Api<CustomResourceDefinition> = Api::all(client);
+
+    for crd in crds() {
+        let name = crd.name();
+
+        // take over the fields applied by kubectl before
+        crd_api
+            .patch(
+                &name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&crd),
+            )
+            .await
+            .tap_err(|err| error!(%name, %err, "apply crd failed"))?;
+
+        info!(%name, "apply crd done");
+
+        time::timeout(
+            ESTABLISH_TIMEOUT,
+            await_condition(crd_api.clone(), &name, conditions::is_crd_established()),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("crd {} is not established in time", name))??;
+
+        info!(%name, "crd is established");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[test]
+    fn crd_yaml_matches_code() {
+        let manifest_crds = serde_yaml::Deserializer::from_str(include_str!("../crd.yaml"))
+            .map(serde_yaml::Value::deserialize)
+            .map(Result::unwrap)
+            .filter(|document| document["kind"] == "CustomResourceDefinition")
+            .map(|document| serde_yaml::from_value::<CustomResourceDefinition>(document).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            manifest_crds,
+            crds(),
+            "crd.yaml is out of date, regenerate it by `ddns crd print`"
+        );
+    }
+}
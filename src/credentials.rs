@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{fs, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, warn};
+
+use crate::cf_dns::CfDns;
+use crate::config::CredentialsFile;
+
+/// The mounted Secret is updated by kubelet with a symlink swap, polling the content is more
+/// reliable than the inotify events
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Poll the credentials file until the shutdown is cancelled, swap the client of the cf_dns when
+/// the content changes
+#[instrument(skip(cf_dns, shutdown))]
+pub async fn watch_credentials_file(
+    credentials_file: CredentialsFile,
+    cf_dns: CfDns,
+    shutdown: CancellationToken,
+) {
+    // the credentials are read when loading the config
+    let mut last_content = fs::read_to_string(credentials_file.path())
+        .await
+        .unwrap_or_default();
+
+    info!("start to watch credentials file");
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                info!("shutdown, stop watching credentials file");
+
+                return;
+            }
+
+            _ = time::sleep(RELOAD_INTERVAL) => {}
+        }
+
+        match reload(&credentials_file, &cf_dns, &mut last_content).await {
+            Err(err) => error!(%err, "reload credentials file failed, keep the old credentials"),
+
+            Ok(false) => {}
+
+            Ok(true) => {
+                if let Err(err) = cf_dns.verify_credentials().await {
+                    warn!(%err, "verify reloaded credentials failed");
+                }
+            }
+        }
+    }
+}
+
+/// Reload the credentials if the content of the file changes, return if the client is swapped
+async fn reload(
+    credentials_file: &CredentialsFile,
+    cf_dns: &CfDns,
+    last_content: &mut String,
+) -> Result<bool> {
+    let content = fs::read_to_string(credentials_file.path()).await?;
+
+    if content == *last_content {
+        return Ok(false);
+    }
+
+    let credentials = credentials_file.parse(&content)?;
+
+    cf_dns.set_credentials(&credentials)?;
+
+    *last_content = content;
+
+    info!("credentials file changed, reload credentials done");
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CfCredentials;
+
+    #[tokio::test]
+    async fn reload_when_changed() {
+        let path =
+            std::env::temp_dir().join(format!("ddns-credentials-test-{}", std::process::id()));
+        let credentials_file = CredentialsFile::Token(path.clone());
+
+        fs::write(&path, "old-token").await.unwrap();
+        let cf_dns = CfDns::new(&CfCredentials::Token("old-token".to_string()), 120)
+            .await
+            .unwrap();
+        let mut last_content = "old-token".to_string();
+
+        let result = reload(&credentials_file, &cf_dns, &mut last_content).await;
+        assert!(!result.unwrap());
+
+        fs::write(&path, "new-token\n").await.unwrap();
+        let result = reload(&credentials_file, &cf_dns, &mut last_content).await;
+        assert!(result.unwrap());
+        assert_eq!(last_content, "new-token\n");
+
+        // an empty file keeps the old credentials, and is retried next time
+        fs::write(&path, "").await.unwrap();
+        let result = reload(&credentials_file, &cf_dns, &mut last_content).await;
+        assert!(result.is_err());
+        assert_eq!(last_content, "new-token\n");
+
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::{Api, Client, ResourceExt};
+use serde_json::json;
+
+use crate::cf_dns::{CfDns, RecordKind};
+use crate::cf_dns_pool::CfDnsPool;
+use crate::config::{
+    self, CredentialsFile, CF_RATE_LIMIT_PERIOD, DEFAULT_CF_RATE_BURST, DEFAULT_CF_RATE_LIMIT,
+    DEFAULT_TTL,
+};
+use crate::ddns::{desired_addresses, FINALIZER};
+use crate::ip_filter::{parse_cidrs, IpFilter};
+use crate::rate_limit::RateLimiter;
+use crate::spec::Ddns;
+
+/// Changing the annotation makes the controller receive a watch event of the Ddns
+const RESYNC_ANNOTATION: &str = "api.sherlockholo.io/resync-at";
+
+/// Inspect and repair the Ddns published by the controller
+#[derive(Debug, Parser)]
+#[command(name = "ddnsctl", version)]
+struct Args {
+    #[command(flatten)]
+    credentials: CredentialsArgs,
+
+    #[command(flatten)]
+    ip_filter: IpFilterArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The default credentials, the same as the controller
+#[derive(Debug, clap::Args)]
+struct CredentialsArgs {
+    /// Cloudflare account email, used with cf-key
+    #[arg(long, env = "CF_DNS_EMAIL", global = true)]
+    cf_email: Option<String>,
+
+    /// Cloudflare global API key, used with cf-email
+    #[arg(long, env = "CF_DNS_KEY", hide_env_values = true, global = true)]
+    cf_key: Option<String>,
+
+    /// Cloudflare API token
+    #[arg(long, env = "CF_DNS_TOKEN", hide_env_values = true, global = true)]
+    cf_token: Option<String>,
+
+    /// File containing the Cloudflare API token, preferred over cf-token
+    #[arg(long, env = "CF_DNS_TOKEN_FILE", global = true)]
+    cf_token_file: Option<PathBuf>,
+}
+
+/// The IP filter of the controller, so the desired IPs are filtered like the controller does
+#[derive(Debug, clap::Args)]
+struct IpFilterArgs {
+    /// Comma separated CIDRs of the load balancer IPs which can be published by every Ddns, empty
+    /// means all IPs
+    #[arg(long, env = "ALLOW_CIDRS", value_delimiter = ',', global = true)]
+    allow_cidrs: Vec<String>,
+
+    /// Comma separated CIDRs of the load balancer IPs which are never published, it wins over
+    /// allow-cidrs
+    #[arg(long, env = "DENY_CIDRS", value_delimiter = ',', global = true)]
+    deny_cidrs: Vec<String>,
+
+    /// Only publish the load balancer IPs which are reachable from the internet [default: false]
+    #[arg(
+        long,
+        env = "PUBLIC_IPS_ONLY",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        global = true
+    )]
+    public_ips_only: Option<bool>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the Ddns with the desired IPs and the published records
+    List {
+        /// Only list the Ddns in the namespace
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+
+    /// Show the records which the controller would add (+) or remove (-)
+    Diff {
+        #[arg(short, long)]
+        namespace: Option<String>,
+
+        /// Only show the Ddns with the name, the namespace is required
+        #[arg(requires = "namespace")]
+        name: Option<String>,
+    },
+
+    /// Make the controller reconcile the Ddns again
+    Resync {
+        #[arg(short, long)]
+        namespace: String,
+
+        name: String,
+    },
+
+    /// Remove the finalizer of a Ddns which is stuck in deleting, the records are removed first
+    RemoveFinalizer {
+        #[arg(short, long)]
+        namespace: String,
+
+        name: String,
+
+        /// Keep the published records, they are not managed by any Ddns after that
+        #[arg(long)]
+        keep_records: bool,
+    },
+}
+
+/// The state of a Ddns in the cluster and in cloudflare
+struct Inspection {
+    ddns: Ddns,
+    desired: Result<Vec<IpAddr>>,
+    published: Result<Vec<IpAddr>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Change {
+    Add,
+    Remove,
+    Keep,
+}
+
+pub async fn run() -> Result<()> {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let client = Client::try_default().await?;
+    let credentials = args.credentials;
+
+    match args.command {
+        Command::List { namespace } => {
+            let cf_dns_pool = credentials.cf_dns_pool(client.clone()).await?;
+            let ip_filter = args.ip_filter.ip_filter()?;
+
+            list(client, &cf_dns_pool, &ip_filter, namespace.as_deref()).await
+        }
+
+        Command::Diff { namespace, name } => {
+            let cf_dns_pool = credentials.cf_dns_pool(client.clone()).await?;
+            let ip_filter = args.ip_filter.ip_filter()?;
+
+            diff(
+                client,
+                &cf_dns_pool,
+                &ip_filter,
+                namespace.as_deref(),
+                name.as_deref(),
+            )
+            .await
+        }
+
+        // only the Ddns is changed, the cloudflare credentials are not needed
+        Command::Resync { namespace, name } => resync(client, &namespace, &name).await,
+
+        Command::RemoveFinalizer {
+            namespace,
+            name,
+            keep_records,
+        } => {
+            let cf_dns_pool = credentials.cf_dns_pool(client.clone()).await?;
+
+            remove_finalizer(client, &cf_dns_pool, &namespace, &name, keep_records).await
+        }
+    }
+}
+
+impl CredentialsArgs {
+    async fn cf_dns_pool(self, client: Client) -> Result<CfDnsPool> {
+        let credentials = match self.cf_token_file {
+            Some(path) => CredentialsFile::Token(path).read()?,
+            None => config::credentials(self.cf_email, self.cf_key, self.cf_token)?,
+        };
+
+        let rate_limiter = RateLimiter::new(
+            DEFAULT_CF_RATE_LIMIT,
+            CF_RATE_LIMIT_PERIOD,
+            DEFAULT_CF_RATE_BURST,
+        );
+
+        // no record is created, the ttl is not used
+        let cf_dns = CfDns::new(&credentials, DEFAULT_TTL)
+            .await?
+            .with_rate_limiter(rate_limiter.clone());
+
+        Ok(CfDnsPool::new(client, cf_dns, DEFAULT_TTL, rate_limiter))
+    }
+}
+
+impl IpFilterArgs {
+    fn ip_filter(self) -> Result<IpFilter> {
+        Ok(IpFilter {
+            allow: parse_cidrs(&self.allow_cidrs)
+                .map_err(|reason| anyhow::anyhow!("allow-cidrs is invalid: {}", reason))?,
+            deny: parse_cidrs(&self.deny_cidrs)
+                .map_err(|reason| anyhow::anyhow!("deny-cidrs is invalid: {}", reason))?,
+            public_only: self.public_ips_only.unwrap_or(false),
+        })
+    }
+}
+
+async fn list(
+    client: Client,
+    cf_dns_pool: &CfDnsPool,
+    ip_filter: &IpFilter,
+    namespace: Option<&str>,
+) -> Result<()> {
+    let inspections = inspect_all(client, cf_dns_pool, ip_filter, namespace, None).await?;
+
+    println!(
+        "{:<20} {:<20} {:<32} {:<10} {:<32} {:<32} SYNCED",
+        "NAMESPACE", "NAME", "DOMAIN", "STATUS", "DESIRED", "PUBLISHED"
+    );
+
+    for inspection in inspections {
+        let ddns = &inspection.ddns;
+        let synced = match (&inspection.desired, &inspection.published) {
+            (Ok(desired), Ok(published)) => diff_ips(desired, published)
+                .iter()
+                .all(|(change, _)| *change == Change::Keep)
+                .to_string(),
+            _ => "unknown".to_string(),
+        };
+
+        println!(
+            "{:<20} {:<20} {:<32} {:<10} {:<32} {:<32} {}",
+            ddns.namespace().unwrap_or_default(),
+            ddns.name(),
+            ddns.spec.domain,
+            ddns.status
+                .as_ref()
+                .map(|status| status.status.as_str())
+                .unwrap_or_default(),
+            format_ips(&inspection.desired),
+            format_ips(&inspection.published),
+            synced
+        );
+    }
+
+    Ok(())
+}
+
+async fn diff(
+    client: Client,
+    cf_dns_pool: &CfDnsPool,
+    ip_filter: &IpFilter,
+    namespace: Option<&str>,
+    name: Option<&str>,
+) -> Result<()> {
+    let inspections = inspect_all(client, cf_dns_pool, ip_filter, namespace, name).await?;
+
+    for inspection in inspections {
+        let ddns = &inspection.ddns;
+
+        println!(
+            "{}/{} {}",
+            ddns.namespace().unwrap_or_default(),
+            ddns.name(),
+            ddns.spec.domain
+        );
+
+        match (&inspection.desired, &inspection.published) {
+            (Err(err), _) => println!("  get desired ips failed: {:#}", err),
+            (_, Err(err)) => println!("  get published records failed: {:#}", err),
+
+            (Ok(desired), Ok(published)) => {
+                for (change, ip) in diff_ips(desired, published) {
+                    let mark = match change {
+                        Change::Add => '+',
+                        Change::Remove => '-',
+                        Change::Keep => ' ',
+                    };
+
+                    println!("  {} {}", mark, ip);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn resync(client: Client, namespace: &str, name: &str) -> Result<()> {
+    let ddns_api: Api<Ddns> = Api::namespaced(client, namespace);
+
+    let patch = json!({
+        "metadata": {
+            "annotations": {
+                RESYNC_ANNOTATION: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+            }
+        }
+    });
+
+    ddns_api
+        .patch(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await?;
+
+    println!("ddns {}/{} will be reconciled", namespace, name);
+
+    Ok(())
+}
+
+async fn remove_finalizer(
+    client: Client,
+    cf_dns_pool: &CfDnsPool,
+    namespace: &str,
+    name: &str,
+    keep_records: bool,
+) -> Result<()> {
+    let ddns_api: Api<Ddns> = Api::namespaced(client, namespace);
+    let ddns = ddns_api.get(name).await?;
+
+    if !ddns
+        .finalizers()
+        .iter()
+        .any(|finalizer| finalizer == FINALIZER)
+    {
+        println!("ddns {}/{} has no finalizer", namespace, name);
+
+        return Ok(());
+    }
+
+    // the controller adds the finalizer back if the ddns is not being deleted
+    if ddns.metadata.deletion_timestamp.is_none() {
+        return Err(anyhow::anyhow!(
+            "ddns {}/{} is not being deleted, delete it first",
+            namespace,
+            name
+        ));
+    }
+
+    let status = ddns.status.clone().unwrap_or_default();
+
+    if keep_records {
+        println!("keep the records of {}", status.domain);
+    } else if !status.domain.is_empty() {
+        let cf_dns = cf_dns_pool
+            .get_published(namespace, &ddns.spec, &status)
+            .await?;
+
+        cf_dns
+            .remove_dns_records(&status.domain, &status.zone, RecordKind::A)
+            .await?;
+
+        for srv_name in &status.srv_records {
+            cf_dns
+                .remove_dns_records(srv_name, &status.zone, RecordKind::SRV)
+                .await?;
+        }
+
+        println!("remove the records of {}", status.domain);
+    }
+
+    let finalizers = ddns
+        .finalizers()
+        .iter()
+        .filter(|finalizer| *finalizer != FINALIZER)
+        .collect::<Vec<_>>();
+
+    // the resource version makes the patch fail if the ddns is changed by others meanwhile
+    let patch = json!({
+        "metadata": {
+            "resourceVersion": ddns.resource_version(),
+            "finalizers": finalizers
+        }
+    });
+
+    ddns_api
+        .patch(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await?;
+
+    println!("remove the finalizer of ddns {}/{}", namespace, name);
+
+    Ok(())
+}
+
+async fn inspect_all(
+    client: Client,
+    cf_dns_pool: &CfDnsPool,
+    ip_filter: &IpFilter,
+    namespace: Option<&str>,
+    name: Option<&str>,
+) -> Result<Vec<Inspection>> {
+    let ddns_api: Api<Ddns> = match namespace {
+        None => Api::all(client.clone()),
+        Some(namespace) => Api::namespaced(client.clone(), namespace),
+    };
+
+    let mut ddns_list = match name {
+        None => ddns_api.list(&ListParams::default()).await?.items,
+        Some(name) => vec![ddns_api.get(name).await?],
+    };
+
+    ddns_list.sort_by_key(|ddns| (ddns.namespace(), ddns.name()));
+
+    let mut inspections = Vec::with_capacity(ddns_list.len());
+
+    for ddns in ddns_list {
+        let namespace = ddns.namespace().unwrap_or_default();
+
+        // the status is only changed locally, like the controller selects the failover tier
+        let mut status = ddns.status.clone().unwrap_or_default();
+        let desired = desired_addresses(
+            client.clone(),
+            &namespace,
+            &ddns.spec,
+            &mut status,
+            ip_filter,
+        )
+        .await
+        .map(|desired| desired.ips)
+        .map_err(anyhow::Error::from);
+
+        let published = published_ips(cf_dns_pool, &namespace, &ddns).await;
+
+        inspections.push(Inspection {
+            ddns,
+            desired,
+            published,
+        });
+    }
+
+    Ok(inspections)
+}
+
+/// The A and AAAA records of the published domain, they are read with the credentials they are
+/// published with
+async fn published_ips(
+    cf_dns_pool: &CfDnsPool,
+    namespace: &str,
+    ddns: &Ddns,
+) -> Result<Vec<IpAddr>> {
+    let status = match ddns
+        .status
+        .as_ref()
+        .filter(|status| !status.domain.is_empty())
+    {
+        None => return Ok(vec![]),
+        Some(status) => status,
+    };
+
+    let cf_dns = cf_dns_pool
+        .get_published(namespace, &ddns.spec, status)
+        .await?;
+
+    let mut ips = cf_dns
+        .get_dns_record(&status.domain, &status.zone, RecordKind::A)
+        .await?;
+    ips.extend(
+        cf_dns
+            .get_dns_record(&status.domain, &status.zone, RecordKind::AAAA)
+            .await?,
+    );
+
+    Ok(ips)
+}
+
+/// Compare the desired IPs with the published records, sorted by the IP
+fn diff_ips(desired: &[IpAddr], published: &[IpAddr]) -> Vec<(Change, IpAddr)> {
+    let desired = desired.iter().copied().collect::<BTreeSet<_>>();
+    let published = published.iter().copied().collect::<BTreeSet<_>>();
+
+    desired
+        .union(&published)
+        .map(|ip| {
+            let change = match (desired.contains(ip), published.contains(ip)) {
+                (true, false) => Change::Add,
+                (false, true) => Change::Remove,
+                _ => Change::Keep,
+            };
+
+            (change, *ip)
+        })
+        .collect()
+}
+
+fn format_ips(ips: &Result<Vec<IpAddr>>) -> String {
+    match ips {
+        Err(_) => "<error>".to_string(),
+        Ok(ips) if ips.is_empty() => "<none>".to_string(),
+        Ok(ips) => ips
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use k8s_openapi::api::core::v1::{
+        LoadBalancerIngress, LoadBalancerStatus, Service, ServiceSpec, ServiceStatus,
+    };
+
+    use super::*;
+    use crate::config::CfCredentials;
+    use crate::fake_cloudflare::FakeCloudflare;
+    use crate::fake_kube::FakeKube;
+    use crate::spec::{DdnsSpec, DdnsStatus, IpFilterSpec};
+
+    const ZONE: &str = "example.com";
+    const NAMESPACE: &str = "default";
+
+    #[test]
+    fn diff_desired_and_published() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            diff_ips(
+                &[ip("10.0.0.2"), ip("10.0.0.1"), ip("10.0.0.1")],
+                &[ip("10.0.0.3"), ip("10.0.0.2")]
+            ),
+            [
+                (Change::Add, ip("10.0.0.1")),
+                (Change::Keep, ip("10.0.0.2")),
+                (Change::Remove, ip("10.0.0.3"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn inspect_like_controller() {
+        let kube = FakeKube::new();
+        let cloudflare = FakeCloudflare::start();
+        cloudflare.add_zone(ZONE);
+
+        let public_ip = IpAddr::from([1, 1, 1, 1]);
+        let private_ip = IpAddr::from([10, 0, 0, 1]);
+        let v6_ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let labels = BTreeMap::from([("app".to_string(), "web".to_string())]);
+
+        let mut service = Service {
+            spec: Some(ServiceSpec {
+                type_: Some("LoadBalancer".to_string()),
+                ..Default::default()
+            }),
+            status: Some(ServiceStatus {
+                load_balancer: Some(LoadBalancerStatus {
+                    ingress: Some(
+                        [public_ip, private_ip]
+                            .iter()
+                            .map(|ip| LoadBalancerIngress {
+                                ip: Some(ip.to_string()),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    ),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        service.metadata.name = Some("web".to_string());
+        service.metadata.namespace = Some(NAMESPACE.to_string());
+        service.metadata.labels = Some(labels.clone());
+        kube.create(&service);
+
+        // the domain is being moved, the published records are still in the old domain
+        let mut ddns = Ddns::new(
+            "web",
+            DdnsSpec {
+                selector: labels.into_iter().collect::<HashMap<_, _>>(),
+                domain: "new.example.com".to_string(),
+                zone: ZONE.to_string(),
+                ip_filter: Some(IpFilterSpec {
+                    public_only: true,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        ddns.metadata.namespace = Some(NAMESPACE.to_string());
+        ddns.status = Some(DdnsStatus {
+            domain: "www.example.com".to_string(),
+            zone: ZONE.to_string(),
+            ..Default::default()
+        });
+        kube.create(&ddns);
+
+        cloudflare.add_record(ZONE, "www.example.com", public_ip);
+        cloudflare.add_record(ZONE, "www.example.com", v6_ip);
+
+        let rate_limiter = RateLimiter::new(
+            DEFAULT_CF_RATE_LIMIT,
+            CF_RATE_LIMIT_PERIOD,
+            DEFAULT_CF_RATE_BURST,
+        );
+        let cf_dns = CfDns::new_with_api_url(
+            &CfCredentials::Token("token".to_string()),
+            DEFAULT_TTL,
+            Some(cloudflare.api_url()),
+        )
+        .await
+        .unwrap();
+        let cf_dns_pool = CfDnsPool::new(kube.client(), cf_dns, DEFAULT_TTL, rate_limiter);
+
+        let inspections = inspect_all(
+            kube.client(),
+            &cf_dns_pool,
+            &IpFilter::default(),
+            Some(NAMESPACE),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(inspections.len(), 1);
+        assert_eq!(inspections[0].desired.as_ref().unwrap(), &[public_ip]);
+        assert_eq!(
+            inspections[0].published.as_ref().unwrap(),
+            &[public_ip, v6_ip]
+        );
+    }
+}
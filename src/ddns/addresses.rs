@@ -0,0 +1,88 @@
+use std::net::IpAddr;
+
+use crate::spec::AddressMode;
+
+/// Parse the additional addresses of the Ddns
+pub fn parse_addresses(addresses: &[String]) -> Result<Vec<IpAddr>, String> {
+    addresses
+        .iter()
+        .map(|address| {
+            address
+                .trim()
+                .parse()
+                .map_err(|err| format!("address {:?} is invalid: {}", address, err))
+        })
+        .collect()
+}
+
+/// Combine the load balancer IPs and the additional addresses by the mode, the load balancer IPs
+/// come first and the duplicated IPs are removed. The IP filters are applied to the result.
+pub fn combine_addresses(
+    mode: AddressMode,
+    lb_ips: Vec<IpAddr>,
+    additional_addresses: Vec<IpAddr>,
+) -> Vec<IpAddr> {
+    let ips = match mode {
+        AddressMode::Merge => lb_ips.into_iter().chain(additional_addresses).collect(),
+        AddressMode::Override => additional_addresses,
+    };
+
+    let mut combined = Vec::with_capacity(ips.len());
+
+    for ip in ips {
+        if !combined.contains(&ip) {
+            combined.push(ip);
+        }
+    }
+
+    combined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_addresses(&["1.1.1.1".to_string(), " 2001:db8::1 ".to_string()]),
+            Ok(ips(&["1.1.1.1", "2001:db8::1"]))
+        );
+        assert!(parse_addresses(&["1.1.1.0/24".to_string()]).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        assert_eq!(
+            combine_addresses(
+                AddressMode::Merge,
+                ips(&["1.1.1.1", "2.2.2.2"]),
+                ips(&["3.3.3.3", "1.1.1.1"])
+            ),
+            ips(&["1.1.1.1", "2.2.2.2", "3.3.3.3"])
+        );
+
+        // the additional addresses are published without any load balancer IP
+        assert_eq!(
+            combine_addresses(AddressMode::Merge, vec![], ips(&["3.3.3.3"])),
+            ips(&["3.3.3.3"])
+        );
+    }
+
+    #[test]
+    fn override_lb_ips() {
+        assert_eq!(
+            combine_addresses(
+                AddressMode::Override,
+                ips(&["1.1.1.1"]),
+                ips(&["3.3.3.3", "3.3.3.3"])
+            ),
+            ips(&["3.3.3.3"])
+        );
+        assert!(combine_addresses(AddressMode::Override, ips(&["1.1.1.1"]), vec![]).is_empty());
+    }
+}
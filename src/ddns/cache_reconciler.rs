@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+use kube::runtime::reflector::{ObjectRef, Store};
+use tracing::{debug, info, instrument};
+
+use crate::ddns::Reconcile;
+use crate::spec::Ddns;
+
+/// Reconcile the latest known Ddns in the cache instead of the snapshot in the event.
+///
+/// The events from the retry queue or the service trigger may carry a snapshot captured long
+/// ago, applying it could roll back a newer spec. The cache is fed by the ddns watch, so when it
+/// is behind the snapshot, the watch delivers the newer object and reconciles it again soon.
+#[derive(Clone)]
+pub struct CacheReconciler<R> {
+    inner_reconciler: R,
+    store: Store<Ddns>,
+}
+
+impl<R> CacheReconciler<R> {
+    pub fn new(reconciler: R, store: Store<Ddns>) -> Self {
+        Self {
+            inner_reconciler: reconciler,
+            store,
+        }
+    }
+
+    /// Return the latest known Ddns, None means the Ddns is deleted, or not seen by the watch
+    /// yet, in both cases the watch will deliver it if there is anything to do
+    fn latest(&self, ddns: &Ddns) -> Option<Ddns> {
+        let obj_ref = ObjectRef::from_obj(ddns);
+
+        let latest = match self.store.get(&obj_ref) {
+            None => {
+                info!(%obj_ref, "ddns is not in cache, drop the event");
+
+                return None;
+            }
+
+            Some(latest) => latest,
+        };
+
+        if latest.metadata.resource_version != ddns.metadata.resource_version {
+            info!(
+                %obj_ref,
+                snapshot_version = ?ddns.metadata.resource_version,
+                latest_version = ?latest.metadata.resource_version,
+                "replace the stale snapshot with the cached ddns"
+            );
+        }
+
+        Some(Ddns::clone(&latest))
+    }
+}
+
+#[async_trait]
+impl<R> Reconcile for CacheReconciler<R>
+where
+    R: Reconcile + Send + Sync,
+{
+    type Error = R::Error;
+
+    #[instrument(skip(self, ddns))]
+    async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        let latest = match self.latest(&ddns) {
+            None => return Ok(()),
+            Some(latest) => latest,
+        };
+
+        // the snapshot may be captured before the ddns is deleted
+        if latest.metadata.deletion_timestamp.is_some() {
+            debug!("cached ddns is being deleted");
+
+            self.inner_reconciler.delete_ddns(latest).await
+        } else {
+            self.inner_reconciler.reconcile_ddns(latest).await
+        }
+    }
+
+    #[instrument(skip(self, ddns))]
+    async fn delete_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        match self.latest(&ddns) {
+            None => Ok(()),
+            Some(latest) => self.inner_reconciler.delete_ddns(latest).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    use chrono::DateTime;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use kube::runtime::reflector::store::Writer;
+    use kube::runtime::watcher::Event;
+
+    use super::*;
+    use crate::ddns::Error;
+
+    #[derive(Clone, Default)]
+    struct RecordReconciler {
+        reconciled: Arc<Mutex<Vec<(String, bool)>>>,
+    }
+
+    #[async_trait]
+    impl Reconcile for RecordReconciler {
+        type Error = Error;
+
+        async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+            self.reconciled
+                .lock()
+                .unwrap()
+                .push((ddns.spec.domain, false));
+
+            Ok(())
+        }
+
+        async fn delete_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+            self.reconciled
+                .lock()
+                .unwrap()
+                .push((ddns.spec.domain, true));
+
+            Ok(())
+        }
+    }
+
+    fn ddns(resource_version: &str, domain: &str) -> Ddns {
+        let mut ddns = Ddns::default();
+        ddns.metadata.namespace.replace(String::from("default"));
+        ddns.metadata.name.replace(String::from("test"));
+        ddns.metadata
+            .resource_version
+            .replace(resource_version.to_string());
+        ddns.spec.domain = domain.to_string();
+
+        ddns
+    }
+
+    #[tokio::test]
+    async fn use_latest_ddns() {
+        let mut writer = Writer::default();
+        let inner = RecordReconciler::default();
+        let reconciler = CacheReconciler::new(inner.clone(), writer.as_reader());
+
+        // not seen by the watch
+        reconciler
+            .reconcile_ddns(ddns("1", "old.example.com"))
+            .await
+            .unwrap();
+        assert!(inner.reconciled.lock().unwrap().is_empty());
+
+        writer.apply_watcher_event(&Event::Applied(ddns("2", "new.example.com")));
+
+        reconciler
+            .reconcile_ddns(ddns("1", "old.example.com"))
+            .await
+            .unwrap();
+
+        let mut deleting = ddns("3", "new.example.com");
+        deleting
+            .metadata
+            .deletion_timestamp
+            .replace(Time(DateTime::from(SystemTime::now())));
+        writer.apply_watcher_event(&Event::Applied(deleting));
+
+        reconciler
+            .reconcile_ddns(ddns("2", "new.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *inner.reconciled.lock().unwrap(),
+            vec![
+                ("new.example.com".to_string(), false),
+                ("new.example.com".to_string(), true)
+            ]
+        );
+    }
+}
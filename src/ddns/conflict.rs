@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+
+use kube::Resource;
+
+use crate::hostname::normalize_hostname;
+use crate::spec::Ddns;
+
+pub const CONFLICT_CONDITION: &str = "Conflict";
+
+/// Find the Ddns which owns the domain in the ddns_list.
+///
+/// The oldest Ddns wins, namespace and name break the tie, so every reconcile gets the same owner
+/// no matter which Ddns triggers it. A Ddns being deleted still owns its domain until the
+/// finalizer is removed, because its records are not cleaned up yet.
+pub fn find_domain_owner<'a>(
+    domain: &str,
+    ddns_list: impl IntoIterator<Item = &'a Ddns>,
+) -> Option<&'a Ddns> {
+    let domain = normalize_hostname(domain);
+
+    ddns_list
+        .into_iter()
+        .filter(|ddns| normalize_hostname(&ddns.spec.domain) == domain)
+        .min_by(|a, b| compare_claim(*a, *b))
+}
+
+/// Order the objects claiming the same name, the oldest first, namespace and name break the tie
+pub fn compare_claim<K: Resource>(a: &K, b: &K) -> Ordering {
+    let (a, b) = (a.meta(), b.meta());
+    let a_created = a.creation_timestamp.as_ref().map(|time| time.0);
+    let b_created = b.creation_timestamp.as_ref().map(|time| time.0);
+
+    // an object without creation timestamp is not persisted yet, treat it as the newest one
+    let created_order = match (a_created, b_created) {
+        (Some(a_created), Some(b_created)) => a_created.cmp(&b_created),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+
+    created_order
+        .then_with(|| a.namespace.cmp(&b.namespace))
+        .then_with(|| a.name.cmp(&b.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use kube::runtime::reflector::ObjectRef;
+
+    use super::*;
+    use crate::spec::DdnsSpec;
+
+    fn ddns(namespace: &str, name: &str, domain: &str, created: Option<i64>) -> Ddns {
+        let mut ddns = Ddns::new(
+            name,
+            DdnsSpec {
+                selector: HashMap::from([("app".to_string(), name.to_string())]),
+                domain: domain.to_string(),
+                zone: "example.com".to_string(),
+                ..Default::default()
+            },
+        );
+        ddns.metadata.namespace.replace(namespace.to_string());
+        ddns.metadata.creation_timestamp = created.map(|secs| Time(Utc.timestamp(secs, 0)));
+
+        ddns
+    }
+
+    fn owner_name(owner: Option<&Ddns>) -> Option<&str> {
+        owner.and_then(|owner| owner.metadata.name.as_deref())
+    }
+
+    #[test]
+    fn oldest_wins() {
+        let ddns_list = [
+            ddns("default", "new", "www.example.com", Some(200)),
+            ddns("default", "old", "WWW.example.com.", Some(100)),
+            ddns("default", "other", "api.example.com", Some(50)),
+        ];
+
+        assert_eq!(
+            owner_name(find_domain_owner("www.example.com", &ddns_list)),
+            Some("old")
+        );
+        assert_eq!(
+            owner_name(find_domain_owner("api.example.com", &ddns_list)),
+            Some("other")
+        );
+        assert!(find_domain_owner("ftp.example.com", &ddns_list).is_none());
+    }
+
+    #[test]
+    fn tie_break_by_namespace_and_name() {
+        let ddns_list = [
+            ddns("b", "a", "www.example.com", Some(100)),
+            ddns("a", "b", "www.example.com", Some(100)),
+            ddns("a", "a", "www.example.com", Some(100)),
+        ];
+
+        let owner = find_domain_owner("www.example.com", &ddns_list).unwrap();
+        assert_eq!(owner.metadata.namespace.as_deref(), Some("a"));
+        assert_eq!(owner.metadata.name.as_deref(), Some("a"));
+
+        // the result doesn't depend on the list order
+        let mut reversed = ddns_list.clone();
+        reversed.reverse();
+        let reversed_owner = find_domain_owner("www.example.com", &reversed).unwrap();
+        assert_eq!(
+            ObjectRef::from_obj(reversed_owner),
+            ObjectRef::from_obj(owner)
+        );
+    }
+
+    #[test]
+    fn not_persisted_ddns_is_newest() {
+        let ddns_list = [
+            ddns("default", "a", "www.example.com", None),
+            ddns("default", "b", "www.example.com", Some(100)),
+        ];
+
+        assert_eq!(
+            owner_name(find_domain_owner("www.example.com", &ddns_list)),
+            Some("b")
+        );
+    }
+}
@@ -1,39 +1,97 @@
+use std::time::Duration;
+
 use anyhow::Error;
 use futures_channel::mpsc;
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures_util::{stream, StreamExt, TryStreamExt};
+use futures_util::{stream, Stream, StreamExt};
+use k8s_openapi::api::core::v1::Service;
+use kube::runtime::reflector::store::Writer;
+use kube::runtime::watcher::Error as WatchError;
 use kube::{Api, Client};
-use tap::TapFallible;
-use tracing::{error, info, info_span, Instrument};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
 
-use crate::cf_dns::CfDns;
+use crate::cf_dns_pool::CfDnsPool;
+use crate::config::Config;
+use crate::ddns::cache_reconciler::CacheReconciler;
 use crate::ddns::default_err_policy::DefaultErrPolicy;
 use crate::ddns::default_reconciler::DefaultReconciler;
+use crate::ddns::limited_reconciler::LimitedReconciler;
 use crate::ddns::watch::watch_ddns;
 use crate::ddns::Error as DdnsError;
-use crate::ddns::{ErrorPolicy, QueueReconciler, Reconcile};
-use crate::service::Trigger;
+use crate::ddns::{ErrorPolicy, QueueReconciler, Reconcile, WATCH_RETRY_DELAY};
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::service::{AnnotationReconciler, Trigger};
+use crate::shutdown::{task_tracker, TaskTracker};
 use crate::spec::Ddns;
 
+type DdnsReconciler =
+    QueueReconciler<LimitedReconciler<CacheReconciler<DefaultReconciler>>, DdnsError>;
+
 pub struct Controller {
     client: Client,
-    reconciler: QueueReconciler<DefaultReconciler, DdnsError>,
+    reconciler: DdnsReconciler,
     err_policy: DefaultErrPolicy<UnboundedSender<Ddns>>,
-    trigger: Trigger<
-        QueueReconciler<DefaultReconciler, DdnsError>,
-        DefaultErrPolicy<UnboundedSender<Ddns>>,
-    >,
+    trigger: Trigger<DdnsReconciler, DefaultErrPolicy<UnboundedSender<Ddns>>>,
     retry_queue_receiver: UnboundedReceiver<Ddns>,
+    ddns_store: Writer<Ddns>,
+    service_store: Writer<Service>,
+    health: Health,
 }
 
 impl Controller {
-    pub fn new(client: Client, cf_dns: CfDns) -> Self {
+    pub fn new(
+        client: Client,
+        cf_dns_pool: CfDnsPool,
+        health: Health,
+        metrics: Metrics,
+        config: &Config,
+    ) -> Self {
         let (queue_sender, queue_receiver) = mpsc::unbounded();
 
-        let reconciler = QueueReconciler::new(DefaultReconciler::new(client.clone(), cf_dns));
-        let err_policy = DefaultErrPolicy::new(queue_sender);
+        let ddns_store = Writer::default();
+        let service_store = Writer::default();
+
+        let annotation_reconciler = AnnotationReconciler::new(
+            client.clone(),
+            cf_dns_pool.default_cf_dns(),
+            config,
+            ddns_store.as_reader(),
+            service_store.as_reader(),
+            health.clone(),
+        );
+
+        let reconciler = QueueReconciler::new(
+            LimitedReconciler::new(
+                CacheReconciler::new(
+                    DefaultReconciler::new(
+                        client.clone(),
+                        cf_dns_pool,
+                        config.no_ip_retry_delay,
+                        config.conflict_recheck_delay,
+                        config.delete_credentials_timeout,
+                        config.ip_filter.clone(),
+                        ddns_store.as_reader(),
+                    ),
+                    ddns_store.as_reader(),
+                ),
+                config.max_concurrent_reconciles,
+            ),
+            metrics,
+            config.queue_idle_timeout,
+        );
+        let err_policy = DefaultErrPolicy::new(queue_sender, config.retry_delay);
 
-        let trigger = Trigger::new(client.clone(), reconciler.clone(), err_policy.clone());
+        let trigger = Trigger::new(
+            client.clone(),
+            reconciler.clone(),
+            err_policy.clone(),
+            annotation_reconciler,
+            ddns_store.as_reader(),
+            health.clone(),
+        );
 
         Self {
             client,
@@ -41,35 +99,117 @@ impl Controller {
             err_policy,
             trigger,
             retry_queue_receiver: queue_receiver,
+            ddns_store,
+            service_store,
+            health,
         }
     }
 
-    pub async fn run(self) -> Result<(), Error> {
-        let trigger = self.trigger;
+    /// Run the controller until the stream stopped or the shutdown is cancelled.
+    ///
+    /// When shutting down, the controller stops accepting new ddns, then waits the in-flight
+    /// reconciles done in drain_timeout.
+    pub async fn run(
+        self,
+        shutdown: CancellationToken,
+        drain_timeout: Duration,
+    ) -> Result<(), Error> {
+        let (tracker, drain) = task_tracker();
 
-        tokio::spawn(trigger.trigger_ddns_reconcile());
+        let trigger_task = tokio::spawn(self.trigger.trigger_ddns_reconcile(
+            self.service_store,
+            shutdown.clone(),
+            tracker.clone(),
+        ));
 
         info!("trigger start to trigger ddns reconcile");
 
-        let ddns_stream = watch_ddns(Api::all(self.client));
-        let retry_queue_receiver = self.retry_queue_receiver.map(Ok);
+        let ddns_stream = watch_ddns(Api::all(self.client), self.ddns_store, self.health);
+
+        let result = Self::reconcile_ddns_stream(
+            ddns_stream,
+            self.reconciler,
+            self.err_policy,
+            self.retry_queue_receiver,
+            &shutdown,
+            tracker,
+        )
+        .await;
+
+        // if the stream stopped unexpectedly, make sure the trigger stops too
+        shutdown.cancel();
+
+        match trigger_task.await {
+            Err(err) => error!(%err, "trigger task panic"),
+            Ok(Err(err)) => error!(%err, "trigger stopped with error"),
+            Ok(Ok(_)) => info!("trigger stopped"),
+        }
+
+        info!(?drain_timeout, "start to drain in-flight reconciles");
+
+        if drain.drain(drain_timeout).await {
+            info!("drain in-flight reconciles done");
+        } else {
+            warn!(?drain_timeout, "drain in-flight reconciles timeout");
+        }
+
+        result
+    }
+
+    /// Reconcile the ddns from the watch stream and the retry queue until the stream stopped or
+    /// the shutdown is cancelled
+    pub(super) async fn reconcile_ddns_stream<R, E>(
+        ddns_stream: impl Stream<Item = Result<Ddns, WatchError>>,
+        reconciler: R,
+        err_policy: E,
+        retry_queue_receiver: UnboundedReceiver<Ddns>,
+        shutdown: &CancellationToken,
+        tracker: TaskTracker,
+    ) -> Result<(), Error>
+    where
+        R: Reconcile + Clone + Send + Sync + 'static,
+        E: ErrorPolicy<Error = R::Error> + Clone + Send + Sync + 'static,
+    {
+        let retry_queue_receiver = retry_queue_receiver.map(Ok);
 
         let ddns_stream = stream::select(ddns_stream, retry_queue_receiver);
         futures_util::pin_mut!(ddns_stream);
 
         info!("start to acquire ddns from ddns stream");
 
-        while let Some(ddns) = ddns_stream
-            .try_next()
-            .await
-            .tap_err(|err| error!(%err, "get ddns from stream failed"))?
-        {
+        loop {
+            let ddns = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("shutdown, stop acquiring ddns from ddns stream");
+
+                    return Ok(());
+                }
+
+                ddns = ddns_stream.next() => ddns,
+            };
+
+            let ddns = match ddns {
+                None => break,
+
+                Some(Err(err)) => {
+                    error!(%err, "get ddns from stream failed");
+
+                    // the watcher will re-list in next poll, don't flood the api server
+                    tokio::select! {
+                        _ = shutdown.cancelled() => return Ok(()),
+                        _ = time::sleep(WATCH_RETRY_DELAY) => continue,
+                    }
+                }
+
+                Some(Ok(ddns)) => ddns,
+            };
+
             info!(?ddns, "acquire ddns done");
 
-            let reconciler = self.reconciler.clone();
-            let err_policy = self.err_policy.clone();
+            let reconciler = reconciler.clone();
+            let err_policy = err_policy.clone();
 
-            tokio::spawn(
+            tracker.spawn(
                 async move {
                     if ddns.metadata.deletion_timestamp.is_some() {
                         info!(?ddns.metadata, "deletion_timestamp is set, delete dns");
@@ -96,3 +236,1200 @@ impl Controller {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::IpAddr;
+
+    use k8s_openapi::api::core::v1::{
+        LoadBalancerIngress, LoadBalancerStatus, Secret, Service, ServicePort, ServiceSpec,
+        ServiceStatus,
+    };
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointConditions, EndpointSlice};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::cf_dns::CfDns;
+    use crate::config::{CfCredentials, CF_RATE_LIMIT_PERIOD};
+    use crate::ddns::FINALIZER;
+    use crate::fake_cloudflare::FakeCloudflare;
+    use crate::fake_kube::FakeKube;
+    use crate::rate_limit::RateLimiter;
+    use crate::service::{HOSTNAME_ANNOTATION, PUBLISHED_ANNOTATION, TTL_ANNOTATION};
+    use crate::spec::{
+        AddressMode, DdnsSpec, DnsProviderConfig, DnsProviderConfigSpec, FailoverSpec,
+        FailoverTier, IpFilterSpec, NamespacedSecretRef, ProviderRef, SecretRef, SrvSpec,
+    };
+
+    const ZONE: &str = "example.com";
+    const NAMESPACE: &str = "default";
+
+    /// The controller running with the fake kubernetes API and the fake cloudflare API
+    struct Harness {
+        kube: FakeKube,
+        cloudflare: FakeCloudflare,
+        shutdown: CancellationToken,
+        controller: JoinHandle<Result<(), Error>>,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            let kube = FakeKube::new();
+            let cloudflare = FakeCloudflare::start();
+            cloudflare.add_zone(ZONE);
+
+            let config = Config::load_from([
+                "ddns",
+                "--cf-token",
+                "token",
+                "--retry-delay",
+                "100ms",
+                "--no-ip-retry-delay",
+                "100ms",
+                "--conflict-recheck-delay",
+                "100ms",
+                "--delete-credentials-timeout",
+                "1s",
+            ])
+            .unwrap();
+
+            let rate_limiter = RateLimiter::new(
+                config.cf_rate_limit,
+                CF_RATE_LIMIT_PERIOD,
+                config.cf_rate_burst,
+            );
+            let cf_dns = CfDns::new_with_api_url(
+                &CfCredentials::Token("token".to_string()),
+                config.default_ttl,
+                Some(cloudflare.api_url()),
+            )
+            .await
+            .unwrap();
+            let cf_dns_pool =
+                CfDnsPool::new(kube.client(), cf_dns, config.default_ttl, rate_limiter);
+
+            let controller = Controller::new(
+                kube.client(),
+                cf_dns_pool,
+                Health::default(),
+                Metrics::default(),
+                &config,
+            );
+
+            let shutdown = CancellationToken::new();
+            let controller =
+                tokio::spawn(controller.run(shutdown.clone(), config.shutdown_timeout));
+
+            Self {
+                kube,
+                cloudflare,
+                shutdown,
+                controller,
+            }
+        }
+
+        async fn stop(self) {
+            self.shutdown.cancel();
+
+            self.controller.await.unwrap().unwrap();
+        }
+
+        fn ddns(&self, name: &str) -> Option<Ddns> {
+            self.kube.get(NAMESPACE, name)
+        }
+
+        /// Wait until the condition is true, panic if it is not true in 10 seconds
+        async fn wait_until(&self, what: &str, mut condition: impl FnMut(&Self) -> bool) {
+            let deadline = time::Instant::now() + Duration::from_secs(10);
+
+            while !condition(self) {
+                if time::Instant::now() > deadline {
+                    panic!("wait until {} timeout", what);
+                }
+
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    }
+
+    fn labels() -> BTreeMap<String, String> {
+        BTreeMap::from([("app".to_string(), "web".to_string())])
+    }
+
+    fn ddns(name: &str, domain: &str) -> Ddns {
+        let mut ddns = Ddns::new(
+            name,
+            DdnsSpec {
+                selector: HashMap::from([("app".to_string(), "web".to_string())]),
+                domain: domain.to_string(),
+                zone: ZONE.to_string(),
+                credentials_secret_ref: None,
+                provider_ref: None,
+                srv: None,
+                ready_endpoints_only: false,
+                ip_filter: None,
+                additional_addresses: vec![],
+                address_mode: Default::default(),
+                failover: None,
+            },
+        );
+        ddns.metadata.namespace = Some(NAMESPACE.to_string());
+        // the service trigger finds the Ddns by the service labels
+        ddns.metadata.labels = Some(labels());
+
+        ddns
+    }
+
+    fn service(name: &str) -> Service {
+        let mut service = Service {
+            spec: Some(ServiceSpec {
+                type_: Some("LoadBalancer".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        service.metadata.name = Some(name.to_string());
+        service.metadata.namespace = Some(NAMESPACE.to_string());
+        service.metadata.labels = Some(labels());
+
+        service
+    }
+
+    fn set_lb_ips(service: &mut Service, ips: &[IpAddr]) {
+        service.status = Some(ServiceStatus {
+            load_balancer: Some(LoadBalancerStatus {
+                ingress: Some(
+                    ips.iter()
+                        .map(|ip| LoadBalancerIngress {
+                            ip: Some(ip.to_string()),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+            }),
+            ..Default::default()
+        });
+    }
+
+    fn endpoint_slice(service: &str, ready: bool) -> EndpointSlice {
+        let mut slice = EndpointSlice {
+            address_type: "IPv4".to_string(),
+            endpoints: vec![Endpoint {
+                conditions: Some(EndpointConditions {
+                    ready: Some(ready),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        slice.metadata.name = Some(format!("{}-abcde", service));
+        slice.metadata.namespace = Some(NAMESPACE.to_string());
+        slice.metadata.labels = Some(BTreeMap::from([(
+            "kubernetes.io/service-name".to_string(),
+            service.to_string(),
+        )]));
+
+        slice
+    }
+
+    fn secret(name: &str) -> Secret {
+        secret_with_token(name, "token")
+    }
+
+    fn secret_with_token(name: &str, token: &str) -> Secret {
+        let mut secret = Secret {
+            string_data: Some(BTreeMap::from([("token".to_string(), token.to_string())])),
+            ..Default::default()
+        };
+        secret.metadata.name = Some(name.to_string());
+        secret.metadata.namespace = Some(NAMESPACE.to_string());
+
+        secret
+    }
+
+    fn set_ready(slice: &mut EndpointSlice, ready: bool) {
+        slice.endpoints[0].conditions.as_mut().unwrap().ready = Some(ready);
+    }
+
+    fn status(ddns: &Ddns) -> (&str, &str, Option<i64>) {
+        let status = ddns.status.as_ref().unwrap();
+
+        (&status.status, &status.domain, status.observed_generation)
+    }
+
+    #[tokio::test]
+    async fn service_gets_lb_ip() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        harness.kube.create(&service("web"));
+        harness.kube.create(&ddns("web", "www.example.com"));
+
+        // nothing is published before the load balancer has an IP
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(harness
+            .cloudflare
+            .records(ZONE, "www.example.com")
+            .is_empty());
+        assert!(harness.ddns("web").unwrap().status.is_none());
+
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service| set_lb_ips(service, &[ip]));
+
+        harness
+            .wait_until("ddns is running", |harness| {
+                harness
+                    .ddns("web")
+                    .and_then(|ddns| ddns.status)
+                    .is_some_and(|status| status.status == "RUNNING")
+            })
+            .await;
+
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        let ddns = harness.ddns("web").unwrap();
+        assert_eq!(status(&ddns), ("RUNNING", "www.example.com", Some(1)));
+        assert_eq!(ddns.metadata.finalizers, Some(vec![FINALIZER.to_string()]));
+
+        // the records follow the load balancer IPs
+        let ips = [IpAddr::from([1, 1, 1, 1]), IpAddr::from([2, 2, 2, 2])];
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service| set_lb_ips(service, &ips));
+
+        harness
+            .wait_until("records are updated", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == ips
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn domain_renamed() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+        harness.kube.create(&ddns("web", "www.example.com"));
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        harness.kube.modify(NAMESPACE, "web", |ddns: &mut Ddns| {
+            ddns.spec.domain = "api.example.com".to_string()
+        });
+
+        harness
+            .wait_until("renamed domain is running", |harness| {
+                harness
+                    .ddns("web")
+                    .is_some_and(|ddns| status(&ddns) == ("RUNNING", "api.example.com", Some(2)))
+            })
+            .await;
+
+        assert_eq!(harness.cloudflare.ips(ZONE, "api.example.com"), [ip]);
+        assert!(harness
+            .cloudflare
+            .records(ZONE, "www.example.com")
+            .is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn ddns_deleted() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+        harness.kube.create(&ddns("web", "www.example.com"));
+        harness.kube.create(&ddns("other", "other.example.com"));
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+                    && harness.cloudflare.ips(ZONE, "other.example.com") == [ip]
+                    && harness
+                        .ddns("web")
+                        .is_some_and(|ddns| ddns.metadata.finalizers.is_some())
+            })
+            .await;
+
+        harness.kube.delete::<Ddns>(NAMESPACE, "web");
+
+        harness
+            .wait_until("ddns is removed", |harness| harness.ddns("web").is_none())
+            .await;
+
+        assert!(harness
+            .cloudflare
+            .records(ZONE, "www.example.com")
+            .is_empty());
+        // the other Ddns is not affected
+        assert_eq!(harness.cloudflare.ips(ZONE, "other.example.com"), [ip]);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn delete_with_published_credentials() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        harness.kube.create(&secret("cloudflare"));
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.credentials_secret_ref = Some(SecretRef {
+            name: "cloudflare".to_string(),
+        });
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        // the new Secret doesn't exist, the records are still removed by the published one
+        harness.kube.modify(NAMESPACE, "web", |ddns: &mut Ddns| {
+            ddns.spec.credentials_secret_ref = Some(SecretRef {
+                name: "missing".to_string(),
+            });
+        });
+        time::sleep(Duration::from_millis(300)).await;
+
+        harness.kube.delete::<Ddns>(NAMESPACE, "web");
+
+        harness
+            .wait_until("ddns is removed", |harness| harness.ddns("web").is_none())
+            .await;
+        assert!(harness
+            .cloudflare
+            .records(ZONE, "www.example.com")
+            .is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn move_to_other_account() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        harness
+            .cloudflare
+            .add_account_zone("example.org", "other-token");
+        harness
+            .kube
+            .create(&secret_with_token("other", "other-token"));
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.org");
+        ddns.spec.zone = "example.org".to_string();
+        ddns.spec.credentials_secret_ref = Some(SecretRef {
+            name: "other".to_string(),
+        });
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips("example.org", "www.example.org") == [ip]
+            })
+            .await;
+
+        // the default credentials can't access the old zone, the old records are removed by the
+        // published credentials
+        harness.kube.modify(NAMESPACE, "web", |ddns: &mut Ddns| {
+            ddns.spec.credentials_secret_ref = None;
+            ddns.spec.domain = "www.example.com".to_string();
+            ddns.spec.zone = ZONE.to_string();
+        });
+
+        harness
+            .wait_until("records are moved to the other account", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+                    && harness
+                        .cloudflare
+                        .records("example.org", "www.example.org")
+                        .is_empty()
+            })
+            .await;
+
+        harness
+            .wait_until("ddns is running", |harness| {
+                harness
+                    .ddns("web")
+                    .is_some_and(|ddns| status(&ddns) == ("RUNNING", "www.example.com", Some(2)))
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn delete_without_credentials() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        harness.kube.create(&secret("cloudflare"));
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.credentials_secret_ref = Some(SecretRef {
+            name: "cloudflare".to_string(),
+        });
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        // like the namespace deletion, the Secret is gone before the Ddns
+        harness.kube.delete::<Secret>(NAMESPACE, "cloudflare");
+        harness.kube.delete::<Ddns>(NAMESPACE, "web");
+
+        harness
+            .wait_until("credentials are unavailable", |harness| {
+                harness
+                    .ddns("web")
+                    .and_then(|ddns| ddns.status)
+                    .is_some_and(|status| {
+                        status.conditions.iter().any(|condition| {
+                            condition.type_ == "CredentialsResolved" && condition.status == "False"
+                        })
+                    })
+            })
+            .await;
+
+        // the finalizer is released after the timeout, the records can't be removed
+        harness
+            .wait_until("ddns is removed", |harness| harness.ddns("web").is_none())
+            .await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn srv_records_follow_ports() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+        let srv_name = "_sip._udp.www.example.com";
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        service.spec.as_mut().unwrap().ports = Some(vec![ServicePort {
+            name: Some("sip".to_string()),
+            protocol: Some("UDP".to_string()),
+            port: 5060,
+            ..Default::default()
+        }]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.srv = Some(SrvSpec {
+            ports: vec!["sip".to_string()],
+        });
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("srv record is published", |harness| {
+                harness
+                    .cloudflare
+                    .records(ZONE, srv_name)
+                    .iter()
+                    .any(|record| record.content == "0 5060 www.example.com")
+            })
+            .await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        harness
+            .wait_until("srv record is in status", |harness| {
+                harness
+                    .ddns("web")
+                    .and_then(|ddns| ddns.status)
+                    .is_some_and(|status| status.srv_records == [srv_name])
+            })
+            .await;
+
+        // the record is removed when the port is not selected anymore
+        harness.kube.modify::<Ddns>(NAMESPACE, "web", |ddns| {
+            ddns.spec.srv = Some(SrvSpec::default());
+        });
+
+        harness
+            .wait_until("srv record is removed", |harness| {
+                harness.cloudflare.records(ZONE, srv_name).is_empty()
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn srv_records_of_conflict_loser() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+        let srv_name = "_sip._udp.www.example.com";
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        service.spec.as_mut().unwrap().ports = Some(vec![ServicePort {
+            name: Some("sip".to_string()),
+            protocol: Some("UDP".to_string()),
+            port: 5060,
+            ..Default::default()
+        }]);
+        harness.kube.create(&service);
+
+        let mut loser = ddns("web", "www.example.com");
+        loser.spec.srv = Some(SrvSpec {
+            ports: vec!["sip".to_string()],
+        });
+        harness.kube.create(&loser);
+
+        harness
+            .wait_until("srv record is published", |harness| {
+                !harness.cloudflare.records(ZONE, srv_name).is_empty()
+            })
+            .await;
+
+        // the Ddns created at the same time with the smaller name owns the domain, it doesn't
+        // publish the SRV record, so the loser removes its own record
+        harness.kube.create(&ddns("a", "www.example.com"));
+
+        harness
+            .wait_until("owner is running", |harness| {
+                harness
+                    .ddns("a")
+                    .is_some_and(|ddns| ddns.status.is_some() && status(&ddns).0 == "RUNNING")
+            })
+            .await;
+
+        // the service change reconciles the loser too
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service: &mut Service| {
+                service.metadata.annotations =
+                    Some(BTreeMap::from([("touch".to_string(), "1".to_string())]));
+            });
+
+        harness
+            .wait_until("srv record of the loser is removed", |harness| {
+                harness.cloudflare.records(ZONE, srv_name).is_empty()
+                    && harness
+                        .ddns("web")
+                        .is_some_and(|ddns| ddns.status.is_some() && status(&ddns).0 == "CONFLICT")
+            })
+            .await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn withhold_not_ready_services() {
+        let harness = Harness::start().await;
+        let ip_a = IpAddr::from([1, 1, 1, 1]);
+        let ip_b = IpAddr::from([2, 2, 2, 2]);
+
+        for (name, ip, ready) in [("web-a", ip_a, true), ("web-b", ip_b, false)] {
+            let mut service = service(name);
+            set_lb_ips(&mut service, &[ip]);
+            harness.kube.create(&service);
+            harness.kube.create(&endpoint_slice(name, ready));
+        }
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.ready_endpoints_only = true;
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("ready service is published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_a]
+            })
+            .await;
+
+        let condition = |harness: &Harness| {
+            harness
+                .ddns("web")
+                .and_then(|ddns| ddns.status)
+                .and_then(|status| {
+                    status
+                        .conditions
+                        .into_iter()
+                        .find(|condition| condition.type_ == "EndpointsReady")
+                })
+                .map(|condition| condition.reason)
+        };
+        harness
+            .wait_until("not ready service is reported", |harness| {
+                condition(harness).as_deref() == Some("EndpointsNotReady")
+            })
+            .await;
+
+        // the readiness change of the endpoints triggers the reconcile
+        harness
+            .kube
+            .modify(NAMESPACE, "web-b-abcde", |slice| set_ready(slice, true));
+        harness
+            .wait_until("both services are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_a, ip_b]
+            })
+            .await;
+
+        harness
+            .kube
+            .modify(NAMESPACE, "web-a-abcde", |slice| set_ready(slice, false));
+        harness
+            .wait_until("not ready service is withheld", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_b]
+            })
+            .await;
+
+        // the records are not emptied when no service is ready
+        harness
+            .kube
+            .modify(NAMESPACE, "web-b-abcde", |slice| set_ready(slice, false));
+        harness
+            .wait_until("all services are published", |harness| {
+                condition(harness).as_deref() == Some("NoReadyEndpoints")
+            })
+            .await;
+        assert_eq!(
+            harness.cloudflare.ips(ZONE, "www.example.com"),
+            [ip_a, ip_b]
+        );
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn filter_private_ips() {
+        let harness = Harness::start().await;
+        let public_ip = IpAddr::from([1, 1, 1, 1]);
+        let private_ip = IpAddr::from([10, 0, 0, 1]);
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[public_ip, private_ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.ip_filter = Some(IpFilterSpec {
+            public_only: true,
+            ..Default::default()
+        });
+        harness.kube.create(&ddns);
+
+        let filtered_ips = |harness: &Harness| {
+            harness
+                .ddns("web")
+                .and_then(|ddns| ddns.status)
+                .map(|status| status.filtered_ips)
+        };
+
+        harness
+            .wait_until("private ip is filtered out", |harness| {
+                filtered_ips(harness) == Some(vec![private_ip.to_string()])
+            })
+            .await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [public_ip]);
+
+        // the records are removed when all the IPs are filtered out
+        harness.kube.modify(NAMESPACE, "web", |service| {
+            set_lb_ips(service, &[private_ip])
+        });
+
+        let is_filtered = |harness: &Harness| {
+            harness
+                .ddns("web")
+                .and_then(|ddns| ddns.status)
+                .is_some_and(|status| {
+                    status.conditions.iter().any(|condition| {
+                        condition.type_ == "Filtered"
+                            && condition.status == "True"
+                            && condition.reason == "AllAddressesFiltered"
+                    })
+                })
+        };
+
+        harness
+            .wait_until("records are removed", |harness| {
+                harness
+                    .cloudflare
+                    .records(ZONE, "www.example.com")
+                    .is_empty()
+                    && is_filtered(harness)
+            })
+            .await;
+        assert_eq!(filtered_ips(&harness), Some(vec![private_ip.to_string()]));
+
+        // the condition is removed once an IP can be published again
+        let new_ip = IpAddr::from([2, 2, 2, 2]);
+        harness.kube.modify(NAMESPACE, "web", |service| {
+            set_lb_ips(service, &[new_ip, private_ip])
+        });
+
+        harness
+            .wait_until("new ip is published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [new_ip] && !is_filtered(harness)
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn additional_addresses() {
+        let harness = Harness::start().await;
+        let lb_ip = IpAddr::from([1, 1, 1, 1]);
+        let fallback_ip = IpAddr::from([3, 3, 3, 3]);
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[lb_ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.additional_addresses = vec![fallback_ip.to_string()];
+        harness.kube.create(&ddns);
+
+        harness
+            .wait_until("addresses are merged", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [lb_ip, fallback_ip]
+            })
+            .await;
+
+        harness.kube.modify(NAMESPACE, "web", |ddns: &mut Ddns| {
+            ddns.spec.address_mode = AddressMode::Override
+        });
+
+        harness
+            .wait_until("load balancer ip is overridden", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [fallback_ip]
+            })
+            .await;
+
+        // the filters are applied to the additional addresses too
+        harness.kube.modify(NAMESPACE, "web", |ddns: &mut Ddns| {
+            ddns.spec.ip_filter = Some(IpFilterSpec {
+                deny: vec![fallback_ip.to_string()],
+                ..Default::default()
+            })
+        });
+
+        harness
+            .wait_until("additional address is filtered out", |harness| {
+                harness
+                    .cloudflare
+                    .records(ZONE, "www.example.com")
+                    .is_empty()
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn failover_between_tiers() {
+        let harness = Harness::start().await;
+        let ip_a = IpAddr::from([1, 1, 1, 1]);
+        let ip_b = IpAddr::from([2, 2, 2, 2]);
+
+        let tier_labels = |tier: &str| BTreeMap::from([(tier.to_string(), "web".to_string())]);
+
+        for (name, tier, ip) in [("web-a", "primary", ip_a), ("web-b", "secondary", ip_b)] {
+            let mut service = service(name);
+            service.metadata.labels = Some(tier_labels(tier));
+            set_lb_ips(&mut service, &[ip]);
+            harness.kube.create(&service);
+            harness.kube.create(&endpoint_slice(name, true));
+        }
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.failover = Some(FailoverSpec {
+            tiers: ["primary", "secondary"]
+                .into_iter()
+                .map(|tier| FailoverTier {
+                    name: tier.to_string(),
+                    match_labels: tier_labels(tier).into_iter().collect(),
+                })
+                .collect(),
+            failback_delay_seconds: 1,
+        });
+        // the services of both tiers trigger the Ddns
+        ddns.metadata.labels = Some(
+            tier_labels("primary")
+                .into_iter()
+                .chain(tier_labels("secondary"))
+                .collect(),
+        );
+        harness.kube.create(&ddns);
+
+        let active_tier = |harness: &Harness| {
+            harness
+                .ddns("web")
+                .and_then(|ddns| ddns.status)
+                .and_then(|status| status.active_tier)
+        };
+
+        harness
+            .wait_until("primary tier is published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_a]
+                    && active_tier(harness).as_deref() == Some("primary")
+            })
+            .await;
+
+        harness
+            .kube
+            .modify(NAMESPACE, "web-a-abcde", |slice| set_ready(slice, false));
+
+        harness
+            .wait_until("fail over to secondary tier", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_b]
+                    && active_tier(harness).as_deref() == Some("secondary")
+            })
+            .await;
+
+        let switch_time = harness
+            .ddns("web")
+            .and_then(|ddns| ddns.status)
+            .and_then(|status| status.last_tier_switch_time);
+        assert!(switch_time.is_some());
+
+        // the recovered primary tier is published again after the failback delay
+        harness
+            .kube
+            .modify(NAMESPACE, "web-a-abcde", |slice| set_ready(slice, true));
+
+        harness
+            .wait_until("fail back to primary tier", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip_a]
+                    && active_tier(harness).as_deref() == Some("primary")
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn annotated_service() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        let get_service = |harness: &Harness| harness.kube.get::<Service>(NAMESPACE, "web");
+
+        // the annotated service needs neither the labels nor a Ddns
+        let mut service = service("web");
+        service.metadata.labels = None;
+        service.metadata.annotations = Some(BTreeMap::from([
+            (
+                HOSTNAME_ANNOTATION.to_string(),
+                "www.example.com".to_string(),
+            ),
+            (TTL_ANNOTATION.to_string(), "120".to_string()),
+        ]));
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+
+        harness
+            .wait_until("annotated service is published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        let service = get_service(&harness).unwrap();
+        assert_eq!(
+            service.metadata.finalizers,
+            Some(vec![FINALIZER.to_string()])
+        );
+        assert!(service
+            .metadata
+            .annotations
+            .unwrap()
+            .contains_key(PUBLISHED_ANNOTATION));
+
+        // the records move with the hostname
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service: &mut Service| {
+                service.metadata.annotations.as_mut().unwrap().insert(
+                    HOSTNAME_ANNOTATION.to_string(),
+                    "api.example.com".to_string(),
+                );
+            });
+
+        harness
+            .wait_until("records are moved", |harness| {
+                harness.cloudflare.ips(ZONE, "api.example.com") == [ip]
+                    && harness
+                        .cloudflare
+                        .records(ZONE, "www.example.com")
+                        .is_empty()
+            })
+            .await;
+
+        // the zone is looked up, the delegated subdomain is the longest zone
+        harness.cloudflare.add_zone("dev.example.com");
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service: &mut Service| {
+                service.metadata.annotations.as_mut().unwrap().insert(
+                    HOSTNAME_ANNOTATION.to_string(),
+                    "app.dev.example.com".to_string(),
+                );
+            });
+
+        harness
+            .wait_until("records are in the delegated zone", |harness| {
+                harness
+                    .cloudflare
+                    .ips("dev.example.com", "app.dev.example.com")
+                    == [ip]
+                    && harness
+                        .cloudflare
+                        .records(ZONE, "api.example.com")
+                        .is_empty()
+            })
+            .await;
+
+        // removing the annotation releases the records and the finalizer
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service: &mut Service| {
+                service
+                    .metadata
+                    .annotations
+                    .as_mut()
+                    .unwrap()
+                    .remove(HOSTNAME_ANNOTATION);
+            });
+
+        harness
+            .wait_until("annotated service is released", |harness| {
+                harness
+                    .cloudflare
+                    .records("dev.example.com", "app.dev.example.com")
+                    .is_empty()
+                    && get_service(harness).is_some_and(|service| {
+                        service.metadata.finalizers.unwrap_or_default().is_empty()
+                    })
+            })
+            .await;
+
+        // the records are removed before the deleted service is gone
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service: &mut Service| {
+                service.metadata.annotations.as_mut().unwrap().insert(
+                    HOSTNAME_ANNOTATION.to_string(),
+                    "www.example.com".to_string(),
+                );
+            });
+
+        harness
+            .wait_until("annotated service is published again", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        harness.kube.delete::<Service>(NAMESPACE, "web");
+
+        harness
+            .wait_until("deleted service is gone", |harness| {
+                get_service(harness).is_none()
+            })
+            .await;
+        assert!(harness
+            .cloudflare
+            .records(ZONE, "www.example.com")
+            .is_empty());
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn annotated_services_with_same_hostname() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+        let other_ip = IpAddr::from([2, 2, 2, 2]);
+
+        let annotated = |name: &str, ip: IpAddr| {
+            let mut service = service(name);
+            service.metadata.labels = None;
+            service.metadata.annotations = Some(BTreeMap::from([(
+                HOSTNAME_ANNOTATION.to_string(),
+                "www.example.com".to_string(),
+            )]));
+            set_lb_ips(&mut service, &[ip]);
+
+            service
+        };
+
+        // the services are created at the same time, the smaller name owns the hostname
+        harness.kube.create(&annotated("b", other_ip));
+        harness.kube.create(&annotated("a", ip));
+
+        harness
+            .wait_until("owner is published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        // the other service neither publishes nor removes the records
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        // the other service takes over once the owner releases the hostname
+        harness
+            .kube
+            .modify(NAMESPACE, "a", |service: &mut Service| {
+                service
+                    .metadata
+                    .annotations
+                    .as_mut()
+                    .unwrap()
+                    .remove(HOSTNAME_ANNOTATION);
+            });
+
+        harness
+            .wait_until("other service takes over", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [other_ip]
+            })
+            .await;
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn provider_settings_change() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        harness.kube.create(&secret("cloudflare"));
+
+        harness.kube.create(&DnsProviderConfig::new(
+            "cloudflare",
+            DnsProviderConfigSpec {
+                credentials_secret_ref: NamespacedSecretRef {
+                    namespace: NAMESPACE.to_string(),
+                    name: "cloudflare".to_string(),
+                },
+                default_ttl: Some(600),
+                ..Default::default()
+            },
+        ));
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+
+        let mut ddns = ddns("web", "www.example.com");
+        ddns.spec.provider_ref = Some(ProviderRef {
+            name: "cloudflare".to_string(),
+        });
+        harness.kube.create(&ddns);
+
+        let records_match = |harness: &Harness, ttl: u32, proxied: bool| {
+            let records = harness.cloudflare.records(ZONE, "www.example.com");
+
+            !records.is_empty()
+                && records
+                    .iter()
+                    .all(|record| record.ttl == ttl && record.proxied == proxied)
+        };
+
+        harness
+            .wait_until("records use the provider ttl", |harness| {
+                records_match(harness, 600, false)
+            })
+            .await;
+
+        // the unchanged IPs are published again with the new provider settings
+        harness
+            .kube
+            .modify("", "cloudflare", |provider: &mut DnsProviderConfig| {
+                provider.spec.default_ttl = Some(900);
+            });
+
+        harness
+            .wait_until("records use the new ttl", |harness| {
+                records_match(harness, 900, false)
+            })
+            .await;
+
+        harness
+            .kube
+            .modify("", "cloudflare", |provider: &mut DnsProviderConfig| {
+                provider.spec.proxied = true;
+            });
+
+        harness
+            .wait_until("records are proxied", |harness| {
+                records_match(harness, 900, true)
+            })
+            .await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn retry_failed_trigger() {
+        let harness = Harness::start().await;
+        let ip = IpAddr::from([1, 1, 1, 1]);
+
+        let mut service = service("web");
+        set_lb_ips(&mut service, &[ip]);
+        harness.kube.create(&service);
+        harness.kube.create(&ddns("web", "www.example.com"));
+
+        harness
+            .wait_until("records are published", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [ip]
+            })
+            .await;
+
+        // the list of the ddns selecting the changed service fails, the change is not lost
+        harness.kube.fail_lists::<Ddns>(1);
+
+        let new_ip = IpAddr::from([2, 2, 2, 2]);
+        harness
+            .kube
+            .modify(NAMESPACE, "web", |service| set_lb_ips(service, &[new_ip]));
+
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(harness.cloudflare.ips(ZONE, "www.example.com"), [ip]);
+
+        harness
+            .wait_until("records are updated by the retry", |harness| {
+                harness.cloudflare.ips(ZONE, "www.example.com") == [new_ip]
+            })
+            .await;
+
+        harness.stop().await;
+    }
+}
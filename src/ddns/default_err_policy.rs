@@ -11,11 +11,15 @@ use crate::spec::Ddns;
 #[derive(Clone)]
 pub struct DefaultErrPolicy<S> {
     retry_queue: S,
+    retry_delay: Duration,
 }
 
 impl<S> DefaultErrPolicy<S> {
-    pub fn new(retry_queue: S) -> Self {
-        Self { retry_queue }
+    pub fn new(retry_queue: S, retry_delay: Duration) -> Self {
+        Self {
+            retry_queue,
+            retry_delay,
+        }
     }
 }
 
@@ -44,10 +48,12 @@ where
             }
 
             Error::Other(err) => {
-                info!(?ddns, %err, "handle ddns failed, need to reconcile after 3 seconds");
+                let retry_delay = self.retry_delay;
+
+                info!(?ddns, %err, ?retry_delay, "handle ddns failed, need to reconcile later");
 
                 tokio::spawn(async move {
-                    time::sleep(Duration::from_secs(3)).await;
+                    time::sleep(retry_delay).await;
 
                     futures_util::pin_mut!(retry_queue);
 
@@ -57,3 +63,80 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::mpsc;
+    use futures_util::StreamExt;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn ddns(name: &str) -> Ddns {
+        let mut ddns = Ddns::default();
+        ddns.metadata.namespace = Some("default".to_string());
+        ddns.metadata.name = Some(name.to_string());
+
+        ddns
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_schedule() {
+        let retry_delay = Duration::from_secs(3);
+        let (sender, mut receiver) = mpsc::unbounded();
+        let err_policy = DefaultErrPolicy::new(sender, retry_delay);
+
+        // the failed reconcile is retried after the retry delay
+        let start = Instant::now();
+        err_policy
+            .error_policy(ddns("failed"), anyhow::anyhow!("failed").into())
+            .await;
+
+        let retried = receiver.next().await.unwrap();
+        assert_eq!(retried.metadata.name.as_deref(), Some("failed"));
+        assert_eq!(start.elapsed(), retry_delay);
+
+        // the re-run is retried after the requested delay
+        let start = Instant::now();
+        err_policy
+            .error_policy(ddns("rerun"), Duration::from_secs(30).into())
+            .await;
+
+        let retried = receiver.next().await.unwrap();
+        assert_eq!(retried.metadata.name.as_deref(), Some("rerun"));
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_in_delay_order() {
+        let (sender, mut receiver) = mpsc::unbounded();
+        let err_policy = DefaultErrPolicy::new(sender, Duration::from_secs(3));
+
+        let start = Instant::now();
+        err_policy
+            .error_policy(ddns("slow"), Duration::from_secs(10).into())
+            .await;
+        err_policy
+            .error_policy(ddns("failed"), anyhow::anyhow!("failed").into())
+            .await;
+        err_policy
+            .error_policy(ddns("fast"), Duration::from_secs(1).into())
+            .await;
+
+        let mut retried = vec![];
+        for _ in 0..3 {
+            let ddns = receiver.next().await.unwrap();
+
+            retried.push((ddns.metadata.name.unwrap(), start.elapsed().as_secs()));
+        }
+
+        assert_eq!(
+            retried,
+            [
+                ("fast".to_string(), 1),
+                ("failed".to_string(), 3),
+                ("slow".to_string(), 10)
+            ]
+        );
+    }
+}
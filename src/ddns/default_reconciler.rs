@@ -1,21 +1,38 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use k8s_openapi::chrono::Utc;
 use kube::api::{ListParams, Patch, PatchParams};
+use kube::runtime::reflector::{ObjectRef, Store};
 use kube::{Api, Client};
 use serde::Serialize;
 use tap::TapFallible;
 use tracing::{error, info, instrument, warn};
 
-use crate::cf_dns::{CfDns, RecordKind};
+use crate::cf_dns::{CfDns, RecordKind, SrvRecord};
+use crate::cf_dns_pool::CfDnsPool;
+use crate::ddns::conflict::{find_domain_owner, CONFLICT_CONDITION};
+use crate::ddns::desired::{desired_addresses, DesiredAddresses};
+use crate::ddns::failover::active_selector;
+use crate::ddns::srv::srv_records;
 use crate::ddns::{Error, Reconcile};
-use crate::spec::{Ddns, DdnsStatus};
+use crate::hostname::normalize_hostname;
+use crate::ip_filter::IpFilter;
+use crate::spec::{Ddns, DdnsSpec, DdnsStatus, PublishedCredentials};
 
-const FINALIZER: &str = "ddns.finalizer.api.sherlockholo.io";
+pub const FINALIZER: &str = "ddns.finalizer.api.sherlockholo.io";
+
+/// Whether the credentials of the published records can be resolved when deleting
+const CREDENTIALS_CONDITION: &str = "CredentialsResolved";
+
+/// Set when all the IPs are filtered out, the records are removed meanwhile
+const FILTERED_CONDITION: &str = "Filtered";
 
 #[derive(Debug, Serialize)]
 struct Finalizers {
@@ -48,21 +65,222 @@ impl From<String> for PatchFinalizers {
 #[derive(Clone)]
 pub struct DefaultReconciler {
     client: Client,
-    cf_dns: CfDns,
+    cf_dns_pool: CfDnsPool,
+    no_ip_retry_delay: Duration,
+    conflict_recheck_delay: Duration,
+    delete_credentials_timeout: Duration,
+    ip_filter: IpFilter,
+    /// All the Ddns of the cluster, so the domain owner is found without listing them
+    ddns_store: Store<Ddns>,
 }
 
 impl DefaultReconciler {
-    pub fn new(client: Client, cf_dns: CfDns) -> Self {
-        Self { client, cf_dns }
+    pub fn new(
+        client: Client,
+        cf_dns_pool: CfDnsPool,
+        no_ip_retry_delay: Duration,
+        conflict_recheck_delay: Duration,
+        delete_credentials_timeout: Duration,
+        ip_filter: IpFilter,
+        ddns_store: Store<Ddns>,
+    ) -> Self {
+        Self {
+            client,
+            cf_dns_pool,
+            no_ip_retry_delay,
+            conflict_recheck_delay,
+            delete_credentials_timeout,
+            ip_filter,
+            ddns_store,
+        }
+    }
+
+    /// Remove the records of the domain published before, unless the domain is claimed by another
+    /// Ddns, which will overwrite the records itself
+    async fn release_old_domain(
+        &self,
+        cf_dns: &CfDns,
+        status: &DdnsStatus,
+        ddns_list: &[Arc<Ddns>],
+        obj_ref: &ObjectRef<Ddns>,
+    ) -> Result<(), Error> {
+        if status.domain.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(owner) = find_domain_owner(&status.domain, ddns_list.iter().map(Arc::as_ref))
+            .map(ObjectRef::from_obj)
+            .filter(|owner| owner != obj_ref)
+        {
+            info!(?status, %owner, "old domain is claimed by other ddns, keep records");
+
+            return Ok(());
+        }
+
+        cf_dns
+            .remove_dns_records(&status.domain, &status.zone, RecordKind::A)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove the published SRV records which are not kept. The owner of the domain never
+    /// publishes the SRV names it doesn't select, so the records of this Ddns are removed even
+    /// if the domain is claimed by another Ddns, except the names the owner publishes too.
+    async fn release_srv_records(
+        &self,
+        cf_dns: &CfDns,
+        status: &DdnsStatus,
+        keep: &[String],
+        ddns_list: &[Arc<Ddns>],
+        obj_ref: &ObjectRef<Ddns>,
+    ) -> Result<(), Error> {
+        let owner_srv_records =
+            find_domain_owner(&status.domain, ddns_list.iter().map(Arc::as_ref))
+                .filter(|owner| ObjectRef::from_obj(*owner) != *obj_ref)
+                .and_then(|owner| owner.status.as_ref())
+                .map(|owner_status| owner_status.srv_records.as_slice())
+                .unwrap_or_default();
+
+        for name in status
+            .srv_records
+            .iter()
+            .filter(|name| !keep.contains(name))
+        {
+            if owner_srv_records.contains(name) {
+                info!(%name, "srv record is published by the domain owner too, keep it");
+
+                continue;
+            }
+
+            cf_dns
+                .remove_dns_records(name, &status.zone, RecordKind::SRV)
+                .await?;
+
+            info!(%name, zone = %status.zone, "remove srv record done");
+        }
+
+        Ok(())
+    }
+
+    /// Release the published records with the credentials they are published with, which are
+    /// not the credentials of the spec if they are changed
+    async fn release_published(
+        &self,
+        namespace: &str,
+        spec: &DdnsSpec,
+        status: &mut DdnsStatus,
+        ddns_list: &[Arc<Ddns>],
+        obj_ref: &ObjectRef<Ddns>,
+    ) -> Result<(), Error> {
+        if status.domain.is_empty() {
+            return Ok(());
+        }
+
+        let published_cf_dns = self
+            .cf_dns_pool
+            .get_published(namespace, spec, status)
+            .await
+            .tap_err(
+                |err| error!(%err, ?status, "get the credentials of the published records failed"),
+            )?;
+
+        self.release_old_domain(&published_cf_dns, status, ddns_list, obj_ref)
+            .await?;
+        self.release_srv_records(&published_cf_dns, status, &[], ddns_list, obj_ref)
+            .await?;
+
+        status.srv_records.clear();
+
+        Ok(())
+    }
+
+    /// Keep the deleting Ddns while the credentials of its records can't be resolved, like the
+    /// Secret deleted together with the namespace. After the delete credentials timeout since
+    /// the deletion, the Ddns is released and the records are left in the zone, so the deletion
+    /// is not blocked forever.
+    async fn wait_credentials(
+        &self,
+        ddns_api: &Api<Ddns>,
+        name: &str,
+        deletion_timestamp: Option<&Time>,
+        status: &mut DdnsStatus,
+        err: anyhow::Error,
+    ) -> Result<(), Error> {
+        status.set_condition(
+            CREDENTIALS_CONDITION,
+            false,
+            "CredentialsUnavailable",
+            format!("get the credentials of the records failed: {:#}", err),
+        );
+
+        let waited = deletion_timestamp
+            .and_then(|time| (Utc::now() - time.0).to_std().ok())
+            .unwrap_or_default();
+
+        if waited < self.delete_credentials_timeout {
+            ddns_api
+                .patch_status(
+                    name,
+                    &PatchParams::default(),
+                    &Patch::Merge(status.to_patch_status()),
+                )
+                .await?;
+
+            warn!(%name, ?waited, %err, "credentials of the records are unavailable, wait for them");
+
+            return Err(err.into());
+        }
+
+        warn!(
+            %name,
+            domain = %status.domain,
+            zone = %status.zone,
+            ?waited,
+            %err,
+            "credentials of the records are still unavailable, leave the records"
+        );
+
+        status.set_condition(
+            CREDENTIALS_CONDITION,
+            false,
+            "RecordsLeft",
+            format!(
+                "the records of {} are left in zone {}, get the credentials failed: {:#}",
+                status.domain, status.zone, err
+            ),
+        );
+
+        Ok(())
     }
 }
 
+/// Whether the published records are not where the spec asks, so they are released before the
+/// records of the spec are published
+fn is_published_elsewhere(spec: &DdnsSpec, status: &DdnsStatus) -> bool {
+    !status.domain.is_empty()
+        && (normalize_hostname(&status.domain) != normalize_hostname(&spec.domain)
+            || normalize_hostname(&status.zone) != normalize_hostname(&spec.zone)
+            || status
+                .published_credentials
+                .as_ref()
+                .is_some_and(|credentials| *credentials != PublishedCredentials::from(spec)))
+}
+
 #[async_trait]
 impl Reconcile for DefaultReconciler {
     type Error = Error;
 
     #[instrument(err, skip(self))]
     async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        // the reconciled ddns comes from the store, so the store is synced already
+        let ddns_list = self.ddns_store.state();
+
+        let obj_ref = ObjectRef::from_obj(&ddns);
+        let domain_owner = find_domain_owner(&ddns.spec.domain, ddns_list.iter().map(Arc::as_ref))
+            .map(ObjectRef::from_obj)
+            .filter(|owner| *owner != obj_ref);
+
         let metadata = ddns.metadata;
         let name = metadata.name.ok_or_else(|| {
             error!("ddns resource doesn't have name");
@@ -82,25 +300,80 @@ impl Reconcile for DefaultReconciler {
 
         let mut status = status.unwrap_or_default();
 
-        if status.domain != spec.domain {
-            info!(?status, ?spec, "status domain != spec domain");
+        let ddns_api: Api<Ddns> = Api::namespaced(self.client.clone(), &namespace);
+
+        if let Some(domain_owner) = domain_owner {
+            let owner_name = format!(
+                "{}/{}",
+                domain_owner.namespace.unwrap_or_default(),
+                domain_owner.name
+            );
+
+            warn!(%name, ?spec, %owner_name, "domain is owned by other ddns");
 
-            self.cf_dns
-                .remove_dns_records(&spec.domain, &spec.zone, RecordKind::A)
+            self.release_published(&namespace, &spec, &mut status, &ddns_list, &obj_ref)
                 .await?;
 
-            info!(%name, ?spec, ?status, "remove old dns records done");
+            status.status = "CONFLICT".to_string();
+            status.selector = spec.selector;
+            status.domain.clear();
+            status.zone.clear();
+            status.srv_records.clear();
+            status.observed_generation = metadata.generation;
+            status.set_condition(
+                CONFLICT_CONDITION,
+                true,
+                "DomainOwnedByOther",
+                format!("domain {} is owned by Ddns {}", spec.domain, owner_name),
+            );
+
+            ddns_api
+                .patch_status(
+                    &name,
+                    &PatchParams::default(),
+                    &Patch::Merge(status.to_patch_status()),
+                )
+                .await?;
+
+            info!(%name, ?status, "update conflict status done");
+
+            return Err(self.conflict_recheck_delay.into());
         }
 
-        let ddns_api: Api<Ddns> = Api::namespaced(self.client.clone(), &namespace);
-        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &namespace);
+        // the published records are kept until the records of the spec can be published
+        let cf_dns = self.cf_dns_pool.get(&namespace, &spec).await?;
+
+        if is_published_elsewhere(&spec, &status) {
+            info!(
+                ?status,
+                ?spec,
+                "records are published with other domain, zone or credentials"
+            );
 
-        let lb_ips = get_service_lb_ips(&service_api, &spec.selector).await?;
+            self.release_published(&namespace, &spec, &mut status, &ddns_list, &obj_ref)
+                .await?;
 
-        if lb_ips.is_empty() {
+            info!(%name, ?spec, ?status, "remove old dns records done");
+        }
+
+        let DesiredAddresses {
+            services,
+            ips: lb_ips,
+            filtered_ips,
+            failback_recheck,
+        } = desired_addresses(
+            self.client.clone(),
+            &namespace,
+            &spec,
+            &mut status,
+            &self.ip_filter,
+        )
+        .await?;
+
+        if lb_ips.is_empty() && filtered_ips.is_empty() {
             warn!(%name, ?spec, ?status, "load balancer has no ip");
 
-            return Err(Duration::from_secs(3).into());
+            return Err(self.no_ip_retry_delay.into());
         }
 
         info!(
@@ -108,10 +381,30 @@ impl Reconcile for DefaultReconciler {
             ?spec,
             ?status,
             load_balancer_ip_list=?lb_ips,
+            ?filtered_ips,
             "get service load balancer ip list success"
         );
 
-        self.cf_dns
+        status.filtered_ips = filtered_ips.iter().map(IpAddr::to_string).collect();
+
+        // the records are removed if all the IPs are filtered out, they must not be published
+        if lb_ips.is_empty() && !filtered_ips.is_empty() {
+            warn!(%name, ?filtered_ips, "all the ips are filtered out, remove the records");
+
+            status.set_condition(
+                FILTERED_CONDITION,
+                true,
+                "AllAddressesFiltered",
+                format!(
+                    "all the addresses {:?} are filtered out, the records are removed",
+                    status.filtered_ips
+                ),
+            );
+        } else {
+            status.remove_condition(FILTERED_CONDITION);
+        }
+
+        cf_dns
             .set_dns_record(&spec.domain, &spec.zone, RecordKind::A, &lb_ips)
             .await?;
 
@@ -123,6 +416,26 @@ impl Reconcile for DefaultReconciler {
             "set dns record success"
         );
 
+        // the SRV records can't point at a domain without any address
+        let srv_records = spec
+            .srv
+            .as_ref()
+            .filter(|_| !lb_ips.is_empty())
+            .map(|srv| srv_records(&services, &spec.domain, srv))
+            .unwrap_or_default();
+        let srv_names = srv_records.iter().map(SrvRecord::name).collect::<Vec<_>>();
+
+        self.release_srv_records(&cf_dns, &status, &srv_names, &ddns_list, &obj_ref)
+            .await?;
+
+        for srv_record in &srv_records {
+            cf_dns.set_srv_record(&spec.zone, srv_record).await?;
+        }
+
+        if !srv_records.is_empty() {
+            info!(%name, ?srv_names, "set srv records success");
+        }
+
         let finalizer_patch = match metadata.finalizers {
             None => Some(PatchFinalizers::from(FINALIZER.to_string())),
             Some(mut finalizers) if !finalizers.iter().any(|finalizer| finalizer == FINALIZER) => {
@@ -153,9 +466,18 @@ impl Reconcile for DefaultReconciler {
         }
 
         status.status = "RUNNING".to_string();
-        status.selector = spec.selector;
+        status.published_credentials = Some(PublishedCredentials::from(&spec));
+        status.set_condition(
+            CONFLICT_CONDITION,
+            false,
+            "DomainOwned",
+            format!("domain {} is owned by this Ddns", spec.domain),
+        );
+        status.selector = active_selector(&spec, Some(&status)).clone();
         status.domain = spec.domain;
         status.zone = spec.zone;
+        status.srv_records = srv_names;
+        status.observed_generation = metadata.generation;
 
         ddns_api
             .patch_status(
@@ -172,6 +494,12 @@ impl Reconcile for DefaultReconciler {
             "update status done"
         );
 
+        if let Some(failback_recheck) = failback_recheck {
+            info!(%name, ?failback_recheck, "higher failover tier waits for the failback delay");
+
+            return Err(failback_recheck.into());
+        }
+
         Ok(())
     }
 
@@ -197,6 +525,12 @@ impl Reconcile for DefaultReconciler {
 
         let patch_params = PatchParams::default();
 
+        // the retry doesn't patch it again, the snapshot may miss the conditions set by the last
+        // attempt and remove them
+        let deleting = status
+            .as_ref()
+            .is_some_and(|status| status.status == "DELETING");
+
         let mut status = if let Some(mut status) = status {
             status.status = "DELETING".to_string();
 
@@ -204,44 +538,75 @@ impl Reconcile for DefaultReconciler {
         } else {
             DdnsStatus {
                 status: "DELETING".to_string(),
-                selector: spec.selector,
-                domain: spec.domain,
-                zone: spec.zone,
+                selector: spec.selector.clone(),
+                domain: spec.domain.clone(),
+                zone: spec.zone.clone(),
+                ..Default::default()
             }
         };
 
         let ddns_api: Api<Ddns> = Api::namespaced(self.client.clone(), &namespace);
 
-        match ddns_api
-            .patch_status(
-                &name,
-                &patch_params,
-                &Patch::Merge(status.to_patch_status()),
-            )
-            .await
-        {
-            Err(kube::Error::Api(err)) if err.code == 404 => {
-                info!(%name, ?status, ?finalizers, "resource has been deleted");
+        if !deleting {
+            match ddns_api
+                .patch_status(
+                    &name,
+                    &patch_params,
+                    &Patch::Merge(status.to_patch_status()),
+                )
+                .await
+            {
+                Err(kube::Error::Api(err)) if err.code == 404 => {
+                    info!(%name, ?status, ?finalizers, "resource has been deleted");
 
-                return Ok(());
-            }
+                    return Ok(());
+                }
 
-            Err(err) => {
-                error!(%err, "patch ddns status failed");
+                Err(err) => {
+                    error!(%err, "patch ddns status failed");
 
-                return Err(err.into());
+                    return Err(err.into());
+                }
+
+                Ok(_) => {}
             }
 
-            Ok(_) => {}
+            info!(%name, ?status, ?finalizers, "update status to DELETING done");
         }
 
-        info!(%name, ?status, ?finalizers, "update status to DELETING done");
-
-        self.cf_dns
-            .remove_dns_records(&status.domain, &status.zone, RecordKind::A)
-            .await?;
-
-        info!(%name, ?status, ?finalizers, "remove dns records success");
+        // a conflicted Ddns doesn't publish any record
+        if !status.domain.is_empty() {
+            match self
+                .cf_dns_pool
+                .get_published(&namespace, &spec, &status)
+                .await
+            {
+                Err(err) => {
+                    self.wait_credentials(
+                        &ddns_api,
+                        &name,
+                        metadata.deletion_timestamp.as_ref(),
+                        &mut status,
+                        err,
+                    )
+                    .await?
+                }
+
+                Ok(cf_dns) => {
+                    cf_dns
+                        .remove_dns_records(&status.domain, &status.zone, RecordKind::A)
+                        .await?;
+
+                    for srv_name in &status.srv_records {
+                        cf_dns
+                            .remove_dns_records(srv_name, &status.zone, RecordKind::SRV)
+                            .await?;
+                    }
+
+                    info!(%name, ?status, ?finalizers, "remove dns records success");
+                }
+            }
+        }
 
         status.status = "DELETED".to_string();
 
@@ -296,45 +661,57 @@ impl Reconcile for DefaultReconciler {
     }
 }
 
+/// Get the services matching any label of the selector
 #[instrument(err, skip(service_api))]
-async fn get_service_lb_ips(
+pub(super) async fn get_services(
     service_api: &Api<Service>,
     selector: &HashMap<String, String>,
-) -> Result<Vec<IpAddr>, Error> {
+) -> Result<Vec<Service>, Error> {
     stream::iter(selector.iter())
         .then(|(key, value)| async move {
             let list_params = ListParams::default().labels(&format!("{}={}", key, value));
 
-            let svc_list = service_api.list(&list_params).await.map_err(|err| {
+            let svc_list = service_api.list(&list_params).await.tap_err(|_| {
                 error!(selector_key=%key, selector_value=%value, "list service failed");
-
-                err
             })?;
 
             Ok::<_, Error>(svc_list.items)
         })
-        .try_fold(vec![], |mut svc_ips, svc_list| async move {
-            let mut svc_status_list = svc_list
-                .into_iter()
-                .filter_map(|svc| {
-                    svc.status
-                        .and_then(|status| status.load_balancer)
-                        .and_then(|lb| lb.ingress)
-                })
-                .flatten()
-                .flat_map(|ingress| ingress.ip)
-                .map(|ip| {
-                    ip.parse().map_err(|err| {
-                        error!(addr_parse_err=%err, "parse load balancer ingress IP failed");
-
-                        anyhow::Error::from(err).into()
-                    })
-                })
-                .collect::<Result<Vec<_>, Error>>()?;
-
-            svc_ips.append(&mut svc_status_list);
-
-            Ok(svc_ips)
+        .try_fold(vec![], |mut services, mut svc_list| async move {
+            services.append(&mut svc_list);
+
+            Ok(services)
         })
         .await
 }
+
+/// Get the load balancer IPs of the services
+pub fn service_lb_ips(services: &[Service]) -> Result<Vec<IpAddr>, Error> {
+    services
+        .iter()
+        .filter_map(|svc| {
+            svc.status
+                .as_ref()
+                .and_then(|status| status.load_balancer.as_ref())
+                .and_then(|lb| lb.ingress.as_ref())
+        })
+        .flatten()
+        .filter_map(|ingress| ingress.ip.as_ref())
+        .map(|ip| {
+            ip.parse().map_err(|err| {
+                error!(addr_parse_err=%err, "parse load balancer ingress IP failed");
+
+                anyhow::Error::from(err).into()
+            })
+        })
+        .collect()
+}
+
+/// Whether the service has any load balancer IP
+pub(super) fn has_lb_ip(svc: &Service) -> bool {
+    svc.status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|lb| lb.ingress.as_ref())
+        .is_some_and(|ingress| ingress.iter().any(|ingress| ingress.ip.is_some()))
+}
@@ -0,0 +1,107 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Service;
+use kube::{Api, Client};
+use tracing::{error, info};
+
+use crate::ddns::addresses::{combine_addresses, parse_addresses};
+use crate::ddns::default_reconciler::{get_services, service_lb_ips};
+use crate::ddns::endpoints::{select_ready_services, ENDPOINTS_READY_CONDITION};
+use crate::ddns::failover::select_tier;
+use crate::ddns::Error;
+use crate::ip_filter::IpFilter;
+use crate::spec::{DdnsSpec, DdnsStatus};
+
+/// The addresses which a Ddns publishes
+#[derive(Debug)]
+pub struct DesiredAddresses {
+    /// The selected services, the SRV records point at their ports
+    pub services: Vec<Service>,
+    pub ips: Vec<IpAddr>,
+    pub filtered_ips: Vec<IpAddr>,
+    /// When a healthy higher tier waits for the failback delay, check it again after this
+    pub failback_recheck: Option<Duration>,
+}
+
+/// Get the addresses of the Ddns like the controller publishes them: the services of the active
+/// tier, only the ready ones if required, their load balancer IPs combined with the additional
+/// addresses, then the controller filter and the Ddns filter. The active tier and the readiness
+/// are recorded in the status.
+///
+/// It is shared by the controller and ddnsctl, so ddnsctl shows what the controller publishes.
+pub async fn desired_addresses(
+    client: Client,
+    namespace: &str,
+    spec: &DdnsSpec,
+    status: &mut DdnsStatus,
+    ip_filter: &IpFilter,
+) -> Result<DesiredAddresses, Error> {
+    let service_api: Api<Service> = Api::namespaced(client.clone(), namespace);
+    let endpoint_slice_api = Api::namespaced(client, namespace);
+
+    let (services, failback_recheck) = match spec
+        .failover
+        .as_ref()
+        .filter(|failover| !failover.tiers.is_empty())
+    {
+        None => {
+            status.active_tier = None;
+            status.last_tier_switch_time = None;
+
+            (get_services(&service_api, &spec.selector).await?, None)
+        }
+
+        Some(failover) => {
+            let selection =
+                select_tier(&service_api, &endpoint_slice_api, failover, status).await?;
+
+            (selection.services, selection.recheck)
+        }
+    };
+
+    let services = if spec.ready_endpoints_only {
+        select_ready_services(&endpoint_slice_api, services, status).await?
+    } else {
+        status.remove_condition(ENDPOINTS_READY_CONDITION);
+
+        services
+    };
+
+    let lb_ips = service_lb_ips(&services)?;
+
+    let additional_addresses = parse_addresses(&spec.additional_addresses).map_err(|reason| {
+        error!(%reason, "additional addresses are invalid");
+
+        anyhow::anyhow!("additional addresses are invalid: {}", reason)
+    })?;
+    let ips = combine_addresses(spec.address_mode, lb_ips, additional_addresses);
+
+    let spec_filter = spec
+        .ip_filter
+        .as_ref()
+        .map(IpFilter::try_from)
+        .transpose()
+        .map_err(|reason| {
+            error!(%reason, "ip filter is invalid");
+
+            anyhow::anyhow!("ip filter is invalid: {}", reason)
+        })?
+        .unwrap_or_default();
+
+    // an IP must pass both the controller filter and the Ddns filter
+    let (ips, filtered_ips): (Vec<_>, Vec<_>) = ips
+        .into_iter()
+        .partition(|ip| ip_filter.is_allowed(ip) && spec_filter.is_allowed(ip));
+
+    if !filtered_ips.is_empty() {
+        info!(?filtered_ips, "filter out load balancer ips");
+    }
+
+    Ok(DesiredAddresses {
+        services,
+        ips,
+        filtered_ips,
+        failback_recheck,
+    })
+}
@@ -0,0 +1,157 @@
+use itertools::Itertools;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::ListParams;
+use kube::Api;
+use tap::TapFallible;
+use tracing::{error, info, warn};
+
+use crate::ddns::default_reconciler::has_lb_ip;
+use crate::ddns::Error;
+use crate::service::{slice_is_ready, SERVICE_NAME_LABEL};
+use crate::spec::DdnsStatus;
+
+pub const ENDPOINTS_READY_CONDITION: &str = "EndpointsReady";
+
+/// Select the load balancer services which have ready endpoints, the IPs of the others are
+/// withheld. If none of them is ready, all of them are selected, an empty record set is worse
+/// than the not ready services. The result is reported by the [`ENDPOINTS_READY_CONDITION`].
+pub async fn select_ready_services(
+    endpoint_slice_api: &Api<EndpointSlice>,
+    services: Vec<Service>,
+    status: &mut DdnsStatus,
+) -> Result<Vec<Service>, Error> {
+    let mut ready_services = vec![];
+    let mut not_ready_services = vec![];
+
+    for svc in services.into_iter().filter(has_lb_ip) {
+        if has_ready_endpoints(endpoint_slice_api, &svc).await? {
+            ready_services.push(svc);
+        } else {
+            not_ready_services.push(svc);
+        }
+    }
+
+    let selection = select(ready_services, not_ready_services);
+
+    status.set_condition(
+        ENDPOINTS_READY_CONDITION,
+        selection.ready,
+        selection.reason,
+        selection.message,
+    );
+
+    Ok(selection.services)
+}
+
+#[derive(Debug)]
+struct Selection {
+    services: Vec<Service>,
+    ready: bool,
+    reason: &'static str,
+    message: String,
+}
+
+fn select(ready_services: Vec<Service>, not_ready_services: Vec<Service>) -> Selection {
+    if not_ready_services.is_empty() {
+        return Selection {
+            services: ready_services,
+            ready: true,
+            reason: "AllReady",
+            message: "all the services have ready endpoints".to_string(),
+        };
+    }
+
+    let not_ready_names = not_ready_services
+        .iter()
+        .filter_map(|svc| svc.metadata.name.as_deref())
+        .join(", ");
+
+    if ready_services.is_empty() {
+        warn!(%not_ready_names, "no service has ready endpoints, publish all of them");
+
+        return Selection {
+            services: not_ready_services,
+            ready: false,
+            reason: "NoReadyEndpoints",
+            message: format!(
+                "no service has ready endpoints, publish all of them: {}",
+                not_ready_names
+            ),
+        };
+    }
+
+    info!(%not_ready_names, "withhold the services without ready endpoints");
+
+    Selection {
+        services: ready_services,
+        ready: false,
+        reason: "EndpointsNotReady",
+        message: format!(
+            "withhold the services without ready endpoints: {}",
+            not_ready_names
+        ),
+    }
+}
+
+pub(super) async fn has_ready_endpoints(
+    endpoint_slice_api: &Api<EndpointSlice>,
+    svc: &Service,
+) -> Result<bool, Error> {
+    let name = svc.metadata.name.as_deref().unwrap_or_default();
+    let list_params = ListParams::default().labels(&format!("{}={}", SERVICE_NAME_LABEL, name));
+
+    let slices = endpoint_slice_api
+        .list(&list_params)
+        .await
+        .tap_err(|err| error!(%err, %name, "list endpoint slices failed"))?;
+
+    Ok(slices.items.iter().any(slice_is_ready))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str) -> Service {
+        let mut svc = Service::default();
+        svc.metadata.name = Some(name.to_string());
+
+        svc
+    }
+
+    fn names(services: &[Service]) -> Vec<&str> {
+        services
+            .iter()
+            .filter_map(|svc| svc.metadata.name.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn all_ready() {
+        let selection = select(vec![service("a"), service("b")], vec![]);
+
+        assert_eq!(names(&selection.services), ["a", "b"]);
+        assert!(selection.ready);
+    }
+
+    #[test]
+    fn withhold_not_ready() {
+        let selection = select(vec![service("a")], vec![service("b"), service("c")]);
+
+        assert_eq!(names(&selection.services), ["a"]);
+        assert!(!selection.ready);
+        assert_eq!(selection.reason, "EndpointsNotReady");
+        assert!(selection.message.ends_with("b, c"));
+    }
+
+    #[test]
+    fn none_ready() {
+        let selection = select(vec![], vec![service("b"), service("c")]);
+
+        // the records are never emptied by the readiness
+        assert_eq!(names(&selection.services), ["b", "c"]);
+        assert!(!selection.ready);
+        assert_eq!(selection.reason, "NoReadyEndpoints");
+    }
+}
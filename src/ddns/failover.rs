@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::chrono::{DateTime, SecondsFormat, Utc};
+use kube::Api;
+use tracing::{info, warn};
+
+use crate::ddns::default_reconciler::{get_services, has_lb_ip};
+use crate::ddns::endpoints::has_ready_endpoints;
+use crate::ddns::Error;
+use crate::spec::{DdnsSpec, DdnsStatus, FailoverSpec};
+
+/// The services of the active tier
+#[derive(Debug)]
+pub struct TierSelection {
+    pub services: Vec<Service>,
+    /// When a healthy higher tier waits for the failback delay, check it again after this
+    pub recheck: Option<Duration>,
+}
+
+/// Select the services of the tier to publish, the active tier and its last switch time are
+/// recorded in the status
+pub async fn select_tier(
+    service_api: &Api<Service>,
+    endpoint_slice_api: &Api<EndpointSlice>,
+    failover: &FailoverSpec,
+    status: &mut DdnsStatus,
+) -> Result<TierSelection, Error> {
+    let mut tier_services = Vec::with_capacity(failover.tiers.len());
+    let mut healthy = Vec::with_capacity(failover.tiers.len());
+
+    for tier in &failover.tiers {
+        let services = get_services(service_api, &tier.match_labels).await?;
+
+        let mut tier_healthy = false;
+        for svc in services.iter().filter(|svc| has_lb_ip(svc)) {
+            if has_ready_endpoints(endpoint_slice_api, svc).await? {
+                tier_healthy = true;
+
+                break;
+            }
+        }
+
+        tier_services.push(services);
+        healthy.push(tier_healthy);
+    }
+
+    let active = status
+        .active_tier
+        .as_ref()
+        .and_then(|name| failover.tiers.iter().position(|tier| tier.name == *name));
+
+    // an unknown time means the switch is long ago, the failback is not blocked by it
+    let since_switch = status
+        .last_tier_switch_time
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .and_then(|time| (Utc::now() - time.with_timezone(&Utc)).to_std().ok());
+
+    let decision = choose_tier(
+        &healthy,
+        active,
+        since_switch,
+        Duration::from_secs(failover.failback_delay_seconds),
+    );
+
+    let tier_name = &failover.tiers[decision.tier].name;
+
+    if !healthy[decision.tier] {
+        warn!(%tier_name, "no failover tier is healthy");
+    }
+
+    if active != Some(decision.tier) {
+        info!(from = ?status.active_tier, to = %tier_name, "switch failover tier");
+
+        status.active_tier = Some(tier_name.clone());
+        status.last_tier_switch_time = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+
+    Ok(TierSelection {
+        services: tier_services.swap_remove(decision.tier),
+        recheck: decision.recheck,
+    })
+}
+
+/// The selector of the services which are published, it is the active tier if the failover is
+/// set
+pub fn active_selector<'a>(
+    spec: &'a DdnsSpec,
+    status: Option<&DdnsStatus>,
+) -> &'a HashMap<String, String> {
+    let failover = match &spec.failover {
+        None => return &spec.selector,
+        Some(failover) => failover,
+    };
+
+    let active_tier = status.and_then(|status| status.active_tier.as_ref());
+
+    failover
+        .tiers
+        .iter()
+        .find(|tier| Some(&tier.name) == active_tier)
+        .or_else(|| failover.tiers.first())
+        .map(|tier| &tier.match_labels)
+        .unwrap_or(&spec.selector)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Decision {
+    tier: usize,
+    recheck: Option<Duration>,
+}
+
+/// Choose the tier by the health of the tiers, there is always at least one tier.
+///
+/// - without an active tier, the first healthy tier, or the first tier if none is healthy
+/// - the active tier isn't healthy, the first healthy tier at once, or keep it if none is healthy
+/// - a higher tier is healthy, switch to it after the failback delay since the last switch
+fn choose_tier(
+    healthy: &[bool],
+    active: Option<usize>,
+    since_switch: Option<Duration>,
+    failback_delay: Duration,
+) -> Decision {
+    let first_healthy = healthy.iter().position(|healthy| *healthy);
+
+    let tier = match (active, first_healthy) {
+        (None, first_healthy) => first_healthy.unwrap_or(0),
+        (Some(active), None) => active,
+        (Some(active), Some(first_healthy)) if !healthy[active] => first_healthy,
+        (Some(active), Some(first_healthy)) if first_healthy < active => match since_switch {
+            Some(since_switch) if since_switch < failback_delay => {
+                return Decision {
+                    tier: active,
+                    recheck: Some(failback_delay - since_switch),
+                };
+            }
+
+            _ => first_healthy,
+        },
+        (Some(active), Some(_)) => active,
+    };
+
+    Decision {
+        tier,
+        recheck: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::FailoverTier;
+
+    const DELAY: Duration = Duration::from_secs(300);
+
+    fn decision(tier: usize, recheck: Option<u64>) -> Decision {
+        Decision {
+            tier,
+            recheck: recheck.map(Duration::from_secs),
+        }
+    }
+
+    #[test]
+    fn first_healthy_tier() {
+        assert_eq!(
+            choose_tier(&[false, true, true], None, None, DELAY),
+            decision(1, None)
+        );
+        assert_eq!(
+            choose_tier(&[false, false], None, None, DELAY),
+            decision(0, None)
+        );
+    }
+
+    #[test]
+    fn failover_at_once() {
+        let just_now = Some(Duration::from_secs(1));
+
+        assert_eq!(
+            choose_tier(&[false, true, true], Some(0), just_now, DELAY),
+            decision(1, None)
+        );
+        assert_eq!(
+            choose_tier(&[false, false, true], Some(1), just_now, DELAY),
+            decision(2, None)
+        );
+
+        // no tier is healthy, an empty record set is worse
+        assert_eq!(
+            choose_tier(&[false, false], Some(1), just_now, DELAY),
+            decision(1, None)
+        );
+    }
+
+    #[test]
+    fn failback_after_delay() {
+        assert_eq!(
+            choose_tier(
+                &[true, true],
+                Some(1),
+                Some(Duration::from_secs(100)),
+                DELAY
+            ),
+            decision(1, Some(200))
+        );
+        assert_eq!(
+            choose_tier(&[true, true], Some(1), Some(DELAY), DELAY),
+            decision(0, None)
+        );
+        assert_eq!(
+            choose_tier(&[true, true], Some(1), None, DELAY),
+            decision(0, None)
+        );
+
+        // the active tier is already the best
+        assert_eq!(
+            choose_tier(&[false, true, true], Some(1), None, DELAY),
+            decision(1, None)
+        );
+    }
+
+    #[test]
+    fn selector_of_active_tier() {
+        let tier = |name: &str| FailoverTier {
+            name: name.to_string(),
+            match_labels: HashMap::from([("site".to_string(), name.to_string())]),
+        };
+
+        let mut spec = DdnsSpec {
+            selector: HashMap::from([("app".to_string(), "web".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(active_selector(&spec, None), &spec.selector);
+
+        spec.failover = Some(FailoverSpec {
+            tiers: vec![tier("a"), tier("b")],
+            failback_delay_seconds: 300,
+        });
+        let status = DdnsStatus {
+            active_tier: Some("b".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            active_selector(&spec, Some(&status)),
+            &tier("b").match_labels
+        );
+        assert_eq!(active_selector(&spec, None), &tier("a").match_labels);
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Semaphore;
+use tracing::{debug, instrument};
+
+use crate::ddns::Reconcile;
+use crate::spec::Ddns;
+
+/// Limit the number of the inner reconciles running at the same time, the others wait for a
+/// permit in FIFO order
+#[derive(Debug, Clone)]
+pub struct LimitedReconciler<R> {
+    inner_reconciler: R,
+    permits: Arc<Semaphore>,
+}
+
+impl<R> LimitedReconciler<R> {
+    pub fn new(reconciler: R, max_concurrent: usize) -> Self {
+        Self {
+            inner_reconciler: reconciler,
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+}
+
+#[async_trait]
+impl<R> Reconcile for LimitedReconciler<R>
+where
+    R: Reconcile + Send + Sync,
+{
+    type Error = R::Error;
+
+    #[instrument(skip(self, ddns))]
+    async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        debug!(
+            available = self.permits.available_permits(),
+            "wait reconcile permit"
+        );
+
+        // the semaphore is never closed
+        let _permit = self.permits.acquire().await.unwrap();
+
+        self.inner_reconciler.reconcile_ddns(ddns).await
+    }
+
+    #[instrument(skip(self, ddns))]
+    async fn delete_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        debug!(
+            available = self.permits.available_permits(),
+            "wait delete permit"
+        );
+
+        let _permit = self.permits.acquire().await.unwrap();
+
+        self.inner_reconciler.delete_ddns(ddns).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures_util::future;
+    use tokio::time;
+
+    use super::*;
+    use crate::ddns::Error;
+
+    #[derive(Default)]
+    struct CountReconciler {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Reconcile for CountReconciler {
+        type Error = Error;
+
+        async fn reconcile_ddns(&self, _ddns: Ddns) -> Result<(), Self::Error> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+
+            time::sleep(Duration::from_secs(1)).await;
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+
+        async fn delete_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+            self.reconcile_ddns(ddns).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_concurrent_reconciles() {
+        let inner = Arc::new(CountReconciler::default());
+        let reconciler = LimitedReconciler::new(inner.clone(), 2);
+
+        let results = future::join_all(
+            (0..5).map(|_| reconciler.reconcile_ddns(Ddns::new("test", Default::default()))),
+        )
+        .await;
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(inner.max_running.load(Ordering::SeqCst), 2);
+    }
+}
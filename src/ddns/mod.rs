@@ -1,14 +1,29 @@
+pub use addresses::parse_addresses;
+pub use conflict::{compare_claim, find_domain_owner};
 pub use controller::Controller;
-use error::Error;
+pub use default_reconciler::{service_lb_ips, FINALIZER};
+pub use desired::desired_addresses;
+pub use error::Error;
 pub use error_policy::ErrorPolicy;
 pub use queue_reconciler::QueueReconciler;
 pub use reconcile::Reconcile;
+pub use watch::{resync_watcher, RESYNC_PERIOD, WATCH_RETRY_DELAY};
 
+mod addresses;
+mod cache_reconciler;
+mod conflict;
 mod controller;
 mod default_err_policy;
 mod default_reconciler;
+mod desired;
+mod endpoints;
 mod error;
 mod error_policy;
+mod failover;
+mod limited_reconciler;
 mod queue_reconciler;
 mod reconcile;
+#[cfg(test)]
+mod simulation;
+mod srv;
 mod watch;
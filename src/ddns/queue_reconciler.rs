@@ -1,27 +1,45 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use async_trait::async_trait;
-use futures_channel::mpsc::Sender;
+use futures_channel::oneshot;
 use futures_channel::oneshot::Sender as OneshotSender;
-use futures_channel::{mpsc, oneshot};
-use futures_util::{SinkExt, StreamExt};
 use kube::runtime::reflector::ObjectRef;
-use tap::TapFallible;
-use tokio::sync::Mutex;
-use tracing::{error, info, instrument};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{self, Instant};
+use tracing::{debug, info, instrument};
 
 use crate::ddns::error::Error;
 use crate::ddns::Reconcile;
+use crate::metrics::Metrics;
 use crate::spec::Ddns;
 
-type OnlineDdnsList<E> =
-    Arc<Mutex<HashMap<ObjectRef<Ddns>, Sender<(Ddns, OneshotSender<Result<(), E>>)>>>>;
+type OnlineDdnsList<E> = Arc<Mutex<HashMap<ObjectRef<Ddns>, ObjectQueue<E>>>>;
 
+/// The queue of an object which has a handler task, it holds at most one pending update
+struct ObjectQueue<E> {
+    pending: Option<Pending<E>>,
+    notify: Arc<Notify>,
+}
+
+struct Pending<E> {
+    ddns: Ddns,
+    result_sender: OneshotSender<Result<(), E>>,
+    queued_at: Instant,
+}
+
+/// Reconcile the updates of the same object one by one.
+///
+/// When the object is being reconciled, the newer update replaces the pending one, so a burst of
+/// updates is reconciled only once with the newest object. The caller of the replaced update gets
+/// `Ok`, the newer update takes over its work.
 pub struct QueueReconciler<R, E> {
     online_ddns_list: OnlineDdnsList<E>,
     inner_reconciler: R,
+    metrics: Metrics,
+    idle_timeout: Duration,
 }
 
 impl<R: Clone, E> Clone for QueueReconciler<R, E> {
@@ -29,6 +47,8 @@ impl<R: Clone, E> Clone for QueueReconciler<R, E> {
         Self {
             online_ddns_list: self.online_ddns_list.clone(),
             inner_reconciler: self.inner_reconciler.clone(),
+            metrics: self.metrics.clone(),
+            idle_timeout: self.idle_timeout,
         }
     }
 }
@@ -37,10 +57,14 @@ impl<R, E> QueueReconciler<R, E>
 where
     R: Reconcile<Error = E>,
 {
-    pub fn new(reconciler: R) -> Self {
+    /// The handle task of an object exits when the object has no update in idle_timeout, and
+    /// it is recreated on the next update
+    pub fn new(reconciler: R, metrics: Metrics, idle_timeout: Duration) -> Self {
         Self {
             online_ddns_list: Arc::new(Default::default()),
             inner_reconciler: reconciler,
+            metrics,
+            idle_timeout,
         }
     }
 }
@@ -59,49 +83,56 @@ where
         info!(%obj_ref, "create object ref done");
 
         let (result_sender, result_receiver) = oneshot::channel();
+        let pending = Pending {
+            ddns,
+            result_sender,
+            queued_at: Instant::now(),
+        };
 
         let mut ddns_list = self.online_ddns_list.lock().await;
 
-        if let Some(mut sender) = ddns_list.get(&obj_ref).cloned() {
-            drop(ddns_list);
+        match ddns_list.get_mut(&obj_ref) {
+            Some(queue) => {
+                match queue.pending.replace(pending) {
+                    None => self.metrics.queue_add(),
 
-            sender
-                .send((ddns.clone(), result_sender))
-                .await
-                .tap_err(|err| {
-                    error!(?ddns, %err, "send ddns object reference to handler task failed");
-                })?;
-        } else {
-            // I think the 3 buffer is enough in normal
-            let (mut sender, mut receiver) = mpsc::channel(3);
-
-            sender.send((ddns, result_sender)).await.unwrap();
+                    Some(replaced) => {
+                        debug!(%obj_ref, "pending update is replaced by the newer one");
 
-            ddns_list.insert(obj_ref, sender);
-            drop(ddns_list);
+                        self.metrics.queue_coalesce();
 
-            let ddns_list = self.online_ddns_list.clone();
-            let reconciler = self.inner_reconciler.clone();
-
-            tokio::spawn(async move {
-                while let Some((ddns, result_sender)) = receiver.next().await {
-                    if ddns.metadata.deletion_timestamp.is_none() {
-                        let result = handle_change(&reconciler, ddns).await;
-
-                        let _ = result_sender.send(result);
-                    } else {
-                        ddns_list.lock().await.remove(&ObjectRef::from_obj(&ddns));
-
-                        let result = handle_delete(reconciler, ddns).await;
+                        let _ = replaced.result_sender.send(Ok(()));
+                    }
+                }
 
-                        let _ = result_sender.send(result);
+                queue.notify.notify_one();
+            }
 
-                        return;
-                    };
-                }
-            });
+            None => {
+                let notify = Arc::new(Notify::new());
+
+                ddns_list.insert(
+                    obj_ref.clone(),
+                    ObjectQueue {
+                        pending: Some(pending),
+                        notify: notify.clone(),
+                    },
+                );
+                self.metrics.queue_add();
+
+                tokio::spawn(handle_object(
+                    self.online_ddns_list.clone(),
+                    obj_ref,
+                    notify,
+                    self.inner_reconciler.clone(),
+                    self.metrics.clone(),
+                    self.idle_timeout,
+                ));
+            }
         }
 
+        drop(ddns_list);
+
         result_receiver
             .await
             .unwrap_or_else(|_| Err(Error::Other(anyhow!("ddns handle task is stopped"))))
@@ -113,13 +144,88 @@ where
     }
 }
 
+/// Handle the pending updates of the object until it is deleted or idle.
+///
+/// The entry is only removed with the lock held and no pending update, the callers queue the
+/// update with the lock held too, so an update is never left in a removed entry.
+async fn handle_object<R: Reconcile<Error = Error>>(
+    ddns_list: OnlineDdnsList<Error>,
+    obj_ref: ObjectRef<Ddns>,
+    notify: Arc<Notify>,
+    reconciler: R,
+    metrics: Metrics,
+    idle_timeout: Duration,
+) {
+    loop {
+        let pending = ddns_list
+            .lock()
+            .await
+            .get_mut(&obj_ref)
+            .and_then(|queue| queue.pending.take());
+
+        let pending = match pending {
+            None => {
+                if time::timeout(idle_timeout, notify.notified())
+                    .await
+                    .is_err()
+                {
+                    let mut ddns_list = ddns_list.lock().await;
+
+                    if ddns_list
+                        .get(&obj_ref)
+                        .is_none_or(|queue| queue.pending.is_none())
+                    {
+                        ddns_list.remove(&obj_ref);
+
+                        debug!(%obj_ref, ?idle_timeout, "ddns is idle, stop handle task");
+
+                        return;
+                    }
+                }
+
+                continue;
+            }
+
+            Some(pending) => pending,
+        };
+
+        metrics.queue_take(pending.queued_at.elapsed());
+
+        let ddns = pending.ddns;
+
+        if ddns.metadata.deletion_timestamp.is_none() {
+            let result = handle_change(&reconciler, ddns).await;
+
+            let _ = pending.result_sender.send(result);
+        } else {
+            let result = handle_delete(&reconciler, ddns).await;
+
+            let _ = pending.result_sender.send(result);
+
+            let mut ddns_list = ddns_list.lock().await;
+
+            // an update arrived while deleting, keep handling it
+            if ddns_list
+                .get(&obj_ref)
+                .is_none_or(|queue| queue.pending.is_none())
+            {
+                ddns_list.remove(&obj_ref);
+
+                info!(%obj_ref, "ddns is deleted, stop handle task");
+
+                return;
+            }
+        }
+    }
+}
+
 #[instrument(err, skip(reconciler))]
 async fn handle_change<R: Reconcile>(reconciler: &R, ddns: Ddns) -> Result<(), R::Error> {
     reconciler.reconcile_ddns(ddns).await
 }
 
 #[instrument(err, skip(reconciler))]
-async fn handle_delete<R: Reconcile>(reconciler: R, ddns: Ddns) -> Result<(), R::Error> {
+async fn handle_delete<R: Reconcile>(reconciler: &R, ddns: Ddns) -> Result<(), R::Error> {
     reconciler.delete_ddns(ddns).await
 }
 
@@ -151,14 +257,19 @@ mod tests {
                 Ok(())
             }
 
+            #[allow(clippy::diverging_sub_expression)]
             async fn delete_ddns(&self, _: Ddns) -> Result<(), Self::Error> {
                 unimplemented!()
             }
         }
 
-        let reconciler = QueueReconciler::new(TestReconciler {
-            n: Arc::new(Default::default()),
-        });
+        let reconciler = QueueReconciler::new(
+            TestReconciler {
+                n: Arc::new(Default::default()),
+            },
+            Metrics::default(),
+            Duration::from_secs(60),
+        );
 
         let n = reconciler.inner_reconciler.n.clone();
         let online_ddns_list = reconciler.online_ddns_list.clone();
@@ -176,16 +287,18 @@ mod tests {
             tokio::spawn(async move { reconciler.reconcile_ddns(ddns).await })
         };
 
+        // the concurrent updates may be coalesced, send the second after the first is done
+        task1.await.unwrap().unwrap();
+
         let task2 = { tokio::spawn(async move { reconciler.reconcile_ddns(ddns).await }) };
 
-        task1.await.unwrap().unwrap();
         task2.await.unwrap().unwrap();
 
         assert_eq!(n.load(Ordering::Acquire), 2);
         assert!(online_ddns_list.lock().await.get(&obj_ref).is_some());
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_delete() {
         #[derive(Clone)]
         struct TestReconciler {
@@ -209,9 +322,13 @@ mod tests {
             }
         }
 
-        let reconciler = QueueReconciler::new(TestReconciler {
-            n: Arc::new(Default::default()),
-        });
+        let reconciler = QueueReconciler::new(
+            TestReconciler {
+                n: Arc::new(Default::default()),
+            },
+            Metrics::default(),
+            Duration::from_secs(60),
+        );
 
         let n = reconciler.inner_reconciler.n.clone();
         let online_ddns_list = reconciler.online_ddns_list.clone();
@@ -248,4 +365,154 @@ mod tests {
         assert_eq!(n.load(Ordering::Acquire), 2);
         assert!(online_ddns_list.lock().await.get(&obj_ref).is_none());
     }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        #[derive(Clone)]
+        struct TestReconciler {
+            reconciled: Arc<std::sync::Mutex<Vec<String>>>,
+            started: Arc<Notify>,
+            release: Arc<Notify>,
+        }
+
+        #[async_trait]
+        impl Reconcile for TestReconciler {
+            type Error = Error;
+
+            async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+                self.started.notify_one();
+                self.release.notified().await;
+
+                self.reconciled.lock().unwrap().push(ddns.spec.domain);
+
+                Ok(())
+            }
+
+            async fn delete_ddns(&self, _: Ddns) -> Result<(), Self::Error> {
+                Ok(())
+            }
+        }
+
+        let test_reconciler = TestReconciler {
+            reconciled: Default::default(),
+            started: Arc::new(Notify::new()),
+            release: Arc::new(Notify::new()),
+        };
+        let metrics = Metrics::default();
+        let reconciler = QueueReconciler::new(
+            test_reconciler.clone(),
+            metrics.clone(),
+            Duration::from_secs(60),
+        );
+
+        let ddns = |domain: &str| {
+            let mut ddns = Ddns::default();
+            ddns.metadata.namespace.replace(String::from("123"));
+            ddns.metadata.name.replace(String::from("123"));
+            ddns.spec.domain = domain.to_string();
+
+            ddns
+        };
+
+        let first = {
+            let reconciler = reconciler.clone();
+            let ddns = ddns("first");
+
+            tokio::spawn(async move { reconciler.reconcile_ddns(ddns).await })
+        };
+
+        // the first update is being reconciled, the others are queued
+        test_reconciler.started.notified().await;
+
+        let second = {
+            let reconciler = reconciler.clone();
+            let ddns = ddns("second");
+
+            tokio::spawn(async move { reconciler.reconcile_ddns(ddns).await })
+        };
+
+        while metrics.queue_depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the third update replaces the second one, the second caller doesn't wait
+        let third = {
+            let reconciler = reconciler.clone();
+            let ddns = ddns("third");
+
+            tokio::spawn(async move { reconciler.reconcile_ddns(ddns).await })
+        };
+
+        second.await.unwrap().unwrap();
+        assert_eq!(metrics.queue_coalesced(), 1);
+        assert_eq!(metrics.queue_depth(), 1);
+
+        test_reconciler.release.notify_one();
+        first.await.unwrap().unwrap();
+
+        test_reconciler.started.notified().await;
+        test_reconciler.release.notify_one();
+        third.await.unwrap().unwrap();
+
+        assert_eq!(
+            *test_reconciler.reconciled.lock().unwrap(),
+            vec!["first".to_string(), "third".to_string()]
+        );
+        assert_eq!(metrics.queue_depth(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle() {
+        #[derive(Clone)]
+        struct TestReconciler {
+            n: Arc<AtomicU8>,
+        }
+
+        #[async_trait]
+        impl Reconcile for TestReconciler {
+            type Error = Error;
+
+            async fn reconcile_ddns(&self, _: Ddns) -> Result<(), Self::Error> {
+                self.n.fetch_add(1, Ordering::AcqRel);
+
+                Ok(())
+            }
+
+            async fn delete_ddns(&self, _: Ddns) -> Result<(), Self::Error> {
+                Ok(())
+            }
+        }
+
+        let idle_timeout = Duration::from_secs(60);
+        let reconciler = QueueReconciler::new(
+            TestReconciler {
+                n: Arc::new(Default::default()),
+            },
+            Metrics::default(),
+            idle_timeout,
+        );
+
+        let n = reconciler.inner_reconciler.n.clone();
+        let online_ddns_list = reconciler.online_ddns_list.clone();
+
+        let mut ddns = Ddns::default();
+        ddns.metadata.namespace.replace(String::from("123"));
+        ddns.metadata.name.replace(String::from("123"));
+
+        let obj_ref = ObjectRef::from_obj(&ddns);
+
+        reconciler.reconcile_ddns(ddns.clone()).await.unwrap();
+
+        time::sleep(idle_timeout / 2).await;
+        assert!(online_ddns_list.lock().await.get(&obj_ref).is_some());
+
+        time::sleep(idle_timeout).await;
+        assert!(online_ddns_list.lock().await.get(&obj_ref).is_none());
+
+        // the handle task is recreated on demand
+        reconciler.reconcile_ddns(ddns).await.unwrap();
+
+        assert_eq!(n.load(Ordering::Acquire), 2);
+        assert!(online_ddns_list.lock().await.get(&obj_ref).is_some());
+    }
 }
@@ -0,0 +1,353 @@
+//! Randomized simulation of the reconcile pipeline on the paused tokio clock.
+//!
+//! The watch events, the stale snapshots from the service trigger, the reconcile durations and
+//! the failures are generated by a seeded random generator, then the whole pipeline of the
+//! controller runs with a recording reconciler in place of the [`DefaultReconciler`]. A failed
+//! seed can be replayed by running [`simulate`] with it.
+//!
+//! [`DefaultReconciler`]: crate::ddns::default_reconciler::DefaultReconciler
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures_channel::mpsc;
+use futures_util::StreamExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::runtime::reflector::store::Writer;
+use kube::runtime::reflector::ObjectRef;
+use kube::runtime::watcher::Event;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::ddns::cache_reconciler::CacheReconciler;
+use crate::ddns::controller::Controller;
+use crate::ddns::default_err_policy::DefaultErrPolicy;
+use crate::ddns::limited_reconciler::LimitedReconciler;
+use crate::ddns::{Error, ErrorPolicy, QueueReconciler, Reconcile};
+use crate::metrics::Metrics;
+use crate::shutdown::task_tracker;
+use crate::spec::Ddns;
+
+const SEEDS: u64 = 2000;
+const OBJECTS: usize = 4;
+const EVENTS: usize = 40;
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+/// Long enough for all the retries to be done
+const SETTLE_TIME: Duration = Duration::from_secs(3600);
+
+/// The xorshift64* generator, good enough to shuffle the events and stable across platforms
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must not be zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn millis(&mut self, max: u64) -> Duration {
+        Duration::from_millis(self.below(max + 1))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    /// The objects being reconciled or deleted
+    in_flight: HashSet<ObjectRef<Ddns>>,
+    /// The versions passed to the inner reconciler, in order
+    attempts: HashMap<ObjectRef<Ddns>, Vec<u64>>,
+    /// The latest successfully reconciled version
+    reconciled: HashMap<ObjectRef<Ddns>, u64>,
+    deleted: HashSet<ObjectRef<Ddns>>,
+    violations: Vec<String>,
+}
+
+/// The recording reconciler, it fails randomly and removes the object from the cache after
+/// a successful delete, like the api server removes the object once the finalizer is removed
+#[derive(Clone)]
+struct SimReconciler {
+    rng: Arc<Mutex<Rng>>,
+    record: Arc<Mutex<Record>>,
+    store: Arc<Mutex<Writer<Ddns>>>,
+}
+
+impl SimReconciler {
+    async fn run(&self, ddns: Ddns, delete: bool) -> Result<(), Error> {
+        let obj_ref = ObjectRef::from_obj(&ddns);
+        let version = version(&ddns);
+
+        {
+            let mut record = self.record.lock().unwrap();
+
+            if !record.in_flight.insert(obj_ref.clone()) {
+                record
+                    .violations
+                    .push(format!("{} is reconciled concurrently", obj_ref));
+            }
+
+            if record.deleted.contains(&obj_ref) {
+                record
+                    .violations
+                    .push(format!("{} is reconciled after deleted", obj_ref));
+            }
+
+            let attempts = record.attempts.entry(obj_ref.clone()).or_default();
+            let out_of_order = attempts
+                .last()
+                .is_some_and(|last| *last > version)
+                .then(|| {
+                    format!(
+                        "{} version {} is reconciled after {:?}",
+                        obj_ref, version, attempts
+                    )
+                });
+            attempts.push(version);
+
+            record.violations.extend(out_of_order);
+        }
+
+        let (duration, result) = {
+            let mut rng = self.rng.lock().unwrap();
+
+            let duration = rng.millis(200);
+            let result = match rng.below(10) {
+                0 => Err(Error::Other(anyhow::anyhow!("injected failure"))),
+                1 => Err(Error::ReRun(rng.millis(10_000))),
+                _ => Ok(()),
+            };
+
+            (duration, result)
+        };
+
+        time::sleep(duration).await;
+
+        let mut record = self.record.lock().unwrap();
+        record.in_flight.remove(&obj_ref);
+
+        if result.is_ok() {
+            if delete {
+                record.deleted.insert(obj_ref);
+
+                self.store
+                    .lock()
+                    .unwrap()
+                    .apply_watcher_event(&Event::Deleted(ddns));
+            } else {
+                record.reconciled.insert(obj_ref, version);
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl Reconcile for SimReconciler {
+    type Error = Error;
+
+    async fn reconcile_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        self.run(ddns, false).await
+    }
+
+    async fn delete_ddns(&self, ddns: Ddns) -> Result<(), Self::Error> {
+        self.run(ddns, true).await
+    }
+}
+
+/// The version is kept in the resource version, it is increased by every change
+fn version(ddns: &Ddns) -> u64 {
+    ddns.metadata
+        .resource_version
+        .as_deref()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+fn new_ddns(index: usize) -> Ddns {
+    let mut ddns = Ddns::default();
+    ddns.metadata.namespace = Some("default".to_string());
+    ddns.metadata.name = Some(format!("ddns-{}", index));
+    ddns.metadata.resource_version = Some("1".to_string());
+
+    ddns
+}
+
+/// Run the simulation of the seed, return the violations of the invariants
+async fn simulate(seed: u64) -> Vec<String> {
+    let rng = Arc::new(Mutex::new(Rng::new(seed)));
+    let record = Arc::new(Mutex::new(Record::default()));
+    let store = Arc::new(Mutex::new(Writer::<Ddns>::default()));
+
+    let max_concurrent = 1 + rng.lock().unwrap().below(3) as usize;
+    let reader = store.lock().unwrap().as_reader();
+    let reconciler = QueueReconciler::new(
+        LimitedReconciler::new(
+            CacheReconciler::new(
+                SimReconciler {
+                    rng: rng.clone(),
+                    record: record.clone(),
+                    store: store.clone(),
+                },
+                reader,
+            ),
+            max_concurrent,
+        ),
+        Metrics::default(),
+        Duration::from_secs(60),
+    );
+
+    let (retry_sender, retry_receiver) = mpsc::unbounded();
+    let err_policy = DefaultErrPolicy::new(retry_sender, RETRY_DELAY);
+
+    let (watch_sender, watch_receiver) = mpsc::unbounded();
+    let shutdown = CancellationToken::new();
+    let (tracker, drain) = task_tracker();
+
+    let controller = tokio::spawn({
+        let reconciler = reconciler.clone();
+        let err_policy = err_policy.clone();
+        let shutdown = shutdown.clone();
+
+        async move {
+            Controller::reconcile_ddns_stream(
+                watch_receiver.map(Ok),
+                reconciler,
+                err_policy,
+                retry_receiver,
+                &shutdown,
+                tracker,
+            )
+            .await
+        }
+    });
+
+    let mut objects = (0..OBJECTS).map(new_ddns).collect::<Vec<_>>();
+    for ddns in &objects {
+        store
+            .lock()
+            .unwrap()
+            .apply_watcher_event(&Event::Applied(ddns.clone()));
+        watch_sender.unbounded_send(ddns.clone()).unwrap();
+    }
+
+    // the snapshots sent before, the service trigger may send any of them again
+    let mut snapshots = objects.clone();
+
+    for _ in 0..EVENTS {
+        let (index, action, delay) = {
+            let mut rng = rng.lock().unwrap();
+
+            (
+                rng.below(OBJECTS as u64) as usize,
+                rng.below(10),
+                rng.millis(300),
+            )
+        };
+
+        let ddns = &mut objects[index];
+
+        match action {
+            // the deleting object is not changed anymore
+            _ if ddns.metadata.deletion_timestamp.is_some() => continue,
+
+            0 => {
+                ddns.metadata.deletion_timestamp = Some(Time(DateTime::from(SystemTime::now())));
+            }
+
+            1..=2 => {
+                let snapshot = {
+                    let mut rng = rng.lock().unwrap();
+
+                    snapshots[rng.below(snapshots.len() as u64) as usize].clone()
+                };
+
+                // like the service trigger, the reconcile is not awaited
+                let reconciler = reconciler.clone();
+                let err_policy = err_policy.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = reconciler.reconcile_ddns(snapshot.clone()).await {
+                        err_policy.error_policy(snapshot, err).await;
+                    }
+                });
+
+                time::sleep(delay).await;
+
+                continue;
+            }
+
+            _ => ddns.spec.domain = format!("{}.example.com", version(ddns) + 1),
+        }
+
+        ddns.metadata.resource_version = Some((version(ddns) + 1).to_string());
+
+        store
+            .lock()
+            .unwrap()
+            .apply_watcher_event(&Event::Applied(ddns.clone()));
+        watch_sender.unbounded_send(ddns.clone()).unwrap();
+        snapshots.push(ddns.clone());
+
+        time::sleep(delay).await;
+    }
+
+    time::sleep(SETTLE_TIME).await;
+
+    shutdown.cancel();
+    controller.await.unwrap().unwrap();
+    assert!(drain.drain(Duration::from_secs(60)).await);
+
+    let mut record = record.lock().unwrap();
+    let mut violations = std::mem::take(&mut record.violations);
+
+    if !record.in_flight.is_empty() {
+        violations.push(format!("{:?} are still in flight", record.in_flight));
+    }
+
+    for ddns in &objects {
+        let obj_ref = ObjectRef::from_obj(ddns);
+
+        if ddns.metadata.deletion_timestamp.is_some() {
+            if !record.deleted.contains(&obj_ref) {
+                violations.push(format!("delete of {} is lost", obj_ref));
+            }
+        } else if record.reconciled.get(&obj_ref) != Some(&version(ddns)) {
+            violations.push(format!(
+                "{} version {} is not reconciled, attempts {:?}",
+                obj_ref,
+                version(ddns),
+                record.attempts.get(&obj_ref)
+            ));
+        }
+    }
+
+    violations
+}
+
+#[tokio::test(start_paused = true)]
+async fn random_interleavings() {
+    for seed in 0..SEEDS {
+        let violations = simulate(seed).await;
+
+        assert!(
+            violations.is_empty(),
+            "seed {} violates invariants: {:#?}",
+            seed,
+            violations
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::Service;
+use tracing::warn;
+
+use crate::cf_dns::SrvRecord;
+use crate::ddns::default_reconciler::has_lb_ip;
+use crate::spec::SrvSpec;
+
+/// The SRV records of the selected named ports, only the services which have a load balancer IP
+/// are reachable by the domain. When several services have the port with the same name and
+/// protocol, the first one is published.
+pub fn srv_records(services: &[Service], domain: &str, srv: &SrvSpec) -> Vec<SrvRecord> {
+    let mut srv_records = BTreeMap::new();
+
+    let ports = services
+        .iter()
+        .filter(|svc| has_lb_ip(svc))
+        .filter_map(|svc| svc.spec.as_ref())
+        .filter_map(|spec| spec.ports.as_ref())
+        .flatten();
+
+    for port in ports {
+        let service = match &port.name {
+            Some(name) if srv.ports.contains(name) => name,
+            _ => continue,
+        };
+
+        let port_number = match u16::try_from(port.port) {
+            Err(_) => {
+                warn!(?port, "service port is out of range");
+
+                continue;
+            }
+
+            Ok(port_number) => port_number,
+        };
+
+        let protocol = port
+            .protocol
+            .as_deref()
+            .unwrap_or("TCP")
+            .to_ascii_lowercase();
+
+        srv_records
+            .entry((service.clone(), protocol.clone()))
+            .or_insert_with(|| SrvRecord {
+                service: service.clone(),
+                protocol,
+                port: port_number,
+                target: domain.to_string(),
+            });
+    }
+
+    srv_records.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{
+        LoadBalancerIngress, LoadBalancerStatus, ServicePort, ServiceSpec, ServiceStatus,
+    };
+
+    use super::*;
+
+    fn service(ports: &[(Option<&str>, Option<&str>, i32)], lb_ip: bool) -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                ports: Some(
+                    ports
+                        .iter()
+                        .map(|(name, protocol, port)| ServicePort {
+                            name: name.map(str::to_string),
+                            protocol: protocol.map(str::to_string),
+                            port: *port,
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            status: lb_ip.then(|| ServiceStatus {
+                load_balancer: Some(LoadBalancerStatus {
+                    ingress: Some(vec![LoadBalancerIngress {
+                        ip: Some("1.1.1.1".to_string()),
+                        ..Default::default()
+                    }]),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn srv_record(service: &str, protocol: &str, port: u16) -> SrvRecord {
+        SrvRecord {
+            service: service.to_string(),
+            protocol: protocol.to_string(),
+            port,
+            target: "sip.example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn selected_named_ports() {
+        let services = [
+            service(
+                &[
+                    (Some("sip"), Some("UDP"), 5060),
+                    (Some("sip"), Some("TCP"), 5060),
+                    (Some("sips"), None, 5061),
+                    (Some("metrics"), Some("TCP"), 9090),
+                    (None, Some("TCP"), 80),
+                ],
+                true,
+            ),
+            // the same port name of another service is ignored
+            service(&[(Some("sip"), Some("UDP"), 15060)], true),
+        ];
+        let srv = SrvSpec {
+            ports: vec!["sip".to_string(), "sips".to_string()],
+        };
+
+        assert_eq!(
+            srv_records(&services, "sip.example.com", &srv),
+            [
+                srv_record("sip", "tcp", 5060),
+                srv_record("sip", "udp", 5060),
+                srv_record("sips", "tcp", 5061),
+            ]
+        );
+
+        assert_eq!(
+            srv_records(&services[0..1], "sip.example.com", &SrvSpec::default()),
+            []
+        );
+    }
+
+    #[test]
+    fn skip_service_without_lb_ip() {
+        let services = [
+            service(&[(Some("sip"), Some("UDP"), 15060)], false),
+            service(&[(Some("sip"), Some("UDP"), 5060)], true),
+        ];
+        let srv = SrvSpec {
+            ports: vec!["sip".to_string()],
+        };
+
+        assert_eq!(
+            srv_records(&services, "sip.example.com", &srv),
+            [srv_record("sip", "udp", 5060)]
+        );
+    }
+}
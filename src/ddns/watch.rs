@@ -1,12 +1,63 @@
-use futures_util::Stream;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use futures_util::{stream, Stream, StreamExt};
 use kube::api::ListParams;
+use kube::runtime::reflector::store::Writer;
 use kube::runtime::utils::try_flatten_applied;
-use kube::runtime::watcher;
-use kube::runtime::watcher::Error;
-use kube::Api;
+use kube::runtime::watcher::{Error, Event};
+use kube::runtime::{reflector, watcher};
+use kube::{Api, Resource};
+use serde::de::DeserializeOwned;
+use tokio::time;
 
+use crate::health::Health;
 use crate::spec::Ddns;
 
-pub fn watch_ddns(api: Api<Ddns>) -> impl Stream<Item = Result<Ddns, Error>> {
-    try_flatten_applied(watcher(api, ListParams::default()))
+/// The delay before polling the watch stream again after it returns an error
+pub const WATCH_RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// The period the watches re-list the resources, so a watch which stops getting events silently
+/// is noticed, and the Ddns are all reconciled again
+pub const RESYNC_PERIOD: Duration = Duration::from_secs(15 * 60);
+
+/// Watch the resources like [`watcher`], but start a new watch with a list every
+/// [`RESYNC_PERIOD`], the list is returned as the [`Event::Restarted`] event
+pub fn resync_watcher<K>(
+    api: Api<K>,
+    list_params: ListParams,
+) -> impl Stream<Item = Result<Event<K>, Error>> + Send
+where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + 'static,
+{
+    stream::repeat(()).flat_map(move |_| {
+        watcher(api.clone(), list_params.clone()).take_until(time::sleep(RESYNC_PERIOD))
+    })
+}
+
+/// Watch the Ddns, the store is updated before the Ddns is returned by the stream
+pub fn watch_ddns(
+    api: Api<Ddns>,
+    store: Writer<Ddns>,
+    health: Health,
+) -> impl Stream<Item = Result<Ddns, Error>> {
+    let ddns_health = health.register("ddns watch");
+
+    let ddns_stream =
+        reflector(store, resync_watcher(api, ListParams::default())).inspect(move |event| {
+            match event {
+                Err(_) => ddns_health.failed(),
+
+                Ok(event) => {
+                    ddns_health.ok();
+
+                    // the first restarted event contains the result of the initial list
+                    if let Event::Restarted(_) = event {
+                        health.set_ddns_synced();
+                    }
+                }
+            }
+        });
+
+    try_flatten_applied(ddns_stream)
 }
@@ -0,0 +1,560 @@
+//! An in-process stand-in of the cloudflare API for the tests.
+//!
+//! It implements the zones, dns_records and credentials verify endpoints with in-memory state,
+//! the responses have the same shape as the real API so [`CfDns`](crate::cf_dns::CfDns) can be
+//! pointed at it by the API base URL.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use url::Url;
+
+const API_PATH: &str = "/client/v4/";
+const DEFAULT_PER_PAGE: usize = 20;
+const TIMESTAMP: &str = "2022-01-01T00:00:00Z";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    pub id: String,
+    pub zone_id: String,
+    pub name: String,
+    pub kind: String,
+    pub content: String,
+    pub ttl: u32,
+    pub proxied: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// zone id to zone name
+    zones: HashMap<String, String>,
+    /// zone id to the only token which can access it, the other zones are accessible by all
+    zone_tokens: HashMap<String, String>,
+    records: Vec<Record>,
+    next_id: u64,
+    /// The page size is capped to it, so the pagination can be tested with a few records
+    max_per_page: Option<usize>,
+    /// The next requests fail with the status
+    failures: Vec<StatusCode>,
+    rate_limit: Option<RateLimit>,
+    requests: usize,
+}
+
+#[derive(Debug)]
+struct RateLimit {
+    limit: usize,
+    period: Duration,
+    window_start: Instant,
+    count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateRecord {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    #[serde(default)]
+    content: Option<String>,
+    /// The SRV records are created with the data instead of the content
+    #[serde(default)]
+    data: Option<SrvData>,
+    #[serde(default)]
+    ttl: Option<u32>,
+    #[serde(default)]
+    proxied: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SrvData {
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// The fake API server, it stops when dropped
+pub struct FakeCloudflare {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl Drop for FakeCloudflare {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl FakeCloudflare {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone()))) }
+        });
+
+        let server = Server::from_tcp(listener).unwrap().serve(make_service);
+        let server = tokio::spawn(async move {
+            let _ = server.await;
+        });
+
+        Self {
+            addr,
+            state,
+            server,
+        }
+    }
+
+    /// The API base URL for [`CfDns::new_with_api_url`](crate::cf_dns::CfDns::new_with_api_url)
+    pub fn api_url(&self) -> Url {
+        Url::parse(&format!("http://{}{}", self.addr, API_PATH)).unwrap()
+    }
+
+    /// Add a zone and return its id
+    pub fn add_zone(&self, name: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+
+        let id = state.next_id();
+        state.zones.insert(id.clone(), name.to_string());
+
+        id
+    }
+
+    /// Add a zone which is only accessible by the token, like a zone of another account
+    pub fn add_account_zone(&self, name: &str, token: &str) -> String {
+        let id = self.add_zone(name);
+
+        self.state
+            .lock()
+            .unwrap()
+            .zone_tokens
+            .insert(id.clone(), token.to_string());
+
+        id
+    }
+
+    /// Add an A or AAAA record to the zone
+    pub fn add_record(&self, zone: &str, name: &str, ip: IpAddr) {
+        self.push_record(zone, name, record_kind(ip), &ip.to_string());
+    }
+
+    /// Add a TXT record to the zone
+    pub fn add_txt_record(&self, zone: &str, name: &str, content: &str) {
+        self.push_record(zone, name, "TXT", content);
+    }
+
+    fn push_record(&self, zone: &str, name: &str, kind: &str, content: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        let zone_id = state.zone_id(zone).expect("zone is not added");
+        let id = state.next_id();
+
+        state.records.push(Record {
+            id,
+            zone_id,
+            name: name.to_string(),
+            kind: kind.to_string(),
+            content: content.to_string(),
+            ttl: 1,
+            proxied: false,
+        });
+    }
+
+    /// The records with the name in the zone
+    pub fn records(&self, zone: &str, name: &str) -> Vec<Record> {
+        let state = self.state.lock().unwrap();
+
+        let zone_id = state.zone_id(zone);
+
+        state
+            .records
+            .iter()
+            .filter(|record| Some(&record.zone_id) == zone_id.as_ref() && record.name == name)
+            .cloned()
+            .collect()
+    }
+
+    /// The IPs of the A and AAAA records with the name in the zone
+    pub fn ips(&self, zone: &str, name: &str) -> Vec<IpAddr> {
+        let mut ips = self
+            .records(zone, name)
+            .into_iter()
+            .filter_map(|record| record.content.parse().ok())
+            .collect::<Vec<_>>();
+        ips.sort();
+
+        ips
+    }
+
+    pub fn set_max_per_page(&self, max_per_page: usize) {
+        self.state.lock().unwrap().max_per_page = Some(max_per_page);
+    }
+
+    /// Make the next `count` requests fail with the status
+    pub fn fail_next(&self, count: usize, status: StatusCode) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .extend(std::iter::repeat_n(status, count));
+    }
+
+    /// Reject the requests over `limit` in every `period` with 429, like the cloudflare global
+    /// rate limit
+    pub fn set_rate_limit(&self, limit: usize, period: Duration) {
+        self.state.lock().unwrap().rate_limit = Some(RateLimit {
+            limit,
+            period,
+            window_start: Instant::now(),
+            count: 0,
+        });
+    }
+
+    /// The number of the received requests, including the failed ones
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+}
+
+impl State {
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+
+        format!("{:032x}", self.next_id)
+    }
+
+    fn zone_id(&self, name: &str) -> Option<String> {
+        self.zones
+            .iter()
+            .find_map(|(id, zone)| (zone == name).then(|| id.clone()))
+    }
+
+    fn can_access(&self, zone_id: &str, token: Option<&str>) -> bool {
+        self.zones.contains_key(zone_id)
+            && self
+                .zone_tokens
+                .get(zone_id)
+                .is_none_or(|zone_token| Some(zone_token.as_str()) == token)
+    }
+
+    fn zone_json(&self, id: &str) -> Value {
+        json!({
+            "id": id,
+            "name": self.zones[id],
+            "account": {"id": "account", "name": "account"},
+            "created_on": TIMESTAMP,
+            "modified_on": TIMESTAMP,
+            "development_mode": 0,
+            "meta": {
+                "custom_certificate_quota": 0,
+                "page_rule_quota": 3,
+                "phishing_detected": false,
+                "multiple_railguns_allowed": false
+            },
+            "name_servers": [],
+            "owner": {"type": "user", "id": "user", "email": "user@example.com"},
+            "paused": false,
+            "permissions": [],
+            "status": "active",
+            "type": "full"
+        })
+    }
+
+    fn record_json(&self, record: &Record) -> Value {
+        json!({
+            "id": record.id,
+            "zone_id": record.zone_id,
+            "zone_name": self.zones[&record.zone_id],
+            "name": record.name,
+            "type": record.kind,
+            "content": record.content,
+            "ttl": record.ttl,
+            "proxied": record.proxied,
+            "proxiable": true,
+            "locked": false,
+            "meta": {"auto_added": false},
+            "created_on": TIMESTAMP,
+            "modified_on": TIMESTAMP
+        })
+    }
+
+    /// Return the status if the request is rejected by the rate limit or the injected failure
+    fn reject(&mut self) -> Option<StatusCode> {
+        if let Some(rate_limit) = &mut self.rate_limit {
+            if rate_limit.window_start.elapsed() >= rate_limit.period {
+                rate_limit.window_start = Instant::now();
+                rate_limit.count = 0;
+            }
+
+            rate_limit.count += 1;
+            if rate_limit.count > rate_limit.limit {
+                return Some(StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
+
+        if self.failures.is_empty() {
+            None
+        } else {
+            Some(self.failures.remove(0))
+        }
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<Mutex<State>>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req
+        .uri()
+        .query()
+        .map(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let authorized = req.headers().contains_key(http::header::AUTHORIZATION)
+        || req.headers().contains_key("X-Auth-Key");
+    let token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Err(err) => return Ok(error(StatusCode::BAD_REQUEST, 1001, &err.to_string())),
+        Ok(body) => body,
+    };
+
+    let mut state = state.lock().unwrap();
+    state.requests += 1;
+
+    if !authorized {
+        return Ok(error(StatusCode::FORBIDDEN, 10000, "Authentication error"));
+    }
+
+    if let Some(status) = state.reject() {
+        return Ok(match status {
+            StatusCode::TOO_MANY_REQUESTS => error(
+                status,
+                971,
+                "Please wait and consider throttling your request speed",
+            ),
+            status => error(status, 1000, "injected failure"),
+        });
+    }
+
+    let segments = match path.strip_prefix(API_PATH) {
+        None => return Ok(error(StatusCode::NOT_FOUND, 7000, "No route for that URI")),
+        Some(path) => path.split('/').collect::<Vec<_>>(),
+    };
+
+    let page = query
+        .get("page")
+        .and_then(|page| page.parse().ok())
+        .unwrap_or(1usize)
+        .max(1);
+    let per_page = query
+        .get("per_page")
+        .and_then(|per_page| per_page.parse().ok())
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .min(state.max_per_page.unwrap_or(usize::MAX))
+        .max(1);
+
+    let resp = match (&method, segments.as_slice()) {
+        (&Method::GET, ["user", "tokens", "verify"]) => {
+            success(json!({"id": "token", "status": "active"}))
+        }
+
+        (&Method::GET, ["user"]) => success(json!({
+            "id": "user",
+            "email": "user@example.com",
+            "username": "user",
+            "organizations": [],
+            "created_on": TIMESTAMP,
+            "modified_on": TIMESTAMP,
+            "two_factor_authentication_enabled": false,
+            "suspended": false
+        })),
+
+        (&Method::GET, ["zones"]) => {
+            let mut zones = state
+                .zones
+                .iter()
+                .filter(|(id, _)| state.can_access(id, token.as_deref()))
+                .filter(|(_, zone)| query.get("name").is_none_or(|name| name == *zone))
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            zones.sort();
+
+            let zones = zones
+                .iter()
+                .map(|id| state.zone_json(id))
+                .collect::<Vec<_>>();
+
+            paginate(zones, page, per_page)
+        }
+
+        (&Method::GET, ["zones", zone_id, "dns_records"])
+            if state.can_access(zone_id, token.as_deref()) =>
+        {
+            let records = state
+                .records
+                .iter()
+                .filter(|record| record.zone_id == *zone_id)
+                .filter(|record| query.get("name").is_none_or(|name| *name == record.name))
+                .filter(|record| query.get("type").is_none_or(|kind| *kind == record.kind))
+                .map(|record| state.record_json(record))
+                .collect::<Vec<_>>();
+
+            paginate(records, page, per_page)
+        }
+
+        (&Method::POST, ["zones", zone_id, "dns_records"])
+            if state.can_access(zone_id, token.as_deref()) =>
+        {
+            match serde_json::from_slice::<CreateRecord>(&body) {
+                Err(err) => error(StatusCode::BAD_REQUEST, 1004, &err.to_string()),
+
+                Ok(CreateRecord {
+                    content: None,
+                    data: None,
+                    ..
+                }) => error(StatusCode::BAD_REQUEST, 9005, "Content is required"),
+
+                Ok(create) => {
+                    // the content of the SRV record is "{weight} {port} {target}"
+                    let content = create.content.unwrap_or_else(|| {
+                        let data = create.data.unwrap();
+
+                        format!("{} {} {}", data.weight, data.port, data.target)
+                    });
+
+                    let id = state.next_id();
+                    let record = Record {
+                        id,
+                        zone_id: zone_id.to_string(),
+                        name: create.name,
+                        kind: create.kind,
+                        content,
+                        ttl: create.ttl.unwrap_or(1),
+                        proxied: create.proxied.unwrap_or(false),
+                    };
+                    let resp = success(state.record_json(&record));
+
+                    state.records.push(record);
+
+                    resp
+                }
+            }
+        }
+
+        (&Method::DELETE, ["zones", zone_id, "dns_records", id])
+            if state.can_access(zone_id, token.as_deref()) =>
+        {
+            match state
+                .records
+                .iter()
+                .position(|record| record.zone_id == *zone_id && record.id == *id)
+            {
+                None => error(StatusCode::NOT_FOUND, 81044, "Record does not exist."),
+
+                Some(index) => {
+                    state.records.remove(index);
+
+                    success(json!({ "id": id }))
+                }
+            }
+        }
+
+        (_, ["zones", _, ..]) => error(StatusCode::NOT_FOUND, 7003, "Could not route to zone"),
+
+        _ => error(StatusCode::NOT_FOUND, 7000, "No route for that URI"),
+    };
+
+    Ok(resp)
+}
+
+fn record_kind(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+fn paginate(items: Vec<Value>, page: usize, per_page: usize) -> Response<Body> {
+    let total_count = items.len();
+    let total_pages = total_count.div_ceil(per_page);
+
+    let items = items
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .collect::<Vec<_>>();
+
+    json_response(
+        StatusCode::OK,
+        json!({
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result_info": {
+                "page": page,
+                "per_page": per_page,
+                "count": items.len(),
+                "total_count": total_count,
+                "total_pages": total_pages
+            },
+            "result": items
+        }),
+    )
+}
+
+fn success(result: Value) -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        json!({
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result": result
+        }),
+    )
+}
+
+fn error(status: StatusCode, code: u32, message: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({
+            "success": false,
+            "errors": [{"code": code, "message": message}],
+            "messages": [],
+            "result": null
+        }),
+    )
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,678 @@
+//! An in-memory stand-in of the kubernetes API server for the tests.
+//!
+//! The [`kube::Client`] returned by [`FakeKube::client`] sends the requests to it directly, it
+//! supports get, list, watch and merge patch of the main resource and the status subresource,
+//! which are all the controller needs. The tests change the objects by the [`FakeKube`] methods,
+//! the changes are sent to the watchers like the real API server.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use futures_util::stream;
+use http::{Method, Request, Response, StatusCode};
+use hyper::body::Bytes;
+use hyper::service::service_fn;
+use hyper::Body;
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::{Client, Resource};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+const MERGE_PATCH: &str = "application/merge-patch+json";
+const TIMESTAMP: &str = "2022-01-01T00:00:00Z";
+
+/// The objects of a resource, keyed by the namespace and the name
+type Objects = BTreeMap<(String, String), Value>;
+
+#[derive(Debug)]
+struct WatchEvent {
+    resource_version: u64,
+    resource: String,
+    namespace: String,
+    type_: &'static str,
+    object: Value,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// `{apiVersion}/{plural}` to the objects
+    resources: HashMap<String, Objects>,
+    resource_version: u64,
+    /// All the changes, so a watch can start from any resource version
+    events: Vec<WatchEvent>,
+    /// The number of the next lists of the resource which fail
+    failing_lists: HashMap<String, usize>,
+}
+
+/// The fake API server, it is shared by the clones
+#[derive(Clone)]
+pub struct FakeKube {
+    state: Arc<Mutex<State>>,
+    /// Notify the watchers the latest resource version
+    version_sender: Arc<watch::Sender<u64>>,
+}
+
+impl Default for FakeKube {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeKube {
+    pub fn new() -> Self {
+        let (version_sender, _) = watch::channel(0);
+
+        Self {
+            state: Default::default(),
+            version_sender: Arc::new(version_sender),
+        }
+    }
+
+    /// The client which sends the requests to this fake API server
+    pub fn client(&self) -> Client {
+        let fake_kube = self.clone();
+
+        let service = service_fn(move |req| {
+            let fake_kube = fake_kube.clone();
+
+            async move { Ok::<_, Infallible>(fake_kube.handle(req).await) }
+        });
+
+        Client::new(service, "default")
+    }
+
+    /// Create the object, the resource version and the generation are set by the server
+    pub fn create<K>(&self, obj: &K)
+    where
+        K: Resource<DynamicType = ()> + Serialize,
+    {
+        let resource = resource_key::<K>();
+        let mut obj = serde_json::to_value(obj).unwrap();
+        let key = object_key(&obj);
+
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            !state
+                .resources
+                .get(&resource)
+                .is_some_and(|objects| objects.contains_key(&key)),
+            "{} {:?} already exists",
+            resource,
+            key
+        );
+
+        let metadata = &mut obj["metadata"];
+        metadata["uid"] = json!(format!("{}-{}-{}", resource, key.0, key.1));
+        metadata["generation"] = json!(1);
+        metadata["creationTimestamp"] = json!(TIMESTAMP);
+
+        self.store(&mut state, resource, key, "ADDED", obj);
+    }
+
+    /// Get the object
+    pub fn get<K>(&self, namespace: &str, name: &str) -> Option<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let state = self.state.lock().unwrap();
+
+        state
+            .resources
+            .get(&resource_key::<K>())
+            .and_then(|objects| objects.get(&(namespace.to_string(), name.to_string())))
+            .map(|obj| serde_json::from_value(obj.clone()).unwrap())
+    }
+
+    /// Change the object by `f`, the generation is increased when the spec is changed
+    pub fn modify<K>(&self, namespace: &str, name: &str, f: impl FnOnce(&mut K))
+    where
+        K: Resource<DynamicType = ()> + Serialize + DeserializeOwned,
+    {
+        let resource = resource_key::<K>();
+        let key = (namespace.to_string(), name.to_string());
+
+        let mut state = self.state.lock().unwrap();
+        let old = state
+            .resources
+            .get(&resource)
+            .and_then(|objects| objects.get(&key))
+            .unwrap_or_else(|| panic!("{} {:?} doesn't exist", resource, key))
+            .clone();
+
+        let mut obj: K = serde_json::from_value(old.clone()).unwrap();
+        f(&mut obj);
+        let obj = serde_json::to_value(&obj).unwrap();
+
+        self.update(&mut state, resource, key, &old, obj);
+    }
+
+    /// Delete the object, it is only marked with the deletion timestamp if it has any finalizer,
+    /// and removed once all the finalizers are removed
+    pub fn delete<K>(&self, namespace: &str, name: &str)
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        let resource = resource_key::<K>();
+        let key = (namespace.to_string(), name.to_string());
+
+        let mut state = self.state.lock().unwrap();
+        let old = state
+            .resources
+            .get(&resource)
+            .and_then(|objects| objects.get(&key))
+            .unwrap_or_else(|| panic!("{} {:?} doesn't exist", resource, key))
+            .clone();
+
+        let mut obj = old.clone();
+        // the controller measures how long the object is being deleted
+        obj["metadata"]["deletionTimestamp"] =
+            json!(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        self.update(&mut state, resource, key, &old, obj);
+    }
+
+    /// Make the next `count` lists of the resource fail with an internal error
+    pub fn fail_lists<K>(&self, count: usize)
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        self.state
+            .lock()
+            .unwrap()
+            .failing_lists
+            .insert(resource_key::<K>(), count);
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let (parts, body) = req.into_parts();
+
+        let path = match parse_path(parts.uri.path()) {
+            None => return status_response(StatusCode::NOT_FOUND, "NotFound", "unknown path"),
+            Some(path) => path,
+        };
+
+        let query = parts
+            .uri
+            .query()
+            .map(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let body = match hyper::body::to_bytes(body).await {
+            Err(err) => {
+                return status_response(StatusCode::BAD_REQUEST, "BadRequest", &err.to_string())
+            }
+            Ok(body) => body,
+        };
+
+        match (&parts.method, &path.name) {
+            (&Method::GET, None) if query.get("watch").is_some_and(|watch| watch == "true") => {
+                let resource_version = query
+                    .get("resourceVersion")
+                    .and_then(|version| version.parse().ok())
+                    .unwrap_or_default();
+
+                self.watch(path, query.get("labelSelector"), resource_version)
+            }
+
+            (&Method::GET, None) => self.list(path, query.get("labelSelector")),
+
+            (&Method::GET, Some(_)) => self.get_object(path),
+
+            (&Method::PATCH, Some(_)) => {
+                let content_type = parts
+                    .headers
+                    .get(http::header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok());
+
+                if content_type != Some(MERGE_PATCH) {
+                    return status_response(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "UnsupportedMediaType",
+                        "only the merge patch is supported",
+                    );
+                }
+
+                match serde_json::from_slice(&body) {
+                    Err(err) => {
+                        status_response(StatusCode::BAD_REQUEST, "BadRequest", &err.to_string())
+                    }
+                    Ok(patch) => self.patch(path, patch),
+                }
+            }
+
+            _ => status_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                "method is not supported",
+            ),
+        }
+    }
+
+    fn list(&self, path: Path, label_selector: Option<&String>) -> Response<Body> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(count) = state
+            .failing_lists
+            .get_mut(&path.resource)
+            .filter(|count| **count > 0)
+        {
+            *count -= 1;
+
+            return status_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InternalError",
+                "list is failed by the test",
+            );
+        }
+
+        let items = state
+            .resources
+            .get(&path.resource)
+            .into_iter()
+            .flat_map(|objects| objects.iter())
+            .filter(|((namespace, _), _)| path.namespace.as_ref().is_none_or(|ns| ns == namespace))
+            .filter(|(_, obj)| match_labels(obj, label_selector))
+            .map(|(_, obj)| obj.clone())
+            .collect::<Vec<_>>();
+
+        json_response(
+            StatusCode::OK,
+            json!({
+                "apiVersion": path.api_version,
+                "kind": "List",
+                "metadata": {"resourceVersion": state.resource_version.to_string()},
+                "items": items
+            }),
+        )
+    }
+
+    fn watch(
+        &self,
+        path: Path,
+        label_selector: Option<&String>,
+        resource_version: u64,
+    ) -> Response<Body> {
+        let state = self.state.clone();
+        let label_selector = label_selector.cloned();
+        let version_receiver = self.version_sender.subscribe();
+
+        let events = stream::unfold(
+            (version_receiver, resource_version),
+            move |(mut version_receiver, resource_version)| {
+                let state = state.clone();
+                let path = path.clone();
+                let label_selector = label_selector.clone();
+
+                async move {
+                    loop {
+                        let (lines, last_version) = {
+                            let state = state.lock().unwrap();
+
+                            let lines = state
+                                .events
+                                .iter()
+                                .filter(|event| event.resource_version > resource_version)
+                                .filter(|event| event.resource == path.resource)
+                                .filter(|event| {
+                                    path.namespace
+                                        .as_ref()
+                                        .is_none_or(|ns| *ns == event.namespace)
+                                })
+                                .filter(|event| {
+                                    match_labels(&event.object, label_selector.as_ref())
+                                })
+                                .map(|event| {
+                                    json!({"type": event.type_, "object": event.object}).to_string()
+                                        + "\n"
+                                })
+                                .collect::<String>();
+
+                            (lines, state.resource_version)
+                        };
+
+                        if !lines.is_empty() {
+                            return Some((
+                                Ok::<_, Infallible>(Bytes::from(lines)),
+                                (version_receiver, last_version),
+                            ));
+                        }
+
+                        // the watch stops when the fake server is dropped
+                        if *version_receiver.borrow_and_update() == last_version
+                            && version_receiver.changed().await.is_err()
+                        {
+                            return None;
+                        }
+                    }
+                }
+            },
+        );
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(events))
+            .unwrap()
+    }
+
+    fn get_object(&self, path: Path) -> Response<Body> {
+        let state = self.state.lock().unwrap();
+
+        match state
+            .resources
+            .get(&path.resource)
+            .and_then(|objects| objects.get(&path.key()))
+        {
+            None => not_found(&path),
+            Some(obj) => json_response(StatusCode::OK, obj.clone()),
+        }
+    }
+
+    fn patch(&self, path: Path, mut patch: Value) -> Response<Body> {
+        let mut state = self.state.lock().unwrap();
+
+        let key = path.key();
+        let old = match state
+            .resources
+            .get(&path.resource)
+            .and_then(|objects| objects.get(&key))
+        {
+            None => return not_found(&path),
+            Some(old) => old.clone(),
+        };
+
+        let mut obj = old.clone();
+
+        // like the resources with the status subresource, the status can only be changed by
+        // the status subresource, and the status subresource can only change the status
+        if path.status {
+            if let Some(status) = patch.get("status") {
+                merge_patch(&mut obj["status"], status);
+            }
+        } else {
+            if let Some(patch) = patch.as_object_mut() {
+                patch.remove("status");
+            }
+
+            merge_patch(&mut obj, &patch);
+        }
+
+        let obj = self.update(&mut state, path.resource.clone(), key, &old, obj);
+
+        json_response(StatusCode::OK, obj)
+    }
+
+    /// Store the changed object, remove it if it is deleting and has no finalizer
+    fn update(
+        &self,
+        state: &mut State,
+        resource: String,
+        key: (String, String),
+        old: &Value,
+        mut obj: Value,
+    ) -> Value {
+        let generation = old["metadata"]["generation"].as_i64().unwrap_or(1);
+        obj["metadata"]["generation"] = if old.get("spec") != obj.get("spec") {
+            json!(generation + 1)
+        } else {
+            json!(generation)
+        };
+
+        // like the real API server, the no-op update doesn't change the resource version
+        if obj == *old {
+            return obj;
+        }
+
+        let deleting = !obj["metadata"]["deletionTimestamp"].is_null();
+        let finalized = obj["metadata"]["finalizers"]
+            .as_array()
+            .is_none_or(|finalizers| finalizers.is_empty());
+
+        if deleting && finalized {
+            state.resources.get_mut(&resource).unwrap().remove(&key);
+            state.resource_version += 1;
+            obj["metadata"]["resourceVersion"] = json!(state.resource_version.to_string());
+
+            self.push_event(state, resource, key.0, "DELETED", obj.clone());
+
+            return obj;
+        }
+
+        self.store(state, resource, key, "MODIFIED", obj)
+    }
+
+    fn store(
+        &self,
+        state: &mut State,
+        resource: String,
+        key: (String, String),
+        type_: &'static str,
+        mut obj: Value,
+    ) -> Value {
+        state.resource_version += 1;
+        obj["metadata"]["resourceVersion"] = json!(state.resource_version.to_string());
+
+        state
+            .resources
+            .entry(resource.clone())
+            .or_default()
+            .insert(key.clone(), obj.clone());
+
+        self.push_event(state, resource, key.0, type_, obj.clone());
+
+        obj
+    }
+
+    fn push_event(
+        &self,
+        state: &mut State,
+        resource: String,
+        namespace: String,
+        type_: &'static str,
+        object: Value,
+    ) {
+        state.events.push(WatchEvent {
+            resource_version: state.resource_version,
+            resource,
+            namespace,
+            type_,
+            object,
+        });
+
+        self.version_sender.send_replace(state.resource_version);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Path {
+    api_version: String,
+    /// `{apiVersion}/{plural}`
+    resource: String,
+    namespace: Option<String>,
+    name: Option<String>,
+    status: bool,
+}
+
+impl Path {
+    fn key(&self) -> (String, String) {
+        (
+            self.namespace.clone().unwrap_or_default(),
+            self.name.clone().unwrap_or_default(),
+        )
+    }
+}
+
+/// Parse `/api/v1/...` or `/apis/{group}/{version}/...`, which is followed by
+/// `[namespaces/{namespace}/]{plural}[/{name}[/status]]`
+fn parse_path(path: &str) -> Option<Path> {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+
+    let (api_version, rest) = match segments.as_slice() {
+        ["api", version, rest @ ..] => (version.to_string(), rest),
+        ["apis", group, version, rest @ ..] => (format!("{}/{}", group, version), rest),
+        _ => return None,
+    };
+
+    let (namespace, rest) = match rest {
+        ["namespaces", namespace, rest @ ..] if !rest.is_empty() => {
+            (Some(namespace.to_string()), rest)
+        }
+        rest => (None, rest),
+    };
+
+    let (plural, name, status) = match rest {
+        [plural] => (plural, None, false),
+        [plural, name] => (plural, Some(name.to_string()), false),
+        [plural, name, "status"] => (plural, Some(name.to_string()), true),
+        _ => return None,
+    };
+
+    Some(Path {
+        resource: format!("{}/{}", api_version, plural),
+        api_version,
+        namespace,
+        name,
+        status,
+    })
+}
+
+fn resource_key<K: Resource<DynamicType = ()>>() -> String {
+    format!("{}/{}", K::api_version(&()), K::plural(&()))
+}
+
+fn object_key(obj: &Value) -> (String, String) {
+    let metadata = &obj["metadata"];
+
+    (
+        metadata["namespace"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        metadata["name"]
+            .as_str()
+            .expect("object doesn't have name")
+            .to_string(),
+    )
+}
+
+/// Only the equality-based selector is supported
+fn match_labels(obj: &Value, label_selector: Option<&String>) -> bool {
+    let label_selector = match label_selector {
+        None => return true,
+        Some(label_selector) => label_selector,
+    };
+
+    let labels = &obj["metadata"]["labels"];
+
+    label_selector
+        .split(',')
+        .filter(|requirement| !requirement.is_empty())
+        .all(|requirement| match requirement.split_once('=') {
+            None => !labels[requirement].is_null(),
+            Some((key, value)) => labels[key].as_str() == Some(value.trim_start_matches('=')),
+        })
+}
+
+/// Apply the JSON merge patch, see RFC 7386
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch.as_object() {
+        None => {
+            *target = patch.clone();
+
+            return;
+        }
+
+        Some(patch) => patch,
+    };
+
+    if !target.is_object() {
+        *target = json!({});
+    }
+
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn not_found(path: &Path) -> Response<Body> {
+    status_response(
+        StatusCode::NOT_FOUND,
+        "NotFound",
+        &format!(
+            "{} {:?} not found",
+            path.resource,
+            path.name.as_deref().unwrap_or_default()
+        ),
+    )
+}
+
+fn status_response(status: StatusCode, reason: &str, message: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "metadata": {},
+            "status": "Failure",
+            "message": message,
+            "reason": reason,
+            "code": status.as_u16()
+        }),
+    )
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_patch_object() {
+        let mut target = json!({"a": {"b": 1, "c": [1, 2]}, "d": "e"});
+
+        merge_patch(
+            &mut target,
+            &json!({"a": {"b": null, "c": [3]}, "f": {"g": 1}}),
+        );
+
+        assert_eq!(target, json!({"a": {"c": [3]}, "d": "e", "f": {"g": 1}}));
+    }
+
+    #[test]
+    fn parse_paths() {
+        let path = parse_path("/api/v1/services").unwrap();
+        assert_eq!(path.resource, "v1/services");
+        assert_eq!(path.namespace, None);
+
+        let path = parse_path("/apis/api.sherlockholo.io/v1/namespaces/default/ddnss/test/status")
+            .unwrap();
+        assert_eq!(path.resource, "api.sherlockholo.io/v1/ddnss");
+        assert_eq!(path.namespace.as_deref(), Some("default"));
+        assert_eq!(path.name.as_deref(), Some("test"));
+        assert!(path.status);
+
+        // the namespaces themselves
+        let path = parse_path("/api/v1/namespaces/default").unwrap();
+        assert_eq!(path.resource, "v1/namespaces");
+        assert_eq!(path.name.as_deref(), Some("default"));
+    }
+}
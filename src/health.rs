@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::ddns::RESYNC_PERIOD;
+use crate::metrics::Metrics;
+
+/// A component is stalled when it keeps failing longer than this
+const STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A component is stuck when it makes no progress longer than this, the watches re-list every
+/// [`RESYNC_PERIOD`] even if nothing is changed
+const STALE_TIMEOUT: Duration = Duration::from_secs(2 * RESYNC_PERIOD.as_secs());
+
+/// The health state of the controller, shared by the components and the health server
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    inner: Arc<HealthInner>,
+}
+
+#[derive(Debug, Default)]
+struct HealthInner {
+    ddns_synced: AtomicBool,
+    cloudflare_verified: AtomicBool,
+    components: Mutex<HashMap<&'static str, ComponentState>>,
+}
+
+#[derive(Debug)]
+struct ComponentState {
+    running: bool,
+    failing_since: Option<Instant>,
+    last_ok: Instant,
+}
+
+impl Health {
+    /// Mark the initial list of the ddns watch is done
+    pub fn set_ddns_synced(&self) {
+        if !self.inner.ddns_synced.swap(true, Ordering::AcqRel) {
+            info!("initial ddns list done");
+        }
+    }
+
+    /// Whether the initial list of the ddns watch is done, so the ddns store has all the ddns
+    pub fn is_ddns_synced(&self) -> bool {
+        self.inner.ddns_synced.load(Ordering::Acquire)
+    }
+
+    /// Mark the cloudflare credentials are verified
+    pub fn set_cloudflare_verified(&self) {
+        self.inner
+            .cloudflare_verified
+            .store(true, Ordering::Release);
+    }
+
+    /// Register a long running component, the component is treated as dead when the returned
+    /// [`ComponentHealth`] is dropped, or it isn't marked ok in [`STALE_TIMEOUT`]
+    pub fn register(&self, name: &'static str) -> ComponentHealth {
+        self.inner.components.lock().unwrap().insert(
+            name,
+            ComponentState {
+                running: true,
+                failing_since: None,
+                last_ok: Instant::now(),
+            },
+        );
+
+        ComponentHealth {
+            health: self.clone(),
+            name,
+        }
+    }
+
+    /// Return the reasons why the controller is not ready
+    pub fn readiness(&self) -> Result<(), Vec<String>> {
+        let mut reasons = vec![];
+
+        if !self.inner.ddns_synced.load(Ordering::Acquire) {
+            reasons.push("initial ddns list is not done".to_string());
+        }
+
+        if !self.inner.cloudflare_verified.load(Ordering::Acquire) {
+            reasons.push("cloudflare credentials are not verified".to_string());
+        }
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(reasons)
+        }
+    }
+
+    /// Return the reasons why the controller is not alive
+    pub fn liveness(&self) -> Result<(), Vec<String>> {
+        let now = Instant::now();
+
+        let mut reasons = self
+            .inner
+            .components
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, state)| {
+                if !state.running {
+                    return Some(format!("{} is stopped", name));
+                }
+
+                if let Some(failing_since) = state
+                    .failing_since
+                    .filter(|failing_since| now.duration_since(*failing_since) >= STALL_TIMEOUT)
+                {
+                    return Some(format!(
+                        "{} keeps failing for {:?}",
+                        name,
+                        now.duration_since(failing_since)
+                    ));
+                }
+
+                let since_ok = now.duration_since(state.last_ok);
+
+                (since_ok >= STALE_TIMEOUT)
+                    .then(|| format!("{} makes no progress for {:?}", name, since_ok))
+            })
+            .collect::<Vec<_>>();
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            reasons.sort();
+
+            Err(reasons)
+        }
+    }
+
+    fn update_component(&self, name: &'static str, f: impl FnOnce(&mut ComponentState)) {
+        if let Some(state) = self.inner.components.lock().unwrap().get_mut(name) {
+            f(state);
+        }
+    }
+}
+
+/// The health handle of a long running component
+#[derive(Debug)]
+pub struct ComponentHealth {
+    health: Health,
+    name: &'static str,
+}
+
+impl ComponentHealth {
+    /// Mark the component makes progress
+    pub fn ok(&self) {
+        self.health.update_component(self.name, |state| {
+            state.failing_since = None;
+            state.last_ok = Instant::now();
+        });
+    }
+
+    /// Mark the component failed, it is stalled if it keeps failing
+    pub fn failed(&self) {
+        self.health.update_component(self.name, |state| {
+            state.failing_since.get_or_insert_with(Instant::now);
+        });
+    }
+}
+
+impl Drop for ComponentHealth {
+    fn drop(&mut self) {
+        self.health
+            .update_component(self.name, |state| state.running = false);
+    }
+}
+
+/// Serve the `/healthz`, `/readyz` and `/metrics` endpoints until the shutdown is cancelled
+pub async fn serve(
+    addr: SocketAddr,
+    health: Health,
+    metrics: Metrics,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        let metrics = metrics.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, health.clone(), metrics.clone())
+            }))
+        }
+    });
+
+    info!(%addr, "health server start");
+
+    Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+
+    info!("health server stopped");
+
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Body>,
+    health: Health,
+    metrics: Metrics,
+) -> Result<Response<Body>, Infallible> {
+    let result = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => health.liveness(),
+        (&Method::GET, "/readyz") => health.readiness(),
+
+        (&Method::GET, "/metrics") => {
+            return Ok(Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics.render()))
+                .unwrap())
+        }
+
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        }
+    };
+
+    let resp = match result {
+        Ok(_) => Response::new(Body::from("ok")),
+
+        Err(reasons) => {
+            warn!(path = req.uri().path(), ?reasons, "health check failed");
+
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(reasons.join("\n")))
+                .unwrap()
+        }
+    };
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    #[test]
+    fn readiness() {
+        let health = Health::default();
+
+        assert_eq!(health.readiness().unwrap_err().len(), 2);
+
+        health.set_ddns_synced();
+        assert_eq!(health.readiness().unwrap_err().len(), 1);
+
+        health.set_cloudflare_verified();
+        assert!(health.readiness().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn liveness_stalled() {
+        let health = Health::default();
+        let component = health.register("ddns watch");
+
+        component.failed();
+        time::advance(STALL_TIMEOUT / 2).await;
+        component.failed();
+        assert!(health.liveness().is_ok());
+
+        time::advance(STALL_TIMEOUT / 2).await;
+        assert!(health.liveness().is_err());
+
+        component.ok();
+        assert!(health.liveness().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn liveness_stale() {
+        let health = Health::default();
+        let component = health.register("provider watch");
+
+        time::advance(STALE_TIMEOUT / 2).await;
+        component.ok();
+        time::advance(STALE_TIMEOUT / 2).await;
+        assert!(health.liveness().is_ok());
+
+        // the watch neither gets events nor re-lists
+        time::advance(STALE_TIMEOUT / 2).await;
+        let reasons = health.liveness().unwrap_err();
+        assert!(reasons[0].starts_with("provider watch makes no progress"));
+
+        component.ok();
+        assert!(health.liveness().is_ok());
+    }
+
+    #[tokio::test]
+    async fn liveness_stopped() {
+        let health = Health::default();
+        let component = health.register("service watch");
+
+        assert!(health.liveness().is_ok());
+
+        drop(component);
+
+        let reasons = health.liveness().unwrap_err();
+        assert_eq!(reasons, vec!["service watch is stopped".to_string()]);
+    }
+}
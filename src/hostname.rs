@@ -0,0 +1,82 @@
+const MAX_DOMAIN_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Lowercase the hostname and remove the trailing dot, so the hostnames can be compared
+pub fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Check if the domain is the zone apex or a subdomain of the zone
+pub fn is_in_zone(domain: &str, zone: &str) -> bool {
+    let domain = normalize_hostname(domain);
+    let zone = normalize_hostname(zone);
+
+    domain == zone
+        || domain
+            .strip_suffix(&zone)
+            .map(|prefix| prefix.ends_with('.'))
+            .unwrap_or(false)
+}
+
+/// The zones which may contain the hostname, from the longest to the shortest, the wildcard label
+/// and the top level domain are never a zone
+pub fn candidate_zones(hostname: &str) -> Vec<String> {
+    let hostname = normalize_hostname(hostname);
+    let hostname = hostname.strip_prefix("*.").unwrap_or(&hostname);
+    let labels = hostname.split('.').collect::<Vec<_>>();
+
+    (0..labels.len().saturating_sub(1))
+        .map(|start| labels[start..].join("."))
+        .collect()
+}
+
+/// Check if the hostname is a valid DNS name, the leading label can be `*` when allow_wildcard is
+/// true
+pub fn check_hostname(hostname: &str, allow_wildcard: bool) -> Result<(), String> {
+    let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+
+    if hostname.is_empty() {
+        return Err("hostname is empty".to_string());
+    }
+
+    if hostname.len() > MAX_DOMAIN_LEN {
+        return Err(format!("hostname is longer than {} bytes", MAX_DOMAIN_LEN));
+    }
+
+    for (index, label) in hostname.split('.').enumerate() {
+        if index == 0 && label == "*" {
+            if !allow_wildcard {
+                return Err("wildcard is not allowed".to_string());
+            }
+
+            continue;
+        }
+
+        if label.is_empty() {
+            return Err("hostname contains an empty label".to_string());
+        }
+
+        if label.len() > MAX_LABEL_LEN {
+            return Err(format!(
+                "label {:?} is longer than {} bytes",
+                label, MAX_LABEL_LEN
+            ));
+        }
+
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("label {:?} starts or ends with a hyphen", label));
+        }
+
+        if let Some(c) = label
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && *c != '-')
+        {
+            return Err(format!(
+                "label {:?} contains invalid character {:?}",
+                label, c
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::spec::IpFilterSpec;
+
+/// The ranges which are not reachable from the internet: private, shared (CGNAT), loopback,
+/// link local, documentation, multicast and the reserved ones
+const NON_PUBLIC_CIDRS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// An IPv4 or IPv6 network, a single address without the prefix length is accepted too
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // shifting out all the bits is None, so the zero prefix contains every address
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let host_bits = 32 - u32::from(self.prefix_len);
+
+                u32::from(addr).checked_shr(host_bits) == u32::from(*ip).checked_shr(host_bits)
+            }
+
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let host_bits = 128 - u32::from(self.prefix_len);
+
+                u128::from(addr).checked_shr(host_bits) == u128::from(*ip).checked_shr(host_bits)
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            None => (s.trim(), None),
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("cidr {:?} is invalid: {}", s, err))?;
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!(
+                        "cidr {:?} is invalid: prefix length is not in 0..={}",
+                        s, max_prefix_len
+                    )
+                })?,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Whether the IP is reachable from the internet
+pub fn is_public(ip: &IpAddr) -> bool {
+    !NON_PUBLIC_CIDRS
+        .iter()
+        // the list is checked by the tests
+        .map(|cidr| cidr.parse::<Cidr>().unwrap())
+        .any(|cidr| cidr.contains(ip))
+}
+
+/// Decide which load balancer IPs can be published. An IP is published when it is in any allow
+/// CIDR, or the allow list is empty, and it is in no deny CIDR, and it is public if public_only
+/// is set. The deny list always wins.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub public_only: bool,
+}
+
+impl IpFilter {
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (!self.public_only || is_public(ip))
+    }
+
+    /// Split the IPs into the allowed ones and the filtered out ones
+    pub fn partition(&self, ips: Vec<IpAddr>) -> (Vec<IpAddr>, Vec<IpAddr>) {
+        ips.into_iter().partition(|ip| self.is_allowed(ip))
+    }
+}
+
+impl TryFrom<&IpFilterSpec> for IpFilter {
+    type Error = String;
+
+    fn try_from(spec: &IpFilterSpec) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allow: parse_cidrs(&spec.allow)?,
+            deny: parse_cidrs(&spec.deny)?,
+            public_only: spec.public_only,
+        })
+    }
+}
+
+pub fn parse_cidrs(cidrs: &[String]) -> Result<Vec<Cidr>, String> {
+    cidrs.iter().map(|cidr| cidr.parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "2001:db8::1".parse::<Cidr>().unwrap().to_string(),
+            "2001:db8::1/128"
+        );
+
+        for invalid in [
+            "10.0.0.0/33",
+            "fc00::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "public",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+
+        for cidr in NON_PUBLIC_CIDRS {
+            assert!(cidr.parse::<Cidr>().is_ok(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let cidr = "172.16.0.0/12".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(&ip("172.31.255.255")));
+        assert!(!cidr.contains(&ip("172.32.0.0")));
+        assert!(!cidr.contains(&ip("::ffff:172.16.0.1")));
+
+        let all = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(all.contains(&ip("1.1.1.1")));
+        assert!(!all.contains(&ip("2606:4700::1111")));
+    }
+
+    #[test]
+    fn public() {
+        for private in [
+            "10.1.2.3",
+            "192.168.1.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "fd00::1",
+        ] {
+            assert!(!is_public(&ip(private)), "{}", private);
+        }
+
+        for public in ["1.1.1.1", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public(&ip(public)), "{}", public);
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let ips = vec![ip("1.1.1.1"), ip("8.8.8.8"), ip("10.0.0.1")];
+
+        assert_eq!(
+            IpFilter::default().partition(ips.clone()),
+            (ips.clone(), vec![])
+        );
+
+        let public_only = IpFilter {
+            public_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            public_only.partition(ips.clone()),
+            (vec![ip("1.1.1.1"), ip("8.8.8.8")], vec![ip("10.0.0.1")])
+        );
+
+        // the deny list wins over the allow list
+        let filter = IpFilter {
+            allow: cidrs(&["1.1.1.0/24", "10.0.0.0/8"]),
+            deny: cidrs(&["10.0.0.1"]),
+            public_only: false,
+        };
+        assert_eq!(
+            filter.partition(ips),
+            (vec![ip("1.1.1.1")], vec![ip("8.8.8.8"), ip("10.0.0.1")])
+        );
+    }
+}
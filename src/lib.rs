@@ -1,26 +1,153 @@
+use std::time::Duration;
+
 use anyhow::Result;
 use kube::Client;
-use tracing::info;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
 use crate::cf_dns::CfDns;
+use crate::cf_dns_pool::CfDnsPool;
+use crate::config::{Command, CF_RATE_LIMIT_PERIOD};
 use crate::ddns::Controller;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
 
 mod cf_dns;
+mod cf_dns_pool;
+mod config;
+mod crd;
+mod credentials;
+mod ctl;
 mod ddns;
+#[cfg(test)]
+mod fake_cloudflare;
+#[cfg(test)]
+mod fake_kube;
+mod health;
+mod hostname;
+mod ip_filter;
+mod metrics;
+mod rate_limit;
 mod service;
+mod shutdown;
 mod spec;
 mod trace;
+mod webhook;
+
+pub use ctl::run as run_ctl;
+
+const VERIFY_CREDENTIALS_INTERVAL: Duration = Duration::from_secs(30);
 
 pub async fn run() -> Result<()> {
-    let _stop_guard = trace::init_tracing()?;
+    let config = match Command::load()? {
+        Command::PrintCrd => {
+            print!("{}", crd::crds_yaml()?);
+
+            return Ok(());
+        }
+
+        Command::Run(config) => config,
+    };
+
+    let _stop_guard = trace::init_tracing(&config.telemetry)?;
+
+    info!(?config, "load config done");
 
     let client = Client::try_default().await?;
 
     info!("init k8s client done");
 
-    let cf_dns = CfDns::new().await?;
+    if config.install_crd {
+        crd::install_crds(client.clone()).await?;
+
+        info!("install crd done");
+    }
+
+    // the requests of all accounts share the limiter, the limit is the same for every account
+    let rate_limiter = RateLimiter::new(
+        config.cf_rate_limit,
+        CF_RATE_LIMIT_PERIOD,
+        config.cf_rate_burst,
+    );
+
+    let cf_dns = CfDns::new_with_api_url(
+        &config.credentials,
+        config.default_ttl,
+        config.cf_api_url.clone(),
+    )
+    .await?
+    .with_rate_limiter(rate_limiter.clone());
 
     info!("init cf dns client done");
 
-    Controller::new(client, cf_dns).run().await
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(shutdown.clone());
+
+    let health = Health::default();
+    let metrics = Metrics::default();
+
+    tokio::spawn(verify_credentials(cf_dns.clone(), health.clone()));
+
+    if let Some(credentials_file) = config.credentials_file.clone() {
+        tokio::spawn(credentials::watch_credentials_file(
+            credentials_file,
+            cf_dns.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let health_server = health::serve(
+        config.health_addr,
+        health.clone(),
+        metrics.clone(),
+        shutdown.clone(),
+    );
+
+    let cf_dns_pool = CfDnsPool::new(client.clone(), cf_dns, config.default_ttl, rate_limiter);
+
+    let controller = Controller::new(client.clone(), cf_dns_pool, health, metrics, &config);
+
+    match config.webhook.clone() {
+        None => {
+            tokio::try_join!(
+                controller.run(shutdown.clone(), config.shutdown_timeout),
+                health_server,
+            )?;
+        }
+
+        Some(webhook_config) => {
+            info!(?webhook_config, "webhook is enabled");
+
+            tokio::try_join!(
+                controller.run(shutdown.clone(), config.shutdown_timeout),
+                health_server,
+                webhook::serve(webhook_config, client, shutdown),
+            )?;
+        }
+    }
+
+    info!("ddns controller stopped");
+
+    Ok(())
+}
+
+/// Verify the cloudflare credentials until success, the controller is not ready before that
+async fn verify_credentials(cf_dns: CfDns, health: Health) {
+    loop {
+        match cf_dns.verify_credentials().await {
+            Ok(_) => {
+                health.set_cloudflare_verified();
+
+                return;
+            }
+
+            Err(err) => {
+                error!(%err, "verify cloudflare credentials failed, retry later");
+
+                time::sleep(VERIFY_CREDENTIALS_INTERVAL).await;
+            }
+        }
+    }
 }
@@ -0,0 +1,151 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The upper bounds of the queue wait time histogram buckets, in seconds
+const WAIT_BUCKETS: [f64; 7] = [0.01, 0.1, 0.5, 1.0, 5.0, 30.0, 120.0];
+
+/// The controller metrics, rendered in the prometheus text format by the health server
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    queue_depth: AtomicI64,
+    queue_coalesced: AtomicU64,
+    queue_wait: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; WAIT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    /// An update of an object is queued
+    pub fn queue_add(&self) {
+        self.inner.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A queued update is replaced by a newer one of the same object
+    pub fn queue_coalesce(&self) {
+        self.inner.queue_coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A queued update starts to be reconciled after waiting in the queue
+    pub fn queue_take(&self, wait: Duration) {
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.inner.queue_wait.observe(wait);
+    }
+
+    pub fn queue_depth(&self) -> i64 {
+        self.inner.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_coalesced(&self) -> u64 {
+        self.inner.queue_coalesced.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        writeln!(
+            output,
+            "# HELP ddns_queue_depth Number of the objects waiting to be reconciled.\n\
+             # TYPE ddns_queue_depth gauge\n\
+             ddns_queue_depth {}",
+            self.queue_depth()
+        )
+        .unwrap();
+
+        writeln!(
+            output,
+            "# HELP ddns_queue_coalesced_total Number of the queued updates replaced by newer ones.\n\
+             # TYPE ddns_queue_coalesced_total counter\n\
+             ddns_queue_coalesced_total {}",
+            self.queue_coalesced()
+        )
+        .unwrap();
+
+        self.inner.queue_wait.render(
+            &mut output,
+            "ddns_queue_wait_seconds",
+            "Time the updates wait in the queue before being reconciled.",
+        );
+
+        output
+    }
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+
+        for (bucket, bound) in self.buckets.iter().zip(WAIT_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        writeln!(
+            output,
+            "# HELP {} {}\n# TYPE {} histogram",
+            name, help, name
+        )
+        .unwrap();
+
+        for (bucket, bound) in self.buckets.iter().zip(WAIT_BUCKETS) {
+            writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(output, "{}_sum {}", name, sum).unwrap();
+        writeln!(output, "{}_count {}", name, count).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_queue_metrics() {
+        let metrics = Metrics::default();
+
+        metrics.queue_add();
+        metrics.queue_add();
+        metrics.queue_coalesce();
+        metrics.queue_take(Duration::from_millis(200));
+
+        let output = metrics.render();
+
+        assert!(output.contains("ddns_queue_depth 1\n"));
+        assert!(output.contains("ddns_queue_coalesced_total 1\n"));
+        assert!(output.contains("ddns_queue_wait_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(output.contains("ddns_queue_wait_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(output.contains("ddns_queue_wait_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(output.contains("ddns_queue_wait_seconds_sum 0.2\n"));
+        assert!(output.contains("ddns_queue_wait_seconds_count 1\n"));
+    }
+}
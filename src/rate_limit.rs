@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant};
+use tracing::debug;
+
+/// The token bucket rate limiter shared by the clones.
+///
+/// The waiters get the token in FIFO order, so the requests are delayed instead of failing.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `limit` requests in any `period`, with at most `burst` requests at once.
+    ///
+    /// The bucket refills `limit - burst` tokens in the period, so a full burst plus the refilled
+    /// tokens never exceed the limit. The burst must be less than the limit.
+    pub fn new(limit: u32, period: Duration, burst: u32) -> Self {
+        assert!(burst > 0 && burst < limit, "burst must be in 1..limit");
+
+        let capacity = burst as f64;
+
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                capacity,
+                tokens: capacity,
+                refill_per_sec: (limit - burst) as f64 / period.as_secs_f64(),
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Wait until a token is available and take it
+    pub async fn acquire(&self) {
+        let mut bucket = self.bucket.lock().await;
+
+        bucket.refill();
+
+        if bucket.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.refill_per_sec);
+
+            debug!(?wait, "rate limited, wait for token");
+
+            // hold the lock, the other waiters are queued behind
+            time::sleep(wait).await;
+
+            bucket.refill();
+        }
+
+        bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_wait() {
+        // refill 1 token per second
+        let limiter = RateLimiter::new(13, Duration::from_secs(10), 3);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        assert_eq!(Instant::now(), start);
+
+        // the fourth request waits for the refill instead of failing
+        assert!(limiter.acquire().now_or_never().is_none());
+
+        limiter.acquire().await;
+        assert!(Instant::now().duration_since(start) >= Duration::from_secs(1));
+
+        time::advance(Duration::from_secs(60)).await;
+
+        // the bucket never exceeds the burst
+        for _ in 0..3 {
+            assert!(limiter.acquire().now_or_never().is_some());
+        }
+        assert!(limiter.acquire().now_or_never().is_none());
+    }
+}
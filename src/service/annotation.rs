@@ -0,0 +1,602 @@
+//! Manage the records of a load balancer service by its annotations, without a Ddns.
+//!
+//! The service is annotated with the [`HOSTNAME_ANNOTATION`], and optionally the
+//! [`ZONE_ANNOTATION`] and the [`TTL_ANNOTATION`], the zone is looked up in the cloudflare
+//! account when it is not set. Like the Ddns, the service gets the
+//! [`FINALIZER`] before the records are published, the published record is kept in the
+//! [`PUBLISHED_ANNOTATION`], so the records are removed when the annotation is changed or removed,
+//! or the service is deleted. When several services annotate the same hostname, the oldest one
+//! publishes the records, namespace and name break the tie.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_util::StreamExt;
+use k8s_openapi::api::core::v1::Service;
+use kube::api::{Patch, PatchParams};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::{Api, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tap::TapFallible;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+use crate::cf_dns::{CfDns, RecordKind};
+use crate::config::{check_ttl, Config};
+use crate::ddns::{compare_claim, find_domain_owner, service_lb_ips, Error, FINALIZER};
+use crate::health::Health;
+use crate::hostname::{check_hostname, is_in_zone, normalize_hostname};
+use crate::ip_filter::IpFilter;
+use crate::shutdown::TaskTracker;
+use crate::spec::Ddns;
+
+pub const HOSTNAME_ANNOTATION: &str = "ddns.sherlockholo.io/hostname";
+/// The zone of the hostname, it is the longest zone of the cloudflare account containing the
+/// hostname if it is not set
+pub const ZONE_ANNOTATION: &str = "ddns.sherlockholo.io/zone";
+/// The ttl of the created records, the controller default ttl is used if it is not set
+pub const TTL_ANNOTATION: &str = "ddns.sherlockholo.io/ttl";
+/// The record published for the service, it is set by the controller
+pub const PUBLISHED_ANNOTATION: &str = "ddns.sherlockholo.io/published";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Published {
+    hostname: String,
+    zone: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct AnnotationSpec {
+    hostname: String,
+    /// None means the zone is looked up
+    zone: Option<String>,
+    ttl: Option<u32>,
+}
+
+/// Whether the service is managed by the annotations, or still has the published records
+pub fn is_annotated(svc: &Service) -> bool {
+    svc.metadata
+        .annotations
+        .as_ref()
+        .is_some_and(|annotations| {
+            annotations.contains_key(HOSTNAME_ANNOTATION)
+                || annotations.contains_key(PUBLISHED_ANNOTATION)
+        })
+}
+
+/// Parse the annotations, None means the service wants no record
+fn annotation_spec(svc: &Service) -> Result<Option<AnnotationSpec>, String> {
+    let is_lb_svc =
+        svc.spec.as_ref().and_then(|spec| spec.type_.as_deref()) == Some("LoadBalancer");
+
+    let annotations = match &svc.metadata.annotations {
+        Some(annotations) if is_lb_svc && svc.metadata.deletion_timestamp.is_none() => annotations,
+        _ => return Ok(None),
+    };
+
+    let hostname = match annotations.get(HOSTNAME_ANNOTATION) {
+        None => return Ok(None),
+        Some(hostname) => hostname.trim(),
+    };
+
+    check_hostname(hostname, true)
+        .map_err(|reason| format!("hostname {:?} is invalid: {}", hostname, reason))?;
+
+    let zone = annotations.get(ZONE_ANNOTATION).map(|zone| zone.trim());
+
+    if let Some(zone) = zone {
+        check_hostname(zone, false)
+            .map_err(|reason| format!("zone {:?} is invalid: {}", zone, reason))?;
+
+        if !is_in_zone(hostname, zone) {
+            return Err(format!("hostname {:?} is not in zone {:?}", hostname, zone));
+        }
+    }
+
+    let ttl = annotations
+        .get(TTL_ANNOTATION)
+        .map(|ttl| {
+            let ttl = ttl
+                .trim()
+                .parse()
+                .map_err(|err| format!("ttl {:?} is invalid: {}", ttl, err))?;
+
+            check_ttl(ttl).map(|_| ttl)
+        })
+        .transpose()?;
+
+    Ok(Some(AnnotationSpec {
+        hostname: normalize_hostname(hostname),
+        zone: zone.map(normalize_hostname),
+        ttl,
+    }))
+}
+
+/// Find the annotated service which owns the hostname in the services, like the Ddns owning a
+/// domain
+fn find_hostname_owner<'a>(
+    hostname: &str,
+    services: impl IntoIterator<Item = &'a Service>,
+) -> Option<&'a Service> {
+    services
+        .into_iter()
+        .filter(|svc| {
+            annotation_spec(svc)
+                .is_ok_and(|spec| spec.is_some_and(|spec| spec.hostname == hostname))
+        })
+        .min_by(|a, b| compare_claim(*a, *b))
+}
+
+fn published(svc: &Service) -> Option<Published> {
+    svc.metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PUBLISHED_ANNOTATION))
+        .and_then(|published| {
+            serde_json::from_str(published)
+                .tap_err(|err| warn!(%err, %published, "published annotation is invalid"))
+                .ok()
+        })
+}
+
+#[derive(Clone)]
+pub struct AnnotationReconciler {
+    client: Client,
+    cf_dns: CfDns,
+    retry_delay: Duration,
+    conflict_recheck_delay: Duration,
+    ip_filter: IpFilter,
+    ddns_store: Store<Ddns>,
+    /// All the services of the cluster, so the owner of a hostname is found without listing them
+    service_store: Store<Service>,
+    health: Health,
+}
+
+impl AnnotationReconciler {
+    pub fn new(
+        client: Client,
+        cf_dns: CfDns,
+        config: &Config,
+        ddns_store: Store<Ddns>,
+        service_store: Store<Service>,
+        health: Health,
+    ) -> Self {
+        Self {
+            client,
+            cf_dns,
+            retry_delay: config.retry_delay,
+            conflict_recheck_delay: config.conflict_recheck_delay,
+            ip_filter: config.ip_filter.clone(),
+            ddns_store,
+            service_store,
+            health,
+        }
+    }
+
+    /// Reconcile the services from the receiver one by one until the shutdown is cancelled. The
+    /// latest service is read before every reconcile, so a retry never applies a stale snapshot.
+    /// The pending retries are tracked and dropped when shutting down.
+    pub async fn run(
+        self,
+        mut receiver: UnboundedReceiver<ObjectRef<Service>>,
+        retry_sender: UnboundedSender<ObjectRef<Service>>,
+        shutdown: CancellationToken,
+        tracker: TaskTracker,
+    ) {
+        loop {
+            let obj_ref = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("shutdown, stop reconciling annotated services");
+
+                    return;
+                }
+
+                obj_ref = receiver.next() => match obj_ref {
+                    None => return,
+                    Some(obj_ref) => obj_ref,
+                },
+            };
+
+            let result = self
+                .reconcile(&obj_ref)
+                .instrument(info_span!("reconcile annotated service", %obj_ref))
+                .await;
+
+            let delay = match result {
+                Ok(_) => continue,
+                Err(Error::ReRun(delay)) => delay,
+                Err(Error::Other(err)) => {
+                    error!(%err, %obj_ref, "reconcile annotated service failed");
+
+                    self.retry_delay
+                }
+            };
+
+            let retry_sender = retry_sender.clone();
+            let shutdown = shutdown.clone();
+            tracker.spawn(async move {
+                tokio::select! {
+                    _ = shutdown.cancelled() => {}
+
+                    _ = time::sleep(delay) => {
+                        let _ = retry_sender.unbounded_send(obj_ref);
+                    }
+                }
+            });
+        }
+    }
+
+    #[instrument(err, skip(self))]
+    async fn reconcile(&self, obj_ref: &ObjectRef<Service>) -> Result<(), Error> {
+        let namespace = obj_ref.namespace.as_deref().unwrap_or_default();
+        let svc_api: Api<Service> = Api::namespaced(self.client.clone(), namespace);
+
+        let svc = match svc_api.get_opt(&obj_ref.name).await? {
+            None => {
+                info!("service is gone");
+
+                return Ok(());
+            }
+
+            Some(svc) => svc,
+        };
+
+        let spec = match annotation_spec(&svc) {
+            Err(reason) => {
+                warn!(%reason, "annotations are invalid, keep the published records");
+
+                return Ok(());
+            }
+
+            Ok(spec) => spec,
+        };
+
+        let published = published(&svc);
+
+        // the owner of the hostname can't be known before the ddns store is synced
+        if !self.health.is_ddns_synced() {
+            info!("ddns are not listed yet, retry later");
+
+            return Err(self.retry_delay.into());
+        }
+
+        let ddns_list = self.ddns_store.state();
+        // the reconciled service comes from the service watch, so the store is synced already
+        let services = self.service_store.state();
+
+        let spec = match spec {
+            None => {
+                if let Some(published) = &published {
+                    self.remove_records(published, &ddns_list, &services, obj_ref)
+                        .await?;
+                }
+
+                self.patch(&svc_api, &svc, None).await?;
+
+                info!(?published, "release annotated service done");
+
+                return Ok(());
+            }
+
+            Some(spec) => spec,
+        };
+
+        if let Some(owner) = find_domain_owner(&spec.hostname, ddns_list.iter().map(Arc::as_ref)) {
+            warn!(?spec, owner = %ObjectRef::from_obj(owner), "hostname is owned by ddns");
+
+            return Err(self.conflict_recheck_delay.into());
+        }
+
+        if let Some(owner) = find_hostname_owner(&spec.hostname, services.iter().map(Arc::as_ref))
+            .map(ObjectRef::from_obj)
+            .filter(|owner| owner != obj_ref)
+        {
+            warn!(?spec, %owner, "hostname is owned by other annotated service, skip it");
+
+            return Err(self.conflict_recheck_delay.into());
+        }
+
+        let zone = match self.zone(&spec, published.as_ref()).await? {
+            None => {
+                warn!(
+                    ?spec,
+                    "no zone contains the hostname, keep the published records"
+                );
+
+                return Ok(());
+            }
+
+            Some(zone) => zone,
+        };
+
+        let new_published = Published {
+            hostname: spec.hostname.clone(),
+            zone: zone.clone(),
+        };
+
+        if let Some(published) = published
+            .as_ref()
+            .filter(|published| **published != new_published)
+        {
+            self.remove_records(published, &ddns_list, &services, obj_ref)
+                .await?;
+        }
+
+        let lb_ips = service_lb_ips(std::slice::from_ref(&svc))?;
+
+        if lb_ips.is_empty() {
+            // the service is watched, it is reconciled again once the load balancer has an IP
+            info!(?spec, "load balancer has no ip");
+
+            return Ok(());
+        }
+
+        // the records are removed if all the IPs are filtered out, like the Ddns
+        let (lb_ips, filtered_ips) = self.ip_filter.partition(lb_ips);
+
+        if !filtered_ips.is_empty() {
+            info!(?spec, ?filtered_ips, "filter out load balancer ips");
+        }
+
+        // the finalizer is set first, so the records are always cleaned up
+        self.patch(&svc_api, &svc, Some(&new_published)).await?;
+
+        let cf_dns = match spec.ttl {
+            None => self.cf_dns.clone(),
+            Some(ttl) => self.cf_dns.clone().with_ttl(ttl),
+        };
+
+        cf_dns
+            .set_dns_record(&spec.hostname, &zone, RecordKind::A, &lb_ips)
+            .await?;
+
+        info!(?spec, ?lb_ips, "set annotated service dns record done");
+
+        Ok(())
+    }
+
+    /// The zone of the annotation, or the published zone of the same hostname, otherwise it is
+    /// looked up in the cloudflare account
+    async fn zone(
+        &self,
+        spec: &AnnotationSpec,
+        published: Option<&Published>,
+    ) -> Result<Option<String>, Error> {
+        if let Some(zone) = &spec.zone {
+            return Ok(Some(zone.clone()));
+        }
+
+        if let Some(published) = published.filter(|published| published.hostname == spec.hostname) {
+            return Ok(Some(published.zone.clone()));
+        }
+
+        Ok(self.cf_dns.find_zone(&spec.hostname).await?)
+    }
+
+    /// Remove the published records, unless a Ddns or another annotated service owns the
+    /// hostname now
+    async fn remove_records(
+        &self,
+        published: &Published,
+        ddns_list: &[Arc<Ddns>],
+        services: &[Arc<Service>],
+        obj_ref: &ObjectRef<Service>,
+    ) -> Result<(), Error> {
+        if let Some(owner) =
+            find_domain_owner(&published.hostname, ddns_list.iter().map(Arc::as_ref))
+        {
+            info!(?published, owner = %ObjectRef::from_obj(owner), "hostname is owned by ddns, keep records");
+
+            return Ok(());
+        }
+
+        if let Some(owner) =
+            find_hostname_owner(&published.hostname, services.iter().map(Arc::as_ref))
+                .map(ObjectRef::from_obj)
+                .filter(|owner| owner != obj_ref)
+        {
+            info!(?published, %owner, "hostname is owned by other annotated service, keep records");
+
+            return Ok(());
+        }
+
+        self.cf_dns
+            .remove_dns_records(&published.hostname, &published.zone, RecordKind::A)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Set the published annotation and the finalizer, or remove them if published is None
+    async fn patch(
+        &self,
+        svc_api: &Api<Service>,
+        svc: &Service,
+        published: Option<&Published>,
+    ) -> Result<(), Error> {
+        let annotations = svc.metadata.annotations.clone().unwrap_or_default();
+        let mut finalizers = svc.metadata.finalizers.clone().unwrap_or_default();
+
+        let published = published.map(|published| {
+            // a struct of strings is always serializable
+            serde_json::to_string(published).unwrap()
+        });
+
+        let has_finalizer = finalizers.iter().any(|finalizer| finalizer == FINALIZER);
+
+        if published.as_ref() == annotations.get(PUBLISHED_ANNOTATION)
+            && has_finalizer == published.is_some()
+        {
+            return Ok(());
+        }
+
+        if published.is_some() {
+            if !has_finalizer {
+                finalizers.push(FINALIZER.to_string());
+            }
+        } else {
+            finalizers.retain(|finalizer| finalizer != FINALIZER);
+        }
+
+        // the resource version makes the patch fail if the service is changed meanwhile
+        let patch = json!({
+            "metadata": {
+                "resourceVersion": svc.metadata.resource_version,
+                "annotations": BTreeMap::from([(PUBLISHED_ANNOTATION, published)]),
+                "finalizers": finalizers,
+            }
+        });
+
+        svc_api
+            .patch(
+                svc.metadata.name.as_deref().unwrap_or_default(),
+                &PatchParams::default(),
+                &Patch::Merge(patch),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::api::core::v1::ServiceSpec;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    use super::*;
+
+    fn service(annotations: &[(&str, &str)]) -> Service {
+        let mut svc = Service {
+            spec: Some(ServiceSpec {
+                type_: Some("LoadBalancer".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        svc.metadata.annotations = Some(
+            annotations
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        );
+
+        svc
+    }
+
+    #[test]
+    fn parse_annotations() {
+        let svc = service(&[(HOSTNAME_ANNOTATION, "App.example.com")]);
+        assert_eq!(
+            annotation_spec(&svc),
+            Ok(Some(AnnotationSpec {
+                hostname: "app.example.com".to_string(),
+                zone: None,
+                ttl: None,
+            }))
+        );
+
+        let svc = service(&[
+            (HOSTNAME_ANNOTATION, "app.example.co.uk"),
+            (ZONE_ANNOTATION, "example.co.uk"),
+            (TTL_ANNOTATION, "120"),
+        ]);
+        assert_eq!(
+            annotation_spec(&svc),
+            Ok(Some(AnnotationSpec {
+                hostname: "app.example.co.uk".to_string(),
+                zone: Some("example.co.uk".to_string()),
+                ttl: Some(120),
+            }))
+        );
+
+        assert_eq!(annotation_spec(&service(&[])), Ok(None));
+
+        let mut cluster_ip = service(&[(HOSTNAME_ANNOTATION, "app.example.com")]);
+        cluster_ip.spec.as_mut().unwrap().type_ = Some("ClusterIP".to_string());
+        assert_eq!(annotation_spec(&cluster_ip), Ok(None));
+    }
+
+    #[test]
+    fn invalid_annotations() {
+        for annotations in [
+            [
+                (HOSTNAME_ANNOTATION, "app..example.com"),
+                (TTL_ANNOTATION, "120"),
+            ],
+            [
+                (HOSTNAME_ANNOTATION, "app.example.com"),
+                (ZONE_ANNOTATION, "example.org"),
+            ],
+            [
+                (HOSTNAME_ANNOTATION, "app.example.com"),
+                (TTL_ANNOTATION, "ten"),
+            ],
+            [
+                (HOSTNAME_ANNOTATION, "app.example.com"),
+                (TTL_ANNOTATION, "10"),
+            ],
+        ] {
+            assert!(
+                annotation_spec(&service(&annotations)).is_err(),
+                "{:?}",
+                annotations
+            );
+        }
+    }
+
+    #[test]
+    fn oldest_service_owns_hostname() {
+        let annotated = |namespace: &str, name: &str, hostname: &str, created: i64| {
+            let mut svc = service(&[(HOSTNAME_ANNOTATION, hostname)]);
+            svc.metadata.namespace = Some(namespace.to_string());
+            svc.metadata.name = Some(name.to_string());
+            svc.metadata.creation_timestamp = Some(Time(Utc.timestamp(created, 0)));
+
+            svc
+        };
+        let owner_name =
+            |owner: Option<&Service>| owner.and_then(|owner| owner.metadata.name.clone());
+
+        let services = [
+            annotated("default", "new", "app.example.com", 200),
+            annotated("default", "old", "App.example.com", 100),
+            annotated("default", "other", "api.example.com", 50),
+        ];
+        assert_eq!(
+            owner_name(find_hostname_owner("app.example.com", &services)),
+            Some("old".to_string())
+        );
+
+        // namespace and name break the tie
+        let services = [
+            annotated("default", "b", "app.example.com", 100),
+            annotated("default", "a", "app.example.com", 100),
+            annotated("alpha", "c", "app.example.com", 100),
+        ];
+        assert_eq!(
+            owner_name(find_hostname_owner("app.example.com", &services)),
+            Some("c".to_string())
+        );
+
+        assert_eq!(
+            owner_name(find_hostname_owner("www.example.com", &services)),
+            None
+        );
+    }
+
+    #[test]
+    fn annotated() {
+        assert!(is_annotated(&service(&[(
+            HOSTNAME_ANNOTATION,
+            "app.example.com"
+        )])));
+        assert!(is_annotated(&service(&[(PUBLISHED_ANNOTATION, "{}")])));
+        assert!(!is_annotated(&service(&[("other", "app.example.com")])));
+    }
+}
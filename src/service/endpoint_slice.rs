@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use futures_util::Stream;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::ListParams;
+use kube::runtime::watcher::{Error, Event};
+use kube::Api;
+
+use crate::ddns::resync_watcher;
+
+/// The label of the endpoint slice which is the name of the service it belongs to
+pub const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// Whether the endpoint slice has any ready endpoint, the unknown readiness means ready like
+/// kubernetes does
+pub fn slice_is_ready(slice: &EndpointSlice) -> bool {
+    slice.endpoints.iter().any(|endpoint| {
+        endpoint
+            .conditions
+            .as_ref()
+            .and_then(|conditions| conditions.ready)
+            .unwrap_or(true)
+    })
+}
+
+pub fn watch_endpoint_slice(
+    api: Api<EndpointSlice>,
+) -> impl Stream<Item = Result<Event<EndpointSlice>, Error>> {
+    resync_watcher(api, ListParams::default().labels(SERVICE_NAME_LABEL))
+}
+
+/// The namespace and the name of a service
+pub type ServiceKey = (String, String);
+
+/// Track the readiness of the services by their endpoint slices, so only the readiness changes
+/// trigger the reconciles instead of every endpoint change
+#[derive(Debug, Default)]
+pub struct ServiceReadiness {
+    /// The readiness of every endpoint slice of the services
+    slices: HashMap<ServiceKey, HashMap<String, bool>>,
+    listed: bool,
+}
+
+impl ServiceReadiness {
+    /// Apply the watch event, return the services whose readiness is changed. The first list
+    /// doesn't change anything, the Ddns are all reconciled when the controller starts.
+    pub fn apply(&mut self, event: Event<EndpointSlice>) -> Vec<ServiceKey> {
+        match event {
+            Event::Applied(slice) => self.update(&slice, true).into_iter().collect(),
+            Event::Deleted(slice) => self.update(&slice, false).into_iter().collect(),
+
+            Event::Restarted(slices) => {
+                let old_slices = std::mem::take(&mut self.slices);
+
+                for slice in &slices {
+                    if let Some((service, slice_name)) = slice_key(slice) {
+                        self.slices
+                            .entry(service)
+                            .or_default()
+                            .insert(slice_name, slice_is_ready(slice));
+                    }
+                }
+
+                if !std::mem::replace(&mut self.listed, true) {
+                    return vec![];
+                }
+
+                let mut changed = old_slices
+                    .keys()
+                    .chain(self.slices.keys())
+                    .filter(|service| {
+                        is_ready(old_slices.get(*service)) != is_ready(self.slices.get(*service))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                changed.sort();
+                changed.dedup();
+
+                changed
+            }
+        }
+    }
+
+    fn update(&mut self, slice: &EndpointSlice, applied: bool) -> Option<ServiceKey> {
+        let (service, slice_name) = slice_key(slice)?;
+
+        let slices = self.slices.entry(service.clone()).or_default();
+        let old_ready = is_ready(Some(slices));
+
+        if applied {
+            slices.insert(slice_name, slice_is_ready(slice));
+        } else {
+            slices.remove(&slice_name);
+        }
+
+        let ready = is_ready(Some(slices));
+
+        if slices.is_empty() {
+            self.slices.remove(&service);
+        }
+
+        (old_ready != ready).then_some(service)
+    }
+}
+
+fn is_ready(slices: Option<&HashMap<String, bool>>) -> bool {
+    slices.is_some_and(|slices| slices.values().any(|ready| *ready))
+}
+
+fn slice_key(slice: &EndpointSlice) -> Option<(ServiceKey, String)> {
+    let metadata = &slice.metadata;
+    let service = metadata.labels.as_ref()?.get(SERVICE_NAME_LABEL)?;
+
+    Some((
+        (metadata.namespace.clone()?, service.clone()),
+        metadata.name.clone()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointConditions};
+
+    use super::*;
+
+    fn slice(name: &str, service: &str, ready: &[Option<bool>]) -> EndpointSlice {
+        let mut slice = EndpointSlice {
+            endpoints: ready
+                .iter()
+                .map(|ready| Endpoint {
+                    conditions: Some(EndpointConditions {
+                        ready: *ready,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        slice.metadata.namespace = Some("default".to_string());
+        slice.metadata.name = Some(name.to_string());
+        slice.metadata.labels = Some(BTreeMap::from([(
+            SERVICE_NAME_LABEL.to_string(),
+            service.to_string(),
+        )]));
+
+        slice
+    }
+
+    fn key(service: &str) -> ServiceKey {
+        ("default".to_string(), service.to_string())
+    }
+
+    #[test]
+    fn ready_slice() {
+        assert!(slice_is_ready(&slice(
+            "web-1",
+            "web",
+            &[Some(false), Some(true)]
+        )));
+        assert!(slice_is_ready(&slice("web-1", "web", &[None])));
+        assert!(!slice_is_ready(&slice("web-1", "web", &[Some(false)])));
+        assert!(!slice_is_ready(&slice("web-1", "web", &[])));
+    }
+
+    #[test]
+    fn readiness_changes() {
+        let mut readiness = ServiceReadiness::default();
+
+        // the first list only records the readiness
+        assert!(readiness
+            .apply(Event::Restarted(vec![slice("web-1", "web", &[Some(true)])]))
+            .is_empty());
+
+        // another ready slice doesn't change the service readiness
+        assert!(readiness
+            .apply(Event::Applied(slice("web-2", "web", &[Some(true)])))
+            .is_empty());
+        assert!(readiness
+            .apply(Event::Applied(slice("web-1", "web", &[Some(false)])))
+            .is_empty());
+
+        assert_eq!(
+            readiness.apply(Event::Applied(slice("web-2", "web", &[Some(false)]))),
+            [key("web")]
+        );
+        assert_eq!(
+            readiness.apply(Event::Applied(slice("api-1", "api", &[None]))),
+            [key("api")]
+        );
+        assert_eq!(
+            readiness.apply(Event::Deleted(slice("api-1", "api", &[None]))),
+            [key("api")]
+        );
+
+        // the re-list finds the changes missed by the broken watch
+        assert_eq!(
+            readiness.apply(Event::Restarted(vec![
+                slice("web-1", "web", &[Some(true)]),
+                slice("api-1", "api", &[Some(false)]),
+            ])),
+            [key("web")]
+        );
+    }
+}
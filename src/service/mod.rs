@@ -1,4 +1,11 @@
+pub use annotation::AnnotationReconciler;
+#[cfg(test)]
+pub use annotation::{HOSTNAME_ANNOTATION, PUBLISHED_ANNOTATION, TTL_ANNOTATION};
+pub use endpoint_slice::{slice_is_ready, SERVICE_NAME_LABEL};
 pub use trigger::Trigger;
 
+mod annotation;
+mod endpoint_slice;
+mod provider;
 mod trigger;
 mod watch;
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use futures_util::Stream;
+use kube::api::ListParams;
+use kube::runtime::watcher::{Error, Event};
+use kube::Api;
+
+use crate::ddns::resync_watcher;
+use crate::spec::DnsProviderConfig;
+
+pub fn watch_provider(
+    api: Api<DnsProviderConfig>,
+) -> impl Stream<Item = Result<Event<DnsProviderConfig>, Error>> {
+    resync_watcher(api, ListParams::default())
+}
+
+/// Track the generations of the providers, so only the spec changes trigger the reconciles of
+/// the Ddns referring them, the clients of the changed providers are rebuilt by the reconciles
+#[derive(Debug, Default)]
+pub struct ProviderGenerations {
+    generations: HashMap<String, i64>,
+    listed: bool,
+}
+
+impl ProviderGenerations {
+    /// Apply the watch event, return the providers whose spec is changed or which are deleted.
+    /// The first list doesn't change anything, the Ddns are all reconciled when the controller
+    /// starts.
+    pub fn apply(&mut self, event: Event<DnsProviderConfig>) -> Vec<String> {
+        match event {
+            Event::Applied(provider) => self.update(&provider).into_iter().collect(),
+
+            Event::Deleted(provider) => provider
+                .metadata
+                .name
+                .filter(|name| self.generations.remove(name).is_some())
+                .into_iter()
+                .collect(),
+
+            Event::Restarted(providers) => {
+                let old_generations = std::mem::take(&mut self.generations);
+
+                for provider in &providers {
+                    if let Some((name, generation)) = generation(provider) {
+                        self.generations.insert(name, generation);
+                    }
+                }
+
+                if !std::mem::replace(&mut self.listed, true) {
+                    return vec![];
+                }
+
+                let mut changed = old_generations
+                    .keys()
+                    .chain(self.generations.keys())
+                    .filter(|name| old_generations.get(*name) != self.generations.get(*name))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                changed.sort();
+                changed.dedup();
+
+                changed
+            }
+        }
+    }
+
+    fn update(&mut self, provider: &DnsProviderConfig) -> Option<String> {
+        let (name, generation) = generation(provider)?;
+
+        let old_generation = self.generations.insert(name.clone(), generation);
+
+        (old_generation != Some(generation)).then_some(name)
+    }
+}
+
+fn generation(provider: &DnsProviderConfig) -> Option<(String, i64)> {
+    Some((
+        provider.metadata.name.clone()?,
+        provider.metadata.generation.unwrap_or_default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::DnsProviderConfigSpec;
+
+    fn provider(name: &str, generation: i64) -> DnsProviderConfig {
+        let mut provider = DnsProviderConfig::new(name, DnsProviderConfigSpec::default());
+        provider.metadata.generation = Some(generation);
+
+        provider
+    }
+
+    #[test]
+    fn generation_changes() {
+        let mut generations = ProviderGenerations::default();
+
+        // the first list only records the generations
+        assert!(generations
+            .apply(Event::Restarted(vec![provider("a", 1), provider("b", 1)]))
+            .is_empty());
+
+        // the metadata change doesn't change the generation
+        assert!(generations
+            .apply(Event::Applied(provider("a", 1)))
+            .is_empty());
+
+        assert_eq!(generations.apply(Event::Applied(provider("a", 2))), ["a"]);
+        assert_eq!(generations.apply(Event::Applied(provider("c", 1))), ["c"]);
+        assert_eq!(generations.apply(Event::Deleted(provider("c", 1))), ["c"]);
+        assert!(generations
+            .apply(Event::Deleted(provider("c", 1)))
+            .is_empty());
+
+        // the re-list finds the changes missed by the broken watch
+        assert_eq!(
+            generations.apply(Event::Restarted(vec![provider("a", 2), provider("d", 1)])),
+            ["b", "d"]
+        );
+    }
+}
@@ -1,26 +1,62 @@
-use futures_util::TryStreamExt;
+use std::collections::BTreeMap;
+
+use futures_channel::mpsc;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::StreamExt;
 use itertools::Itertools;
+use k8s_openapi::api::core::v1::Service;
 use kube::api::ListParams;
+use kube::runtime::reflector::store::Writer;
+use kube::runtime::reflector::{ObjectRef, Store};
 use kube::{Api, Client};
 use tap::TapFallible;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, info_span, Instrument};
 
-use crate::ddns::{ErrorPolicy, Reconcile};
-use crate::service::watch::{watch_service, ServiceEvent};
+use crate::ddns::{ErrorPolicy, Reconcile, WATCH_RETRY_DELAY};
+use crate::health::Health;
+use crate::service::annotation::{is_annotated, AnnotationReconciler};
+use crate::service::endpoint_slice::{watch_endpoint_slice, ServiceKey, ServiceReadiness};
+use crate::service::provider::{watch_provider, ProviderGenerations};
+use crate::service::watch::{is_ddns_service, watch_service, ServiceEvent};
+use crate::shutdown::TaskTracker;
 use crate::spec::Ddns;
 
+/// A trigger whose list of the Ddns failed, it is run again after [`WATCH_RETRY_DELAY`], the
+/// watch events which caused it are not sent again
+#[derive(Debug)]
+enum PendingTrigger {
+    /// The labels of the changed service
+    Service(BTreeMap<String, String>),
+    Readiness(ServiceKey),
+}
+
 pub struct Trigger<R, E> {
     client: Client,
     reconciler: R,
     err_policy: E,
+    annotation_reconciler: AnnotationReconciler,
+    ddns_store: Store<Ddns>,
+    health: Health,
 }
 
 impl<R, E> Trigger<R, E> {
-    pub fn new(client: Client, reconciler: R, err_policy: E) -> Self {
+    pub fn new(
+        client: Client,
+        reconciler: R,
+        err_policy: E,
+        annotation_reconciler: AnnotationReconciler,
+        ddns_store: Store<Ddns>,
+        health: Health,
+    ) -> Self {
         Self {
             client,
             reconciler,
             err_policy,
+            annotation_reconciler,
+            ddns_store,
+            health,
         }
     }
 }
@@ -30,66 +66,189 @@ where
     R: Reconcile + Clone + Send + Sync + 'static,
     E: ErrorPolicy<Error = R::Error> + Clone + Send + Sync + 'static,
 {
-    // TODO remove it when we can use intersperse only with std lib
-    #[allow(unstable_name_collisions)]
-    pub async fn trigger_ddns_reconcile(self) -> Result<(), anyhow::Error> {
+    /// Trigger the reconciles until the shutdown is cancelled, the service store is fed by the
+    /// service watch
+    pub async fn trigger_ddns_reconcile(
+        self,
+        service_store: Writer<Service>,
+        shutdown: CancellationToken,
+        tracker: TaskTracker,
+    ) -> Result<(), anyhow::Error> {
         info!("start trigger ddns reconcile");
 
+        let service_health = self.health.register("service watch");
+        let endpoint_slice_health = self.health.register("endpoint slice watch");
+        let provider_health = self.health.register("provider watch");
+
         let svc_api = Api::all(self.client.clone());
 
-        let service_change_stream = watch_service(svc_api);
+        let service_change_stream = watch_service(svc_api, service_store);
         futures_util::pin_mut!(service_change_stream);
 
-        while let Some(service_event) = service_change_stream.try_next().await.tap_err(|err| {
-            error!(%err, "get service change stream failed");
-        })? {
-            info!(?service_event, "get service change event");
+        let endpoint_slice_stream = watch_endpoint_slice(Api::all(self.client.clone()));
+        futures_util::pin_mut!(endpoint_slice_stream);
 
-            let ddns_api = Api::<Ddns>::all(self.client.clone());
+        let mut service_readiness = ServiceReadiness::default();
 
-            let svc = match service_event {
-                ServiceEvent::Applied(svc) | ServiceEvent::Deleted(svc) => svc,
-            };
+        let provider_stream = watch_provider(Api::all(self.client.clone()));
+        futures_util::pin_mut!(provider_stream);
+
+        let mut provider_generations = ProviderGenerations::default();
+
+        let (retry_sender, mut retry_receiver) = mpsc::unbounded();
+
+        let (annotated_sender, annotated_receiver) = mpsc::unbounded();
+        tracker.spawn(self.annotation_reconciler.clone().run(
+            annotated_receiver,
+            annotated_sender.clone(),
+            shutdown.clone(),
+            tracker.clone(),
+        ));
+
+        loop {
+            let service_event = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("shutdown, stop triggering ddns reconcile");
+
+                    return Ok(());
+                }
+
+                service_event = service_change_stream.next() => service_event,
+
+                Some(pending) = retry_receiver.next() => {
+                    info!(?pending, "retry trigger");
+
+                    // the failed trigger is retried again by run_trigger
+                    let _ = self.run_trigger(pending, &retry_sender, &shutdown, &tracker).await;
+
+                    continue;
+                }
+
+                Some(endpoint_slice_event) = endpoint_slice_stream.next() => {
+                    match endpoint_slice_event {
+                        Err(err) => {
+                            error!(%err, "get endpoint slice change stream failed");
+
+                            endpoint_slice_health.failed();
+
+                            tokio::select! {
+                                _ = shutdown.cancelled() => return Ok(()),
+                                _ = time::sleep(WATCH_RETRY_DELAY) => continue,
+                            }
+                        }
 
-            let labels = svc
-                .metadata
-                .labels
-                .expect("service_change_stream return empty labels service");
+                        Ok(endpoint_slice_event) => {
+                            endpoint_slice_health.ok();
 
-            info!(?labels, "get labels map");
+                            for service in service_readiness.apply(endpoint_slice_event) {
+                                info!(?service, "service readiness is changed");
 
-            let labels = labels
-                .into_iter()
-                .map(|(key, value)| format!("{}={}", key, value))
-                .intersperse(",".to_string())
-                .collect::<String>();
+                                let result = self
+                                    .run_trigger(
+                                        PendingTrigger::Readiness(service),
+                                        &retry_sender,
+                                        &shutdown,
+                                        &tracker,
+                                    )
+                                    .await;
 
-            info!(%labels, "get filter labels");
+                                if result.is_err() {
+                                    endpoint_slice_health.failed();
+                                }
+                            }
 
-            let list_params = ListParams::default().labels(&labels);
+                            continue;
+                        }
+                    }
+                }
+
+                Some(provider_event) = provider_stream.next() => {
+                    match provider_event {
+                        Err(err) => {
+                            error!(%err, "get provider change stream failed");
 
-            let ddns_list = ddns_api.list(&list_params).await.tap_err(|err| {
-                error!(%err, "list ddns failed");
-            })?;
+                            provider_health.failed();
 
-            for ddns in ddns_list {
-                let reconciler = self.reconciler.clone();
-                let err_policy = self.err_policy.clone();
+                            tokio::select! {
+                                _ = shutdown.cancelled() => return Ok(()),
+                                _ = time::sleep(WATCH_RETRY_DELAY) => continue,
+                            }
+                        }
 
-                tokio::spawn(
-                    async move {
-                        info!(?ddns, "start reconcile ddns");
+                        Ok(provider_event) => {
+                            provider_health.ok();
 
-                        if let Err(err) = reconciler.reconcile_ddns(ddns.clone()).await {
-                            error!(%err, ?ddns, "reconcile failed");
+                            for provider in provider_generations.apply(provider_event) {
+                                info!(%provider, "provider is changed");
 
-                            err_policy.error_policy(ddns.clone(), err).await;
+                                self.trigger_provider_change(&provider, &tracker);
+                            }
 
-                            info!(?ddns, "run error policy done");
+                            continue;
                         }
                     }
-                    .instrument(info_span!("reconcile ddns")),
-                );
+                }
+            };
+
+            let service_event = match service_event {
+                None => break,
+
+                Some(Err(err)) => {
+                    error!(%err, "get service change stream failed");
+
+                    service_health.failed();
+
+                    // the watcher will re-list in next poll, don't flood the api server
+                    tokio::select! {
+                        _ = shutdown.cancelled() => return Ok(()),
+                        _ = time::sleep(WATCH_RETRY_DELAY) => continue,
+                    }
+                }
+
+                Some(Ok(service_event)) => service_event,
+            };
+
+            info!(?service_event, "get service change event");
+
+            let svc = match service_event {
+                ServiceEvent::Applied(svc) | ServiceEvent::Deleted(svc) => svc,
+
+                ServiceEvent::Restarted(services) => {
+                    service_health.ok();
+
+                    for svc in &services {
+                        send_annotated(&annotated_sender, svc);
+                    }
+
+                    continue;
+                }
+            };
+
+            if is_annotated(&svc) {
+                send_annotated(&annotated_sender, &svc);
+            }
+
+            let labels = match svc.metadata.labels {
+                Some(labels) if is_ddns_service(&svc) => labels,
+                _ => {
+                    service_health.ok();
+
+                    continue;
+                }
+            };
+
+            let result = self
+                .run_trigger(
+                    PendingTrigger::Service(labels),
+                    &retry_sender,
+                    &shutdown,
+                    &tracker,
+                )
+                .await;
+
+            match result {
+                Err(_) => service_health.failed(),
+                Ok(_) => service_health.ok(),
             }
         }
 
@@ -99,4 +258,145 @@ where
             "service change stream is dry, that should not happened"
         ))
     }
+
+    /// Run the trigger, it is retried after [`WATCH_RETRY_DELAY`] if it fails, so the event is
+    /// not lost when the API server is unavailable for a while
+    async fn run_trigger(
+        &self,
+        pending: PendingTrigger,
+        retry_sender: &UnboundedSender<PendingTrigger>,
+        shutdown: &CancellationToken,
+        tracker: &TaskTracker,
+    ) -> Result<(), kube::Error> {
+        let result = match &pending {
+            PendingTrigger::Service(labels) => self
+                .list_ddns(labels.clone())
+                .await
+                .map(|ddns_list| self.reconcile_ddns_list(ddns_list, tracker)),
+
+            PendingTrigger::Readiness(service) => {
+                self.trigger_service_readiness(service, tracker).await
+            }
+        };
+
+        if result.is_err() {
+            let retry_sender = retry_sender.clone();
+            let shutdown = shutdown.clone();
+
+            tracker.spawn(async move {
+                tokio::select! {
+                    _ = shutdown.cancelled() => {}
+
+                    _ = time::sleep(WATCH_RETRY_DELAY) => {
+                        // the receiver only stops when shutting down
+                        let _ = retry_sender.unbounded_send(pending);
+                    }
+                }
+            });
+        }
+
+        result
+    }
+
+    /// Reconcile the Ddns of the service whose readiness is changed, only the load balancer
+    /// services are cared like the service change stream
+    async fn trigger_service_readiness(
+        &self,
+        (namespace, name): &ServiceKey,
+        tracker: &TaskTracker,
+    ) -> Result<(), kube::Error> {
+        let svc_api = Api::<Service>::namespaced(self.client.clone(), namespace);
+
+        let svc = match svc_api.get_opt(name).await.tap_err(
+            |err| error!(%err, %namespace, %name, "get service of endpoint slice failed"),
+        )? {
+            None => return Ok(()),
+            Some(svc) => svc,
+        };
+
+        let is_lb_svc =
+            svc.spec.as_ref().and_then(|spec| spec.type_.as_deref()) == Some("LoadBalancer");
+
+        let labels = match svc.metadata.labels {
+            Some(labels) if is_lb_svc && !labels.is_empty() => labels,
+            _ => return Ok(()),
+        };
+
+        let ddns_list = self.list_ddns(labels).await?;
+        self.reconcile_ddns_list(ddns_list, tracker);
+
+        Ok(())
+    }
+
+    /// Reconcile the Ddns referring the changed provider, so the records are published with the
+    /// new settings. The Ddns are found in the store, so a provider event never lists them.
+    fn trigger_provider_change(&self, provider: &str, tracker: &TaskTracker) {
+        let ddns_list = self
+            .ddns_store
+            .state()
+            .into_iter()
+            .filter(|ddns| {
+                ddns.spec
+                    .provider_ref
+                    .as_ref()
+                    .is_some_and(|provider_ref| provider_ref.name == provider)
+            })
+            .map(|ddns| Ddns::clone(&ddns))
+            .collect();
+
+        self.reconcile_ddns_list(ddns_list, tracker);
+    }
+
+    /// List the Ddns which have all the labels of the service
+    // TODO remove it when we can use intersperse only with std lib
+    #[allow(unstable_name_collisions)]
+    async fn list_ddns(&self, labels: BTreeMap<String, String>) -> Result<Vec<Ddns>, kube::Error> {
+        let ddns_api = Api::<Ddns>::all(self.client.clone());
+
+        info!(?labels, "get labels map");
+
+        let labels = labels
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .intersperse(",".to_string())
+            .collect::<String>();
+
+        info!(%labels, "get filter labels");
+
+        let list_params = ListParams::default().labels(&labels);
+
+        let ddns_list = ddns_api
+            .list(&list_params)
+            .await
+            .tap_err(|err| error!(%err, "list ddns failed"))?;
+
+        Ok(ddns_list.items)
+    }
+
+    fn reconcile_ddns_list(&self, ddns_list: Vec<Ddns>, tracker: &TaskTracker) {
+        for ddns in ddns_list {
+            let reconciler = self.reconciler.clone();
+            let err_policy = self.err_policy.clone();
+
+            tracker.spawn(
+                async move {
+                    info!(?ddns, "start reconcile ddns");
+
+                    if let Err(err) = reconciler.reconcile_ddns(ddns.clone()).await {
+                        error!(%err, ?ddns, "reconcile failed");
+
+                        err_policy.error_policy(ddns.clone(), err).await;
+
+                        info!(?ddns, "run error policy done");
+                    }
+                }
+                .instrument(info_span!("reconcile ddns")),
+            );
+        }
+    }
+}
+
+fn send_annotated(sender: &UnboundedSender<ObjectRef<Service>>, svc: &Service) {
+    // the receiver only stops when shutting down
+    let _ = sender.unbounded_send(ObjectRef::from_obj(svc));
 }
@@ -1,11 +1,15 @@
 use futures_util::{future, Stream, TryStreamExt};
 use k8s_openapi::api::core::v1::Service;
 use kube::api::ListParams;
-use kube::runtime::watcher;
+use kube::runtime::reflector;
+use kube::runtime::reflector::store::Writer;
 use kube::runtime::watcher::{Error, Event};
 use kube::Api;
 use tracing::info;
 
+use crate::ddns::resync_watcher;
+use crate::service::annotation::is_annotated;
+
 /// The Service Changing event
 #[derive(Debug)]
 pub enum ServiceEvent {
@@ -14,57 +18,63 @@ pub enum ServiceEvent {
 
     /// A service is deleted
     Deleted(Service),
-}
 
-pub fn watch_service(api: Api<Service>) -> impl Stream<Item = Result<ServiceEvent, Error>> {
-    watcher(api, ListParams::default())
-        .try_filter_map(|event| {
-            // we only care the service changing, such as adding a new load balance service, or
-            // remove an exist load balance service
-            let service_event = match event {
-                Event::Applied(svc) => ServiceEvent::Applied(svc),
-                Event::Deleted(svc) => ServiceEvent::Deleted(svc),
+    /// The services are re-listed, only the annotated services are kept, the Ddns are all
+    /// reconciled when the controller starts. It is returned even without any annotated
+    /// service, so the watch is known to make progress
+    Restarted(Vec<Service>),
+}
 
-                _ => return future::ok(None),
-            };
+/// Whether the service may be selected by a Ddns
+pub fn is_ddns_service(svc: &Service) -> bool {
+    // we only care about the load balance service
+    let is_lb_svc = svc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.type_.as_ref())
+        .map(|svc_type| svc_type == "LoadBalancer")
+        .unwrap_or(false);
 
-            info!(?service_event, "get service change event");
+    // we only care about the load balance service who has labels, because ddns need it, if a
+    // service doesn't have labels, ddns won't work with it
+    let contain_labels = svc
+        .metadata
+        .labels
+        .as_ref()
+        .map(|labels| !labels.is_empty())
+        .unwrap_or(false);
 
-            future::ok(Some(service_event))
-        })
-        // we only care about the load balance service
-        .try_filter(|service_event| match service_event {
-            ServiceEvent::Applied(svc) | ServiceEvent::Deleted(svc) => {
-                let is_lb_svc = svc
-                    .spec
-                    .as_ref()
-                    .and_then(|spec| spec.type_.as_ref())
-                    .map(|svc_type| svc_type == "LoadBalancer")
-                    .unwrap_or(false);
+    is_lb_svc && contain_labels
+}
 
-                if is_lb_svc {
-                    info!(?svc, "get load balancer service");
-                }
+/// Watch the services, the store is updated before the event is returned by the stream
+pub fn watch_service(
+    api: Api<Service>,
+    store: Writer<Service>,
+) -> impl Stream<Item = Result<ServiceEvent, Error>> {
+    reflector(store, resync_watcher(api, ListParams::default())).try_filter_map(|event| {
+        // we only care the service changing, such as adding a new load balance service, or
+        // remove an exist load balance service, and the annotated services
+        let service_event = match event {
+            Event::Applied(svc) if is_ddns_service(&svc) || is_annotated(&svc) => {
+                ServiceEvent::Applied(svc)
+            }
 
-                future::ready(is_lb_svc)
+            Event::Deleted(svc) if is_ddns_service(&svc) || is_annotated(&svc) => {
+                ServiceEvent::Deleted(svc)
             }
-        })
-        // we only care about the load balance service who has labels, because ddns need it, if a
-        // service doesn't have labels, ddns won't work with it
-        .try_filter(|service_event| match service_event {
-            ServiceEvent::Applied(svc) | ServiceEvent::Deleted(svc) => {
-                let contain_labels = svc
-                    .metadata
-                    .labels
-                    .as_ref()
-                    .map(|labels| !labels.is_empty())
-                    .unwrap_or(false);
 
-                if contain_labels {
-                    info!(?svc, "get labels contained load balancer service");
-                }
+            Event::Restarted(mut services) => {
+                services.retain(is_annotated);
 
-                future::ready(contain_labels)
+                ServiceEvent::Restarted(services)
             }
-        })
+
+            _ => return future::ok(None),
+        };
+
+        info!(?service_event, "get service change event");
+
+        future::ok(Some(service_event))
+    })
 }
@@ -0,0 +1,110 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Cancel the token when receiving SIGTERM or SIGINT
+pub fn cancel_on_signal(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Err(err) => {
+                error!(%err, "listen SIGTERM failed");
+
+                return;
+            }
+
+            Ok(sigterm) => sigterm,
+        };
+
+        tokio::select! {
+            _ = sigterm.recv() => info!("receive SIGTERM, start to shutdown"),
+            _ = tokio::signal::ctrl_c() => info!("receive SIGINT, start to shutdown"),
+        }
+
+        shutdown.cancel();
+    });
+}
+
+/// Create a task tracker and the drain which waits all tracked tasks done
+pub fn task_tracker() -> (TaskTracker, TaskDrain) {
+    let (sender, receiver) = mpsc::channel(1);
+
+    (TaskTracker { _sender: sender }, TaskDrain { receiver })
+}
+
+/// Spawn tasks which should be finished before exiting
+#[derive(Debug, Clone)]
+pub struct TaskTracker {
+    // every tracked task holds a sender, the receiver will know all tasks are done when all
+    // senders are dropped
+    _sender: mpsc::Sender<()>,
+}
+
+impl TaskTracker {
+    pub fn spawn<F>(&self, fut: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let guard = self.clone();
+
+        tokio::spawn(async move {
+            let output = fut.await;
+
+            drop(guard);
+
+            output
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TaskDrain {
+    receiver: mpsc::Receiver<()>,
+}
+
+impl TaskDrain {
+    /// Wait all tracked tasks done, all [`TaskTracker`] should be dropped before calling it.
+    ///
+    /// Return false if some tasks are still running after timeout.
+    pub async fn drain(mut self, timeout: Duration) -> bool {
+        time::timeout(timeout, self.receiver.recv()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::oneshot;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_finished_tasks() {
+        let (tracker, drain) = task_tracker();
+
+        for secs in 1..=3 {
+            tracker.spawn(time::sleep(Duration::from_secs(secs)));
+        }
+
+        drop(tracker);
+
+        assert!(drain.drain(Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_timeout() {
+        let (tracker, drain) = task_tracker();
+        let (_sender, receiver) = oneshot::channel::<()>();
+
+        tracker.spawn(receiver);
+
+        drop(tracker);
+
+        assert!(!drain.drain(Duration::from_secs(5)).await);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::hostname::normalize_hostname;
+
+pub mod v2;
+
+#[derive(Debug, Clone, Serialize, Deserialize, CustomResource, PartialEq, JsonSchema, Default)]
+#[kube(
+    group = "api.sherlockholo.io",
+    version = "v1",
+    kind = "Ddns",
+    plural = "ddnss",
+    shortname = "dd",
+    status = "DdnsStatus",
+    namespaced,
+    derive = "Default",
+    derive = "PartialEq",
+    printcolumn = r#"{"name":"DOMAIN", "type":"string", "jsonPath":".spec.domain"}"#,
+    printcolumn = r#"{"name":"AGE", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#,
+    printcolumn = r#"{"name":"STATUS", "type":"string", "jsonPath":".status.status"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DdnsSpec {
+    pub selector: HashMap<String, String>,
+    pub domain: String,
+    pub zone: String,
+    /// The Secret in the same namespace which contains the cloudflare credentials, the
+    /// controller credentials are used if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_secret_ref: Option<SecretRef>,
+    /// The [`DnsProviderConfig`] which publishes the records, it can't be set with the
+    /// credentials_secret_ref
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_ref: Option<ProviderRef>,
+    /// Publish the SRV records of the named ports of the selected services, which point at the
+    /// domain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srv: Option<SrvSpec>,
+    /// Only publish the load balancer IPs of the services which have ready endpoints, the IPs of
+    /// all the services are published when none of them has
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ready_endpoints_only: bool,
+    /// Filter the load balancer IPs before they are published, the controller wide filter is
+    /// applied too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_filter: Option<IpFilterSpec>,
+    /// The static IPs published under the same hostname, like an on-premises fallback
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_addresses: Vec<String>,
+    /// How the additional addresses are combined with the load balancer IPs
+    #[serde(default, skip_serializing_if = "AddressMode::is_merge")]
+    pub address_mode: AddressMode,
+    /// Publish the first healthy tier of the ordered service selectors instead of the selector
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverSpec>,
+}
+
+/// The SRV record `_{port name}._{protocol}.{domain}` is published for every selected port,
+/// with priority 0 and weight 0
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SrvSpec {
+    /// The names of the service ports, the unnamed ports can't be selected
+    pub ports: Vec<String>,
+}
+
+/// A tier is healthy when any of its services has a load balancer IP and ready endpoints. The
+/// controller publishes the first healthy tier, and fails over to the next healthy tier at once
+/// when the active tier isn't healthy anymore. If no tier is healthy, the active tier is kept.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverSpec {
+    /// The service selectors ordered by the priority
+    pub tiers: Vec<FailoverTier>,
+    /// A healthy higher tier is only published again when the last switch is older than it, so
+    /// a flapping tier doesn't flap the records
+    #[serde(default = "default_failback_delay_seconds")]
+    pub failback_delay_seconds: u64,
+}
+
+fn default_failback_delay_seconds() -> u64 {
+    300
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverTier {
+    /// The name reported by the status
+    pub name: String,
+    /// Select the services matching any of the labels, like the selector
+    pub match_labels: HashMap<String, String>,
+}
+
+/// The published IPs of the different modes, the IP filters are applied to them at last
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum AddressMode {
+    /// The load balancer IPs and the additional addresses
+    #[default]
+    Merge,
+    /// Only the additional addresses, the load balancer IPs are ignored, such as during the
+    /// maintenance
+    Override,
+}
+
+impl AddressMode {
+    pub fn is_merge(&self) -> bool {
+        *self == AddressMode::Merge
+    }
+}
+
+/// An IP is published when it is in any allow CIDR, or the allow list is empty, and it is in no
+/// deny CIDR. The deny list always wins. When all the IPs are filtered out, the records are
+/// removed and the `Filtered` condition is set.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IpFilterSpec {
+    /// The CIDRs of the IPs which can be published, like `203.0.113.0/24` or `2001:db8::/32`
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// The CIDRs of the IPs which are never published
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Only publish the IPs which are reachable from the internet, the private, shared, loopback
+    /// and link local addresses are denied
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public_only: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretRef {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderRef {
+    pub name: String,
+}
+
+/// A dns backend which the Ddns can refer by name
+#[derive(Debug, Clone, Serialize, Deserialize, CustomResource, PartialEq, JsonSchema, Default)]
+#[kube(
+    group = "api.sherlockholo.io",
+    version = "v1",
+    kind = "DnsProviderConfig",
+    plural = "dnsproviderconfigs",
+    shortname = "dpc",
+    derive = "Default",
+    printcolumn = r#"{"name":"PROVIDER", "type":"string", "jsonPath":".spec.provider"}"#,
+    printcolumn = r#"{"name":"AGE", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsProviderConfigSpec {
+    pub provider: DnsProviderKind,
+    pub credentials_secret_ref: NamespacedSecretRef,
+    /// The ttl of the records, the controller default ttl is used if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_ttl: Option<u32>,
+    #[serde(default)]
+    pub proxied: bool,
+    /// The zones the Ddns can publish records in, empty means all zones
+    #[serde(default)]
+    pub allowed_zones: Vec<String>,
+    /// The namespaces of the Ddns which can refer this provider, empty means all namespaces
+    #[serde(default)]
+    pub allowed_namespaces: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum DnsProviderKind {
+    #[default]
+    Cloudflare,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespacedSecretRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl DnsProviderConfigSpec {
+    /// Return why the Ddns in the namespace can't publish records in the zone by this provider
+    pub fn check_allowed(&self, namespace: &str, zone: &str) -> Result<(), String> {
+        if !self.allowed_namespaces.is_empty()
+            && !self
+                .allowed_namespaces
+                .iter()
+                .any(|allowed| allowed == namespace)
+        {
+            return Err(format!("namespace {} is not allowed", namespace));
+        }
+
+        let zone = normalize_hostname(zone);
+
+        if !self.allowed_zones.is_empty()
+            && !self
+                .allowed_zones
+                .iter()
+                .any(|allowed| normalize_hostname(allowed) == zone)
+        {
+            return Err(format!("zone {} is not allowed", zone));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DdnsStatus {
+    pub status: String,
+    pub selector: HashMap<String, String>,
+    pub domain: String,
+    pub zone: String,
+    #[serde(default)]
+    pub conditions: Vec<DdnsCondition>,
+    /// The generation of the spec which the status is reconciled from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// The names of the published SRV records, the records which are not selected anymore are
+    /// removed by them
+    #[serde(default)]
+    pub srv_records: Vec<String>,
+    /// The load balancer IPs which are not published because of the IP filters
+    #[serde(default)]
+    pub filtered_ips: Vec<String>,
+    /// The name of the published failover tier
+    #[serde(default)]
+    pub active_tier: Option<String>,
+    /// RFC 3339 time when the active failover tier was switched
+    #[serde(default)]
+    pub last_tier_switch_time: Option<String>,
+    /// The credentials which the records are published with, the records are removed by them
+    #[serde(default)]
+    pub published_credentials: Option<PublishedCredentials>,
+}
+
+/// The credentials of the published records, neither of the refs means the controller
+/// credentials. The refs are always serialized, so the merge patch removes the unset one.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishedCredentials {
+    #[serde(default)]
+    pub credentials_secret_ref: Option<SecretRef>,
+    #[serde(default)]
+    pub provider_ref: Option<ProviderRef>,
+}
+
+impl From<&DdnsSpec> for PublishedCredentials {
+    fn from(spec: &DdnsSpec) -> Self {
+        Self {
+            credentials_secret_ref: spec.credentials_secret_ref.clone(),
+            provider_ref: spec.provider_ref.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DdnsCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// One of "True", "False" or "Unknown"
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    /// RFC 3339 time when the status of the condition changed
+    pub last_transition_time: String,
+}
+
+impl DdnsStatus {
+    pub fn to_patch_status(&self) -> PatchStatus {
+        self.clone().into()
+    }
+
+    pub fn remove_condition(&mut self, type_: &str) {
+        self.conditions.retain(|condition| condition.type_ != type_);
+    }
+
+    /// Insert or update the condition with the same type, the last transition time is only
+    /// updated when the condition status changes
+    pub fn set_condition(&mut self, type_: &str, status: bool, reason: &str, message: String) {
+        let status = if status { "True" } else { "False" }.to_string();
+
+        let last_transition_time = match self
+            .conditions
+            .iter()
+            .position(|condition| condition.type_ == type_)
+        {
+            Some(index) => {
+                let condition = self.conditions.remove(index);
+
+                if condition.status == status {
+                    condition.last_transition_time
+                } else {
+                    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+                }
+            }
+
+            None => Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+
+        self.conditions.push(DdnsCondition {
+            type_: type_.to_string(),
+            status,
+            reason: reason.to_string(),
+            message,
+            last_transition_time,
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchStatus {
+    status: DdnsStatus,
+}
+
+impl From<DdnsStatus> for PatchStatus {
+    fn from(status: DdnsStatus) -> Self {
+        Self { status }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_allowed() {
+        let spec = DnsProviderConfigSpec {
+            allowed_zones: vec!["Example.com.".to_string()],
+            allowed_namespaces: vec!["team-a".to_string()],
+            ..Default::default()
+        };
+
+        assert!(spec.check_allowed("team-a", "example.com").is_ok());
+        assert!(spec.check_allowed("team-b", "example.com").is_err());
+        assert!(spec.check_allowed("team-a", "example.org").is_err());
+
+        assert!(DnsProviderConfigSpec::default()
+            .check_allowed("team-b", "example.org")
+            .is_ok());
+    }
+}
@@ -0,0 +1,344 @@
+//! The `api.sherlockholo.io/v2` version of the Ddns.
+//!
+//! v1 is still the storage version, the api server converts the objects between the versions by
+//! the conversion webhook. The v2 fields which v1 can't hold are kept in the
+//! [`HOSTNAMES_ANNOTATION`] of the v1 object, so the conversion is lossless in both directions.
+
+use std::collections::HashMap;
+
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::spec::{
+    self as v1, AddressMode, DdnsStatus, FailoverSpec, IpFilterSpec, ProviderRef, SecretRef,
+    SrvSpec,
+};
+
+/// The JSON list of all v2 hostnames, it is only set on the v1 object when the v2 object
+/// doesn't have exactly one hostname
+pub const HOSTNAMES_ANNOTATION: &str = "api.sherlockholo.io/v2-hostnames";
+
+#[derive(Debug, Clone, Serialize, Deserialize, CustomResource, PartialEq, JsonSchema, Default)]
+#[kube(
+    group = "api.sherlockholo.io",
+    version = "v2",
+    kind = "Ddns",
+    plural = "ddnss",
+    shortname = "dd",
+    status = "DdnsStatus",
+    namespaced,
+    derive = "Default",
+    derive = "PartialEq",
+    printcolumn = r#"{"name":"HOSTNAMES", "type":"string", "jsonPath":".spec.hostnames"}"#,
+    printcolumn = r#"{"name":"AGE", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#,
+    printcolumn = r#"{"name":"STATUS", "type":"string", "jsonPath":".status.status"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DdnsSpec {
+    /// The hostnames which point to the load balancer IPs, exactly one hostname is supported for
+    /// now
+    pub hostnames: Vec<String>,
+    pub zone: String,
+    pub selector: ServiceSelector,
+    /// The Secret in the same namespace which contains the cloudflare credentials, the
+    /// controller credentials are used if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_secret_ref: Option<SecretRef>,
+    /// The DnsProviderConfig which publishes the records, it can't be set with the
+    /// credentialsSecretRef
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_ref: Option<ProviderRef>,
+    /// Publish the SRV records of the named ports of the selected services, which point at the
+    /// first hostname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srv: Option<SrvSpec>,
+    /// Only publish the load balancer IPs of the services which have ready endpoints, the IPs of
+    /// all the services are published when none of them has
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ready_endpoints_only: bool,
+    /// Filter the load balancer IPs before they are published, the controller wide filter is
+    /// applied too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_filter: Option<IpFilterSpec>,
+    /// The static IPs published under the same hostname, like an on-premises fallback
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_addresses: Vec<String>,
+    /// How the additional addresses are combined with the load balancer IPs
+    #[serde(default, skip_serializing_if = "AddressMode::is_merge")]
+    pub address_mode: AddressMode,
+    /// Publish the first healthy tier of the ordered service selectors instead of the
+    /// selector
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<FailoverSpec>,
+}
+
+/// Select the load balancer services of the Ddns
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceSelector {
+    /// Select the services matching any of the labels, like the v1 selector, a service with
+    /// only one of the labels is published too
+    #[serde(default)]
+    pub match_labels: HashMap<String, String>,
+}
+
+impl From<v1::Ddns> for Ddns {
+    fn from(ddns: v1::Ddns) -> Self {
+        let mut metadata = ddns.metadata;
+        let spec = ddns.spec;
+
+        let hostnames_annotation = metadata
+            .annotations
+            .as_mut()
+            .and_then(|annotations| annotations.remove(HOSTNAMES_ANNOTATION));
+
+        // the annotations are created by the conversion from v2
+        if hostnames_annotation.is_some()
+            && metadata
+                .annotations
+                .as_ref()
+                .is_some_and(|annotations| annotations.is_empty())
+        {
+            metadata.annotations = None;
+        }
+
+        let hostnames = hostnames_annotation
+            .and_then(|hostnames| serde_json::from_str::<Vec<String>>(&hostnames).ok())
+            // the domain may be changed by a v1 client, the annotation is stale then
+            .filter(|hostnames| {
+                hostnames.first().map(String::as_str).unwrap_or_default() == spec.domain
+            })
+            .unwrap_or_else(|| vec![spec.domain]);
+
+        let mut converted = Ddns::new(
+            "",
+            DdnsSpec {
+                hostnames,
+                zone: spec.zone,
+                selector: ServiceSelector {
+                    match_labels: spec.selector,
+                },
+                credentials_secret_ref: spec.credentials_secret_ref,
+                provider_ref: spec.provider_ref,
+                srv: spec.srv,
+                ready_endpoints_only: spec.ready_endpoints_only,
+                ip_filter: spec.ip_filter,
+                additional_addresses: spec.additional_addresses,
+                address_mode: spec.address_mode,
+                failover: spec.failover,
+            },
+        );
+        converted.metadata = metadata;
+        converted.status = ddns.status;
+
+        converted
+    }
+}
+
+impl From<Ddns> for v1::Ddns {
+    fn from(ddns: Ddns) -> Self {
+        let mut metadata = ddns.metadata;
+        let spec = ddns.spec;
+
+        if spec.hostnames.len() != 1 {
+            // a list of strings is always serializable
+            let hostnames = serde_json::to_string(&spec.hostnames).unwrap();
+
+            metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(HOSTNAMES_ANNOTATION.to_string(), hostnames);
+        }
+
+        let mut converted = v1::Ddns::new(
+            "",
+            v1::DdnsSpec {
+                selector: spec.selector.match_labels,
+                domain: spec.hostnames.into_iter().next().unwrap_or_default(),
+                zone: spec.zone,
+                credentials_secret_ref: spec.credentials_secret_ref,
+                provider_ref: spec.provider_ref,
+                srv: spec.srv,
+                ready_endpoints_only: spec.ready_endpoints_only,
+                ip_filter: spec.ip_filter,
+                additional_addresses: spec.additional_addresses,
+                address_mode: spec.address_mode,
+                failover: spec.failover,
+            },
+        );
+        converted.metadata = metadata;
+        converted.status = ddns.status;
+
+        converted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::spec::{DdnsCondition, FailoverTier, PublishedCredentials};
+
+    fn status() -> DdnsStatus {
+        DdnsStatus {
+            status: "RUNNING".to_string(),
+            selector: HashMap::from([("app".to_string(), "test".to_string())]),
+            domain: "www.example.com".to_string(),
+            zone: "example.com".to_string(),
+            conditions: vec![DdnsCondition {
+                type_: "Conflict".to_string(),
+                status: "False".to_string(),
+                reason: "DomainOwned".to_string(),
+                message: "domain www.example.com is owned by this Ddns".to_string(),
+                last_transition_time: "2022-04-01T00:00:00Z".to_string(),
+            }],
+            observed_generation: Some(3),
+            srv_records: vec!["_sip._udp.www.example.com".to_string()],
+            filtered_ips: vec!["10.0.0.1".to_string()],
+            active_tier: Some("primary".to_string()),
+            last_tier_switch_time: Some("2022-04-01T00:00:00Z".to_string()),
+            published_credentials: Some(PublishedCredentials {
+                credentials_secret_ref: Some(SecretRef {
+                    name: "cloudflare".to_string(),
+                }),
+                provider_ref: None,
+            }),
+        }
+    }
+
+    fn v1_ddns() -> v1::Ddns {
+        let mut ddns = v1::Ddns::new(
+            "test",
+            v1::DdnsSpec {
+                selector: HashMap::from([("app".to_string(), "test".to_string())]),
+                domain: "www.example.com".to_string(),
+                zone: "example.com".to_string(),
+                credentials_secret_ref: Some(SecretRef {
+                    name: "cloudflare".to_string(),
+                }),
+                provider_ref: Some(ProviderRef {
+                    name: "cloudflare".to_string(),
+                }),
+                srv: Some(SrvSpec {
+                    ports: vec!["sip".to_string()],
+                }),
+                ready_endpoints_only: true,
+                ip_filter: Some(IpFilterSpec {
+                    allow: vec![],
+                    deny: vec!["10.0.0.0/8".to_string()],
+                    public_only: true,
+                }),
+                additional_addresses: vec!["203.0.113.7".to_string()],
+                address_mode: AddressMode::Override,
+                failover: Some(FailoverSpec {
+                    tiers: vec![FailoverTier {
+                        name: "primary".to_string(),
+                        match_labels: HashMap::from([("site".to_string(), "a".to_string())]),
+                    }],
+                    failback_delay_seconds: 60,
+                }),
+            },
+        );
+        ddns.metadata.namespace = Some("default".to_string());
+        ddns.metadata.annotations = Some(BTreeMap::from([(
+            "note".to_string(),
+            "keep me".to_string(),
+        )]));
+        ddns.metadata.generation = Some(3);
+        ddns.status = Some(status());
+
+        ddns
+    }
+
+    fn v2_ddns(hostnames: &[&str]) -> Ddns {
+        let mut ddns = Ddns::new(
+            "test",
+            DdnsSpec {
+                hostnames: hostnames
+                    .iter()
+                    .map(|hostname| hostname.to_string())
+                    .collect(),
+                zone: "example.com".to_string(),
+                selector: ServiceSelector {
+                    match_labels: HashMap::from([("app".to_string(), "test".to_string())]),
+                },
+                credentials_secret_ref: Some(SecretRef {
+                    name: "cloudflare".to_string(),
+                }),
+                provider_ref: Some(ProviderRef {
+                    name: "cloudflare".to_string(),
+                }),
+                srv: Some(SrvSpec {
+                    ports: vec!["sip".to_string()],
+                }),
+                ready_endpoints_only: true,
+                ip_filter: Some(IpFilterSpec {
+                    allow: vec![],
+                    deny: vec!["10.0.0.0/8".to_string()],
+                    public_only: true,
+                }),
+                additional_addresses: vec!["203.0.113.7".to_string()],
+                address_mode: AddressMode::Override,
+                failover: Some(FailoverSpec {
+                    tiers: vec![FailoverTier {
+                        name: "primary".to_string(),
+                        match_labels: HashMap::from([("site".to_string(), "a".to_string())]),
+                    }],
+                    failback_delay_seconds: 60,
+                }),
+            },
+        );
+        ddns.metadata.namespace = Some("default".to_string());
+        ddns.metadata.generation = Some(3);
+        ddns.status = Some(status());
+
+        ddns
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let ddns = v1_ddns();
+
+        let converted = Ddns::from(ddns.clone());
+        assert_eq!(converted.spec.hostnames, ["www.example.com"]);
+        assert_eq!(converted.spec.selector.match_labels, ddns.spec.selector);
+        assert_eq!(converted.metadata, ddns.metadata);
+        assert_eq!(converted.status, ddns.status);
+
+        assert_eq!(v1::Ddns::from(converted), ddns);
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        for hostnames in [
+            &["www.example.com"][..],
+            &["www.example.com", "api.example.com"],
+            &[],
+            &[""],
+        ] {
+            let ddns = v2_ddns(hostnames);
+
+            let converted = v1::Ddns::from(ddns.clone());
+            assert_eq!(
+                converted.spec.domain,
+                hostnames.first().copied().unwrap_or_default()
+            );
+
+            assert_eq!(Ddns::from(converted), ddns, "hostnames {:?}", hostnames);
+        }
+    }
+
+    #[test]
+    fn ignore_stale_hostnames_annotation() {
+        let mut ddns = v1::Ddns::from(v2_ddns(&["www.example.com", "api.example.com"]));
+
+        // changed by a v1 client which doesn't know the annotation
+        ddns.spec.domain = "web.example.com".to_string();
+
+        let converted = Ddns::from(ddns);
+        assert_eq!(converted.spec.hostnames, ["web.example.com"]);
+        assert!(converted.metadata.annotations.is_none());
+    }
+}
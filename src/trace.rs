@@ -1,40 +1,152 @@
-use std::env;
-
 use anyhow::Result;
-use opentelemetry::global;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 
-pub fn init_tracing() -> Result<TracingStop> {
-    global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
-
-    let agent_addr = env::var("JAEGER_AGENT").unwrap_or_else(|_| "127.0.0.1:6831".to_string());
-
-    let tracer = opentelemetry_jaeger::new_pipeline()
-        .with_service_name("ddns")
-        .with_agent_endpoint(agent_addr)
-        .install_simple()?;
+use crate::config::{LogFormat, TelemetryConfig, TraceExporter};
 
-    let stderr_subscriber = tracing_subscriber::fmt::layer().pretty().with_target(true);
+/// Install the global subscriber, the traces are exported by the configured exporter in
+/// batches, the returned [`TracingStop`] flushes them when dropped
+pub fn init_tracing(config: &TelemetryConfig) -> Result<TracingStop> {
     let env_filter = tracing_subscriber::filter::EnvFilter::from_default_env();
 
-    let trace = tracing_opentelemetry::layer().with_tracer(tracer);
+    let (pretty_layer, json_layer) = match config.log_format {
+        LogFormat::Pretty => (
+            Some(tracing_subscriber::fmt::layer().pretty().with_target(true)),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(tracing_subscriber::fmt::layer().json().with_target(true)),
+        ),
+    };
+
     let subscriber = Registry::default()
         .with(env_filter)
-        .with(trace)
-        .with(stderr_subscriber);
+        .with(otel::layer(config)?)
+        .with(pretty_layer)
+        .with(json_layer);
 
     tracing::subscriber::set_global_default(subscriber)?;
 
-    Ok(TracingStop { _priv: () })
+    Ok(TracingStop {
+        exporting: !matches!(config.exporter, TraceExporter::None),
+    })
 }
 
 pub struct TracingStop {
-    _priv: (),
+    exporting: bool,
 }
 
 impl Drop for TracingStop {
     fn drop(&mut self) {
+        if self.exporting {
+            otel::shutdown();
+        }
+    }
+}
+
+#[cfg(any(feature = "jaeger", feature = "otlp"))]
+mod otel {
+    use anyhow::Result;
+    use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+    use opentelemetry::sdk::Resource;
+    use opentelemetry::{global, KeyValue};
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    use crate::config::{TelemetryConfig, TraceExporter};
+
+    const SERVICE_NAME: &str = "ddns";
+
+    pub fn layer<S>(config: &TelemetryConfig) -> Result<Option<OpenTelemetryLayer<S, Tracer>>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let trace_config = trace::config()
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sample_ratio,
+            ))))
+            .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]));
+
+        let tracer = match &config.exporter {
+            TraceExporter::None => return Ok(None),
+
+            TraceExporter::Jaeger { agent } => jaeger_tracer(agent, trace_config)?,
+
+            TraceExporter::OtlpGrpc { endpoint } => otlp_tracer(endpoint, false, trace_config)?,
+
+            TraceExporter::OtlpHttp { endpoint } => otlp_tracer(endpoint, true, trace_config)?,
+        };
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    pub fn shutdown() {
         global::shutdown_tracer_provider();
     }
+
+    #[cfg(feature = "jaeger")]
+    fn jaeger_tracer(agent: &str, trace_config: trace::Config) -> Result<Tracer> {
+        global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
+
+        Ok(opentelemetry_jaeger::new_pipeline()
+            .with_service_name(SERVICE_NAME)
+            .with_agent_endpoint(agent)
+            .with_trace_config(trace_config)
+            .install_batch(opentelemetry::runtime::TokioCurrentThread)?)
+    }
+
+    #[cfg(not(feature = "jaeger"))]
+    fn jaeger_tracer(_agent: &str, _trace_config: trace::Config) -> Result<Tracer> {
+        Err(anyhow::anyhow!("ddns is built without the jaeger feature"))
+    }
+
+    #[cfg(feature = "otlp")]
+    fn otlp_tracer(endpoint: &str, http: bool, trace_config: trace::Config) -> Result<Tracer> {
+        use opentelemetry::sdk::propagation::TraceContextPropagator;
+        use opentelemetry_otlp::WithExportConfig;
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let pipeline = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_trace_config(trace_config);
+
+        let pipeline = if http {
+            pipeline.with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(endpoint),
+            )
+        } else {
+            pipeline.with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+        };
+
+        Ok(pipeline.install_batch(opentelemetry::runtime::TokioCurrentThread)?)
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    fn otlp_tracer(_endpoint: &str, _http: bool, _trace_config: trace::Config) -> Result<Tracer> {
+        Err(anyhow::anyhow!("ddns is built without the otlp feature"))
+    }
+}
+
+/// Without the OpenTelemetry stack only the logs are written
+#[cfg(not(any(feature = "jaeger", feature = "otlp")))]
+mod otel {
+    use anyhow::Result;
+    use tracing_subscriber::layer::Identity;
+
+    use crate::config::TelemetryConfig;
+
+    pub fn layer(_config: &TelemetryConfig) -> Result<Option<Identity>> {
+        Ok(None)
+    }
+
+    pub fn shutdown() {}
 }
@@ -0,0 +1,216 @@
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::spec::{self, v2};
+
+const V1_API_VERSION: &str = "api.sherlockholo.io/v1";
+const V2_API_VERSION: &str = "api.sherlockholo.io/v2";
+
+/// The `apiextensions.k8s.io/v1` ConversionReview, the api server sends the request and expects
+/// the response with the same uid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<ConversionRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<ConversionResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionRequest {
+    pub uid: String,
+    #[serde(rename = "desiredAPIVersion")]
+    pub desired_api_version: String,
+    pub objects: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionResponse {
+    pub uid: String,
+    pub converted_objects: Vec<Value>,
+    pub result: Status,
+}
+
+/// Convert the objects in the review request, the response fails if any object can't be
+/// converted
+pub fn convert_review(review: ConversionReview) -> ConversionReview {
+    let request = match review.request {
+        None => {
+            warn!("conversion review has no request");
+
+            return invalid_review("missing request");
+        }
+
+        Some(request) => request,
+    };
+
+    let desired_api_version = request.desired_api_version;
+
+    let converted_objects = request
+        .objects
+        .into_iter()
+        .map(|object| convert_object(object, &desired_api_version))
+        .collect::<Result<Vec<_>>>();
+
+    match &converted_objects {
+        Err(err) => warn!(uid = %request.uid, %desired_api_version, %err, "convert ddns failed"),
+        Ok(objects) => info!(
+            uid = %request.uid,
+            %desired_api_version,
+            count = objects.len(),
+            "convert ddns done"
+        ),
+    }
+
+    into_review(request.uid, converted_objects)
+}
+
+/// The failed review of a request which can't be decoded, the api server rejects it because the
+/// uid doesn't match
+pub fn invalid_review(err: impl Display) -> ConversionReview {
+    into_review(
+        String::new(),
+        Err(anyhow::anyhow!("invalid review: {}", err)),
+    )
+}
+
+fn into_review(uid: String, converted_objects: Result<Vec<Value>>) -> ConversionReview {
+    let response = match converted_objects {
+        Err(err) => ConversionResponse {
+            uid,
+            converted_objects: vec![],
+            result: Status {
+                status: Some("Failure".to_string()),
+                message: Some(format!("{:#}", err)),
+                ..Default::default()
+            },
+        },
+
+        Ok(converted_objects) => ConversionResponse {
+            uid,
+            converted_objects,
+            result: Status {
+                status: Some("Success".to_string()),
+                ..Default::default()
+            },
+        },
+    };
+
+    ConversionReview {
+        api_version: "apiextensions.k8s.io/v1".to_string(),
+        kind: "ConversionReview".to_string(),
+        request: None,
+        response: Some(response),
+    }
+}
+
+fn convert_object(object: Value, desired_api_version: &str) -> Result<Value> {
+    let api_version = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .context("object has no apiVersion")?;
+
+    let converted = match (api_version, desired_api_version) {
+        (from, to) if from == to => return Ok(object),
+
+        (V1_API_VERSION, V2_API_VERSION) => {
+            let ddns: spec::Ddns = serde_json::from_value(object).context("decode v1 ddns")?;
+
+            serde_json::to_value(v2::Ddns::from(ddns))?
+        }
+
+        (V2_API_VERSION, V1_API_VERSION) => {
+            let ddns: v2::Ddns = serde_json::from_value(object).context("decode v2 ddns")?;
+
+            serde_json::to_value(spec::Ddns::from(ddns))?
+        }
+
+        (from, to) => return Err(anyhow::anyhow!("can't convert {} to {}", from, to)),
+    };
+
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn conversion_review(desired_api_version: &str, objects: Vec<Value>) -> ConversionReview {
+        serde_json::from_value(json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "ConversionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "desiredAPIVersion": desired_api_version,
+                "objects": objects
+            }
+        }))
+        .unwrap()
+    }
+
+    fn v1_object() -> Value {
+        json!({
+            "apiVersion": V1_API_VERSION,
+            "kind": "Ddns",
+            "metadata": {"name": "test", "namespace": "default", "resourceVersion": "1"},
+            "spec": {
+                "selector": {"app": "test"},
+                "domain": "www.example.com",
+                "zone": "example.com"
+            }
+        })
+    }
+
+    #[test]
+    fn convert_between_versions() {
+        let review = convert_review(conversion_review(V2_API_VERSION, vec![v1_object()]));
+        let response = review.response.unwrap();
+
+        assert_eq!(response.uid, "705ab4f5-6393-11e8-b7cc-42010a800002");
+        assert_eq!(response.result.status.as_deref(), Some("Success"));
+        assert_eq!(
+            response.converted_objects,
+            [json!({
+                "apiVersion": V2_API_VERSION,
+                "kind": "Ddns",
+                "metadata": {"name": "test", "namespace": "default", "resourceVersion": "1"},
+                "spec": {
+                    "hostnames": ["www.example.com"],
+                    "zone": "example.com",
+                    "selector": {"matchLabels": {"app": "test"}}
+                }
+            })]
+        );
+
+        let review = convert_review(conversion_review(
+            V1_API_VERSION,
+            response.converted_objects,
+        ));
+
+        assert_eq!(review.response.unwrap().converted_objects, [v1_object()]);
+    }
+
+    #[test]
+    fn fail_unknown_version() {
+        let review = convert_review(conversion_review(
+            "api.sherlockholo.io/v3",
+            vec![v1_object()],
+        ));
+        let response = review.response.unwrap();
+
+        assert_eq!(response.result.status.as_deref(), Some("Failure"));
+        assert!(response.converted_objects.is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+pub use server::{serve, CONVERT_PATH};
+
+mod convert;
+mod server;
+mod validate;
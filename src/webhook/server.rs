@@ -0,0 +1,312 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use anyhow::Result;
+use http::{Method, Request, Response, StatusCode};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::Body;
+use kube::api::ListParams;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation};
+use kube::core::DynamicObject;
+use kube::{Api, Client};
+use tap::TapFallible;
+use tokio::fs;
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::config::WebhookConfig;
+use crate::spec::Ddns;
+use crate::webhook::convert::{convert_review, invalid_review, ConversionReview};
+use crate::webhook::validate::validate_ddns;
+
+const VALIDATE_PATH: &str = "/validate";
+pub const CONVERT_PATH: &str = "/convert";
+
+/// Serve the admission and conversion webhooks over HTTPS until an accept error happened or the shutdown is
+/// cancelled
+pub async fn serve(
+    config: WebhookConfig,
+    client: Client,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let cert = fs::read(&config.cert_path).await?;
+    let key = fs::read(&config.key_path).await?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+    let acceptor = Arc::new(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?));
+
+    let listener = TcpListener::bind(config.addr).await?;
+
+    info!(addr = %config.addr, "webhook server start");
+
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            _ = shutdown.cancelled() => {
+                info!("shutdown, stop webhook server");
+
+                return Ok(());
+            }
+
+            accepted = listener.accept() => accepted
+                .tap_err(|err| error!(%err, "accept webhook connection failed"))?,
+        };
+
+        let acceptor = acceptor.clone();
+        let client = client.clone();
+
+        tokio::spawn(
+            async move {
+                let stream = match acceptor.accept(stream).await {
+                    Err(err) => {
+                        warn!(%err, "webhook tls handshake failed");
+
+                        return;
+                    }
+
+                    Ok(stream) => stream,
+                };
+
+                let service = service_fn(move |req| handle(req, client.clone()));
+
+                if let Err(err) = Http::new().serve_connection(stream, service).await {
+                    warn!(%err, "serve webhook connection failed");
+                }
+            }
+            .instrument(info_span!("webhook connection", %peer_addr)),
+        );
+    }
+}
+
+async fn handle(req: Request<Body>, client: Client) -> Result<Response<Body>, Infallible> {
+    let body = match (req.method(), req.uri().path()) {
+        (&Method::POST, VALIDATE_PATH) => validate(req, client).await,
+        (&Method::POST, CONVERT_PATH) => convert(req).await,
+
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap())
+        }
+    };
+
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+async fn validate(req: Request<Body>, client: Client) -> Vec<u8> {
+    let review = match hyper::body::to_bytes(req.into_body()).await {
+        Err(err) => AdmissionResponse::invalid(err).into_review(),
+
+        Ok(body) => match serde_json::from_slice::<AdmissionReview<Ddns>>(&body) {
+            Err(err) => {
+                warn!(%err, "decode admission review failed");
+
+                AdmissionResponse::invalid(err).into_review()
+            }
+
+            Ok(review) => validate_review(review, &Api::all(client)).await,
+        },
+    };
+
+    serde_json::to_vec(&review).expect("admission review must be serializable")
+}
+
+async fn convert(req: Request<Body>) -> Vec<u8> {
+    let review = match hyper::body::to_bytes(req.into_body()).await {
+        Err(err) => invalid_review(err),
+
+        Ok(body) => match serde_json::from_slice::<ConversionReview>(&body) {
+            Err(err) => {
+                warn!(%err, "decode conversion review failed");
+
+                invalid_review(err)
+            }
+
+            Ok(review) => convert_review(review),
+        },
+    };
+
+    serde_json::to_vec(&review).expect("conversion review must be serializable")
+}
+
+async fn validate_review(
+    review: AdmissionReview<Ddns>,
+    ddns_api: &Api<Ddns>,
+) -> AdmissionReview<DynamicObject> {
+    let req: AdmissionRequest<Ddns> = match review.try_into() {
+        Err(err) => return AdmissionResponse::invalid(err).into_review(),
+        Ok(req) => req,
+    };
+
+    if !matches!(req.operation, Operation::Create | Operation::Update) {
+        return AdmissionResponse::from(&req).into_review();
+    }
+
+    let exist_ddns_list = match ddns_api.list(&ListParams::default()).await {
+        Err(err) => {
+            error!(%err, "list ddns for admission review failed");
+
+            return AdmissionResponse::from(&req)
+                .deny(format!("list exist ddns failed: {}", err))
+                .into_review();
+        }
+
+        Ok(ddns_list) => ddns_list.items,
+    };
+
+    review_request(&req, &exist_ddns_list)
+}
+
+/// Build the admission response of the request, exist_ddns_list should contain all Ddns in the
+/// cluster
+fn review_request(
+    req: &AdmissionRequest<Ddns>,
+    exist_ddns_list: &[Ddns],
+) -> AdmissionReview<DynamicObject> {
+    let resp = AdmissionResponse::from(req);
+
+    let ddns = match (&req.operation, &req.object) {
+        (Operation::Create | Operation::Update, Some(ddns)) => ddns,
+        _ => return resp.into_review(),
+    };
+
+    let mut ddns = ddns.clone();
+
+    // the object may not contain namespace when creating
+    if ddns.metadata.namespace.is_none() {
+        ddns.metadata.namespace = req.namespace.clone();
+    }
+
+    let violations = validate_ddns(&ddns, exist_ddns_list);
+    if violations.is_empty() {
+        info!(uid = %req.uid, name = %req.name, "admit ddns");
+
+        return resp.into_review();
+    }
+
+    info!(uid = %req.uid, name = %req.name, ?violations, "deny ddns");
+
+    resp.deny(violations.join("; ")).into_review()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::spec::DdnsSpec;
+
+    fn admission_review(operation: &str, namespace: &str, object: Value) -> AdmissionReview<Ddns> {
+        serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": {"group": "api.sherlockholo.io", "version": "v1", "kind": "Ddns"},
+                "resource": {"group": "api.sherlockholo.io", "version": "v1", "resource": "ddnss"},
+                "name": "test",
+                "namespace": namespace,
+                "operation": operation,
+                "userInfo": {"username": "admin"},
+                "object": object,
+                "oldObject": null,
+                "dryRun": false
+            }
+        }))
+        .unwrap()
+    }
+
+    fn ddns_object(domain: &str, zone: &str) -> Value {
+        json!({
+            "apiVersion": "api.sherlockholo.io/v1",
+            "kind": "Ddns",
+            "metadata": {"name": "test"},
+            "spec": {
+                "selector": {"app": "test"},
+                "domain": domain,
+                "zone": zone
+            }
+        })
+    }
+
+    fn response(review: AdmissionReview<DynamicObject>) -> AdmissionResponse {
+        review.response.unwrap()
+    }
+
+    fn review(review: AdmissionReview<Ddns>, exist_ddns_list: &[Ddns]) -> AdmissionResponse {
+        let req: AdmissionRequest<Ddns> = review.try_into().unwrap();
+
+        response(review_request(&req, exist_ddns_list))
+    }
+
+    #[test]
+    fn allow_valid_ddns() {
+        let resp = review(
+            admission_review(
+                "CREATE",
+                "default",
+                ddns_object("www.example.com", "example.com"),
+            ),
+            &[],
+        );
+
+        assert!(resp.allowed);
+        assert_eq!(resp.uid, "705ab4f5-6393-11e8-b7cc-42010a800002");
+    }
+
+    #[test]
+    fn deny_invalid_ddns() {
+        let resp = review(
+            admission_review(
+                "UPDATE",
+                "default",
+                ddns_object("www.other.com", "example.com"),
+            ),
+            &[],
+        );
+
+        assert!(!resp.allowed);
+        assert!(resp.result.message.unwrap().contains("is not in zone"));
+    }
+
+    #[test]
+    fn deny_hostname_owned_by_other_namespace() {
+        let mut owner = Ddns::new(
+            "owner",
+            DdnsSpec {
+                selector: HashMap::from([("app".to_string(), "owner".to_string())]),
+                domain: "www.example.com".to_string(),
+                zone: "example.com".to_string(),
+                ..Default::default()
+            },
+        );
+        owner.metadata.namespace.replace("team-b".to_string());
+
+        let resp = review(
+            admission_review(
+                "CREATE",
+                "team-a",
+                ddns_object("www.example.com", "example.com"),
+            ),
+            &[owner],
+        );
+
+        assert!(!resp.allowed);
+        assert!(resp.result.message.unwrap().contains("team-b/owner"));
+    }
+
+    #[test]
+    fn allow_delete() {
+        let resp = review(admission_review("DELETE", "default", Value::Null), &[]);
+
+        assert!(resp.allowed);
+    }
+}
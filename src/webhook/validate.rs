@@ -0,0 +1,411 @@
+use crate::ddns::parse_addresses;
+use crate::hostname::{check_hostname, is_in_zone, normalize_hostname};
+use crate::ip_filter::IpFilter;
+use crate::spec::{v2, AddressMode, Ddns};
+
+/// Validate the Ddns against the rules which the reconciler relies on, the exist_ddns_list is
+/// used to find the hostname conflict with other namespaces.
+///
+/// Return all violations, an empty list means the Ddns is valid.
+pub fn validate_ddns(ddns: &Ddns, exist_ddns_list: &[Ddns]) -> Vec<String> {
+    let spec = &ddns.spec;
+    let mut violations = vec![];
+
+    // a v2 object with more hostnames is converted with the hostnames annotation, only the first
+    // hostname would be published
+    let hostnames = v2::Ddns::from(ddns.clone()).spec.hostnames;
+    if hostnames.len() != 1 {
+        violations.push(format!(
+            "hostnames {:?} are not supported, exactly one hostname is required",
+            hostnames
+        ));
+    }
+
+    if let Err(reason) = check_hostname(&spec.zone, false) {
+        violations.push(format!("zone {:?} is invalid: {}", spec.zone, reason));
+    }
+
+    if let Err(reason) = check_hostname(&spec.domain, true) {
+        violations.push(format!("domain {:?} is invalid: {}", spec.domain, reason));
+    } else if !is_in_zone(&spec.domain, &spec.zone) {
+        violations.push(format!(
+            "domain {:?} is not in zone {:?}",
+            spec.domain, spec.zone
+        ));
+    }
+
+    // the failover tiers select the services instead
+    if spec.selector.is_empty() && spec.failover.is_none() {
+        violations.push("selector is empty, it can't match any service".to_string());
+    }
+
+    if spec.selector.keys().any(|key| key.is_empty()) {
+        violations.push("selector contains an empty label key".to_string());
+    }
+
+    if let Some(secret_ref) = &spec.credentials_secret_ref {
+        if secret_ref.name.is_empty() {
+            violations.push("credentialsSecretRef name is empty".to_string());
+        }
+    }
+
+    if let Some(provider_ref) = &spec.provider_ref {
+        if provider_ref.name.is_empty() {
+            violations.push("providerRef name is empty".to_string());
+        }
+
+        if spec.credentials_secret_ref.is_some() {
+            violations
+                .push("providerRef and credentialsSecretRef can't be set together".to_string());
+        }
+    }
+
+    if let Some(srv) = &spec.srv {
+        if spec.domain.starts_with('*') {
+            violations.push("srv records can't point to a wildcard domain".to_string());
+        }
+
+        for port in &srv.ports {
+            if let Err(reason) = check_port_name(port) {
+                violations.push(format!("srv port {:?} is invalid: {}", port, reason));
+            }
+        }
+    }
+
+    if let Some(Err(reason)) = spec.ip_filter.as_ref().map(IpFilter::try_from) {
+        violations.push(format!("ipFilter is invalid: {}", reason));
+    }
+
+    if let Some(failover) = &spec.failover {
+        if failover.tiers.is_empty() {
+            violations.push("failover tiers are empty".to_string());
+        }
+
+        for (index, tier) in failover.tiers.iter().enumerate() {
+            if tier.name.is_empty() {
+                violations.push(format!("failover tier {} name is empty", index));
+            } else if failover.tiers[..index]
+                .iter()
+                .any(|other| other.name == tier.name)
+            {
+                violations.push(format!("failover tier name {:?} is duplicated", tier.name));
+            }
+
+            if tier.match_labels.is_empty() {
+                violations.push(format!(
+                    "failover tier {:?} matchLabels is empty, it can't match any service",
+                    tier.name
+                ));
+            }
+        }
+    }
+
+    if let Err(reason) = parse_addresses(&spec.additional_addresses) {
+        violations.push(format!("additionalAddresses is invalid: {}", reason));
+    }
+
+    if spec.address_mode == AddressMode::Override && spec.additional_addresses.is_empty() {
+        violations.push("override address mode needs additionalAddresses".to_string());
+    }
+
+    let domain = normalize_hostname(&spec.domain);
+
+    if let Some(owner) = exist_ddns_list.iter().find(|exist_ddns| {
+        exist_ddns.metadata.namespace != ddns.metadata.namespace
+            && exist_ddns.metadata.deletion_timestamp.is_none()
+            && normalize_hostname(&exist_ddns.spec.domain) == domain
+    }) {
+        violations.push(format!(
+            "domain {:?} is already owned by Ddns {}/{}",
+            spec.domain,
+            owner.metadata.namespace.as_deref().unwrap_or_default(),
+            owner.metadata.name.as_deref().unwrap_or_default()
+        ));
+    }
+
+    violations
+}
+
+/// Check the port name like kubernetes does for the named service port, it becomes the service
+/// label of the SRV record
+fn check_port_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > 15 {
+        return Err("length must be 1 to 15");
+    }
+
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    {
+        return Err("only lowercase letters, digits and '-' are allowed");
+    }
+
+    if name.starts_with('-') || name.ends_with('-') {
+        return Err("must not start or end with '-'");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::spec::v2::ServiceSelector;
+    use crate::spec::{
+        DdnsSpec, FailoverSpec, FailoverTier, IpFilterSpec, ProviderRef, SecretRef, SrvSpec,
+    };
+
+    fn ddns(namespace: &str, name: &str, domain: &str, zone: &str) -> Ddns {
+        let mut ddns = Ddns::new(
+            name,
+            DdnsSpec {
+                selector: HashMap::from([("app".to_string(), "test".to_string())]),
+                domain: domain.to_string(),
+                zone: zone.to_string(),
+                ..Default::default()
+            },
+        );
+        ddns.metadata.namespace.replace(namespace.to_string());
+
+        ddns
+    }
+
+    #[test]
+    fn valid_ddns() {
+        let ddns = ddns("default", "test", "www.example.com", "example.com");
+
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+    }
+
+    #[test]
+    fn zone_apex_and_wildcard() {
+        let apex = ddns("default", "test", "Example.com.", "example.com");
+        assert!(validate_ddns(&apex, &[]).is_empty());
+
+        let wildcard = ddns("default", "test", "*.example.com", "example.com");
+        assert!(validate_ddns(&wildcard, &[]).is_empty());
+
+        let wildcard_zone = ddns("default", "test", "*.example.com", "*.example.com");
+        assert_eq!(validate_ddns(&wildcard_zone, &[]).len(), 1);
+    }
+
+    #[test]
+    fn domain_outside_zone() {
+        let ddns = ddns("default", "test", "www.notexample.com", "example.com");
+
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("is not in zone"));
+    }
+
+    #[test]
+    fn malformed_hostname() {
+        for domain in [
+            "",
+            "www..example.com",
+            "-www.example.com",
+            "www_1.example.com",
+            "a.*.example.com",
+        ] {
+            let ddns = ddns("default", "test", domain, "example.com");
+
+            let violations = validate_ddns(&ddns, &[]);
+            assert_eq!(violations.len(), 1, "{}: {:?}", domain, violations);
+            assert!(violations[0].starts_with("domain"));
+        }
+
+        let long_label = format!("{}.example.com", "a".repeat(64));
+        let ddns = ddns("default", "test", &long_label, "example.com");
+        assert_eq!(validate_ddns(&ddns, &[]).len(), 1);
+    }
+
+    #[test]
+    fn empty_selector() {
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.selector.clear();
+
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("selector"));
+    }
+
+    #[test]
+    fn empty_credentials_secret_name() {
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.credentials_secret_ref = Some(SecretRef::default());
+
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("credentialsSecretRef"));
+    }
+
+    #[test]
+    fn provider_with_credentials_secret() {
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.provider_ref = Some(ProviderRef {
+            name: "cloudflare".to_string(),
+        });
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        ddns.spec.credentials_secret_ref = Some(SecretRef {
+            name: "cloudflare".to_string(),
+        });
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("can't be set together"));
+    }
+
+    #[test]
+    fn hostname_owned_by_other_namespace() {
+        let ddns = ddns("team-a", "test", "www.example.com", "example.com");
+
+        let same_namespace = self::ddns("team-a", "other", "www.example.com", "example.com");
+        assert!(validate_ddns(&ddns, &[same_namespace]).is_empty());
+
+        let other_namespace = self::ddns("team-b", "owner", "WWW.example.com", "example.com");
+        let violations = validate_ddns(&ddns, &[other_namespace]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("team-b/owner"));
+    }
+
+    #[test]
+    fn srv_port_names() {
+        let mut ddns = ddns("default", "test", "sip.example.com", "example.com");
+        ddns.spec.srv = Some(SrvSpec {
+            ports: vec!["sip".to_string(), "web-2".to_string()],
+        });
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        for port in [
+            "",
+            "SIP",
+            "-sip",
+            "sip-",
+            "sip_tls",
+            "a-very-long-port-name",
+        ] {
+            ddns.spec.srv = Some(SrvSpec {
+                ports: vec![port.to_string()],
+            });
+
+            let violations = validate_ddns(&ddns, &[]);
+            assert_eq!(violations.len(), 1, "{}: {:?}", port, violations);
+            assert!(violations[0].starts_with("srv port"));
+        }
+
+        let mut wildcard = self::ddns("default", "test", "*.example.com", "example.com");
+        wildcard.spec.srv = Some(SrvSpec::default());
+        let violations = validate_ddns(&wildcard, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("wildcard"));
+    }
+
+    #[test]
+    fn ip_filter_cidrs() {
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.ip_filter = Some(IpFilterSpec {
+            allow: vec!["203.0.113.0/24".to_string(), "2001:db8::/32".to_string()],
+            deny: vec!["203.0.113.7".to_string()],
+            public_only: true,
+        });
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        ddns.spec.ip_filter = Some(IpFilterSpec {
+            deny: vec!["10.0.0.0/40".to_string()],
+            ..Default::default()
+        });
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("ipFilter is invalid"));
+    }
+
+    #[test]
+    fn additional_addresses() {
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.additional_addresses = vec!["203.0.113.7".to_string(), "2001:db8::7".to_string()];
+        ddns.spec.address_mode = AddressMode::Override;
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        ddns.spec.additional_addresses = vec!["203.0.113.0/24".to_string()];
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("additionalAddresses is invalid"));
+
+        ddns.spec.additional_addresses.clear();
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("override"));
+    }
+
+    #[test]
+    fn failover_tiers() {
+        let tier = |name: &str, labels: &[(&str, &str)]| FailoverTier {
+            name: name.to_string(),
+            match_labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+
+        // the tiers select the services instead of the selector
+        let mut ddns = ddns("default", "test", "www.example.com", "example.com");
+        ddns.spec.selector.clear();
+        ddns.spec.failover = Some(FailoverSpec {
+            tiers: vec![
+                tier("primary", &[("site", "a")]),
+                tier("secondary", &[("site", "b")]),
+            ],
+            failback_delay_seconds: 300,
+        });
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        ddns.spec.failover = Some(FailoverSpec {
+            tiers: vec![
+                tier("primary", &[("site", "a")]),
+                tier("primary", &[("site", "b")]),
+                tier("", &[]),
+            ],
+            failback_delay_seconds: 300,
+        });
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 3, "{:?}", violations);
+
+        ddns.spec.failover = Some(FailoverSpec::default());
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations, ["failover tiers are empty"]);
+    }
+
+    #[test]
+    fn v2_hostnames() {
+        let v2_ddns = |hostnames: &[&str]| {
+            v2::Ddns::new(
+                "test",
+                v2::DdnsSpec {
+                    hostnames: hostnames
+                        .iter()
+                        .map(|hostname| hostname.to_string())
+                        .collect(),
+                    zone: "example.com".to_string(),
+                    selector: ServiceSelector {
+                        match_labels: HashMap::from([("app".to_string(), "test".to_string())]),
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        let ddns = Ddns::from(v2_ddns(&["www.example.com"]));
+        assert!(validate_ddns(&ddns, &[]).is_empty());
+
+        let ddns = Ddns::from(v2_ddns(&["www.example.com", "api.example.com"]));
+        let violations = validate_ddns(&ddns, &[]);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(violations[0].contains("exactly one hostname"));
+
+        // the empty domain is reported too
+        let ddns = Ddns::from(v2_ddns(&[]));
+        assert_eq!(validate_ddns(&ddns, &[]).len(), 2);
+    }
+}